@@ -0,0 +1,44 @@
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use deku::DekuContainerRead;
+
+use denis::{data::Message, view::MessageView};
+
+const QUERY: &[u8] = &[
+    100, 68, 1, 0, 0, 1, 0, 0, 0, 0, 0, 1, 3, 102, 111, 111, 5, 108, 111, 99, 97, 108, 3, 100, 101,
+    118, 0, 0, 255, 0, 1, 0, 0, 41, 2, 0, 0, 0, 0, 0, 0, 0,
+];
+
+const RESPONSE: &[u8] = &[
+    13, 208, 129, 128, 0, 1, 0, 1, 0, 0, 0, 0, 4, 110, 101, 119, 115, 11, 121, 99, 111, 109, 98,
+    105, 110, 97, 116, 111, 114, 3, 99, 111, 109, 0, 0, 1, 0, 1, 192, 12, 0, 1, 0, 1, 0, 0, 0, 1,
+    0, 4, 209, 216, 230, 240,
+];
+
+fn parse(c: &mut Criterion) {
+    let mut group = c.benchmark_group("parse");
+
+    for (name, data) in [("query", QUERY), ("response", RESPONSE)] {
+        group.bench_with_input(BenchmarkId::new("deku", name), data, |b, data| {
+            b.iter(|| Message::from_bytes((black_box(data), 0)).unwrap())
+        });
+
+        group.bench_with_input(BenchmarkId::new("view", name), data, |b, data| {
+            b.iter(|| {
+                let message = MessageView::parse(black_box(data)).unwrap();
+
+                for question in message.questions() {
+                    black_box(question.qname.labels().count());
+                }
+
+                for record in message.answers() {
+                    black_box(record.rdata());
+                }
+            })
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, parse);
+criterion_main!(benches);
@@ -0,0 +1,110 @@
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+
+use denis::{
+    data::Label,
+    trie::{Key, Trie},
+};
+
+/// Counts the bytes currently allocated, to compare the memory used by each layout.
+struct Counting;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+const SIZES: &[usize] = &[1_000, 100_000];
+
+/// Names shaped like a blocklist: many hosts spread over a few thousand domains.
+fn names(count: usize) -> Vec<Vec<Key<Label>>> {
+    let tlds = ["com", "net", "org", "dev", "io"];
+
+    (0..count)
+        .map(|i| {
+            let labels = [
+                tlds[i % tlds.len()].to_string(),
+                format!("domain{}", i % 5000),
+                format!("host{i}"),
+            ];
+
+            labels
+                .into_iter()
+                .map(|label| Key::Exact(Label::new(label.into_bytes())))
+                .collect()
+        })
+        .collect()
+}
+
+fn measure<T>(f: impl FnOnce() -> T) -> (T, usize) {
+    let before = ALLOCATED.load(Ordering::Relaxed);
+    let value = f();
+    let after = ALLOCATED.load(Ordering::Relaxed);
+
+    (value, after.saturating_sub(before))
+}
+
+fn lookup(c: &mut Criterion) {
+    let mut group = c.benchmark_group("lookup");
+
+    for &size in SIZES {
+        let names = names(size);
+
+        let (trie, trie_bytes) = measure(|| {
+            let mut trie = Trie::new();
+            for (i, name) in names.iter().enumerate() {
+                trie.insert(name.clone(), i as u32);
+            }
+            trie
+        });
+
+        let (arena, arena_bytes) = measure(|| {
+            let mut arena = trie.clone();
+            arena.compact();
+            arena
+        });
+
+        println!(
+            "{size} names: trie {} KiB, arena {} KiB",
+            trie_bytes / 1024,
+            arena_bytes / 1024
+        );
+
+        group.bench_with_input(BenchmarkId::new("trie", size), &names, |b, names| {
+            b.iter(|| {
+                for name in names.iter().step_by(97) {
+                    black_box(trie.lookup(black_box(name)));
+                }
+            })
+        });
+
+        group.bench_with_input(BenchmarkId::new("arena", size), &names, |b, names| {
+            b.iter(|| {
+                for name in names.iter().step_by(97) {
+                    black_box(arena.lookup(black_box(name)));
+                }
+            })
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, lookup);
+criterion_main!(benches);
@@ -0,0 +1,736 @@
+use std::{
+    cmp::Ordering,
+    collections::BTreeMap,
+    fs::File,
+    io::{self, BufRead, BufReader, Write},
+    mem,
+    ops::Range,
+    path::Path,
+    str::FromStr,
+};
+
+use color_eyre::{eyre::eyre, Report};
+use memmap2::Mmap;
+
+use crate::{
+    data::{Label, Name},
+    trie::{Key, Match, Trie},
+};
+
+/// Marks a node without value, and the label of a wildcard edge.
+const NONE: u32 = u32::MAX;
+
+const MAGIC: &[u8; 8] = b"DENISTR1";
+const HEADER_SIZE: usize = MAGIC.len() + 4 * 4;
+
+/// Read access to the flat arrays of an arena trie, whether owned or mapped.
+///
+/// A node is `[first edge, edge count, value]`, an edge is `[label, child]`.
+/// The edges of a node are contiguous, with the wildcard edge first and the
+/// others sorted by label, so that children can be found by binary search.
+trait Layout<K> {
+    fn node(&self, index: u32) -> [u32; 3];
+    fn edge(&self, index: u32) -> [u32; 2];
+
+    /// Compare the label at `index` with `key`.
+    fn compare(&self, index: u32, key: &K) -> Ordering;
+
+    /// The child of `node` along exactly `key`.
+    fn child(&self, node: u32, key: &Key<K>) -> Option<u32> {
+        let [first, count, _] = self.node(node);
+        let wildcard = count > 0 && self.edge(first)[0] == NONE;
+
+        let label = match key {
+            Key::Wildcard => return wildcard.then(|| self.edge(first)[1]),
+            Key::Exact(label) => label,
+        };
+
+        let (mut lo, mut hi) = (first + u32::from(wildcard), first + count);
+
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            let [edge, child] = self.edge(mid);
+
+            match self.compare(edge, label) {
+                Ordering::Less => lo = mid + 1,
+                Ordering::Greater => hi = mid,
+                Ordering::Equal => return Some(child),
+            }
+        }
+
+        None
+    }
+
+    fn value_index(&self, node: u32) -> Option<u32> {
+        let [_, _, value] = self.node(node);
+        (value != NONE).then_some(value)
+    }
+
+    /// Same semantics as [`Trie::lookup`].
+    fn lookup_index(&self, node: u32, keys: &[Key<K>]) -> Option<u32> {
+        let Some((head, tail)) = keys.split_first() else {
+            return self.value_index(node);
+        };
+
+        match self
+            .child(node, head)
+            .or_else(|| self.child(node, &Key::Wildcard))
+        {
+            Some(child) => self.lookup_index(child, tail),
+            None => None,
+        }
+    }
+
+    /// The node at exactly the given path, without wildcard matching.
+    fn node_at(&self, keys: &[Key<K>]) -> Option<u32> {
+        keys.iter().try_fold(0, |node, key| self.child(node, key))
+    }
+}
+
+/// A read-only trie stored in a few flat arrays, with interned labels.
+///
+/// Uses a fraction of the memory of the nodes of a [`Trie`] for large zones,
+/// and can back one with [`Trie::compact`]. It can also be written to disk
+/// and memory-mapped back with [`MappedTrie`].
+#[derive(Clone, Debug)]
+pub struct ArenaTrie<K, V> {
+    labels: Vec<K>,
+    nodes: Vec<[u32; 3]>,
+    edges: Vec<[u32; 2]>,
+    values: Vec<V>,
+}
+
+impl<K: Ord, V> Layout<K> for ArenaTrie<K, V> {
+    fn node(&self, index: u32) -> [u32; 3] {
+        self.nodes[index as usize]
+    }
+
+    fn edge(&self, index: u32) -> [u32; 2] {
+        self.edges[index as usize]
+    }
+
+    fn compare(&self, index: u32, key: &K) -> Ordering {
+        self.labels[index as usize].cmp(key)
+    }
+}
+
+#[derive(Debug, Default)]
+struct BuilderNode {
+    children: Vec<(u32, u32)>,
+    value: Option<u32>,
+}
+
+/// Builds an [`ArenaTrie`] from sorted paths, interning labels along the way.
+///
+/// Since paths come in order, the children of a node are appended in order
+/// too, and only the last one can be shared with the next path.
+#[derive(Debug)]
+struct Builder<K, V> {
+    interned: BTreeMap<K, u32>,
+    labels: Vec<K>,
+    nodes: Vec<BuilderNode>,
+    values: Vec<V>,
+    last: Option<Vec<Key<K>>>,
+}
+
+impl<K: Clone + Ord, V> Builder<K, V> {
+    fn new() -> Self {
+        Self {
+            interned: BTreeMap::new(),
+            labels: Vec::new(),
+            nodes: vec![BuilderNode::default()],
+            values: Vec::new(),
+            last: None,
+        }
+    }
+
+    fn intern(&mut self, label: &K) -> u32 {
+        if let Some(&index) = self.interned.get(label) {
+            return index;
+        }
+
+        let index = self.labels.len() as u32;
+        self.labels.push(label.clone());
+        self.interned.insert(label.clone(), index);
+
+        index
+    }
+
+    fn push(&mut self, keys: Vec<Key<K>>, value: V) -> Result<(), Report> {
+        match self.last.as_ref().map(|last| keys.cmp(last)) {
+            Some(Ordering::Less) => return Err(eyre!("Trie entries are not sorted")),
+            Some(Ordering::Equal) => {
+                // The last value wins, as with `Trie::insert`.
+                *self.values.last_mut().unwrap() = value;
+                return Ok(());
+            }
+            _ => (),
+        }
+
+        let mut node = 0;
+
+        for key in &keys {
+            let label = match key {
+                Key::Wildcard => NONE,
+                Key::Exact(label) => self.intern(label),
+            };
+
+            node = match self.nodes[node].children.last() {
+                Some(&(last, child)) if last == label => child as usize,
+                _ => {
+                    let child = self.nodes.len();
+                    self.nodes.push(BuilderNode::default());
+                    self.nodes[node].children.push((label, child as u32));
+                    child
+                }
+            };
+        }
+
+        self.nodes[node].value = Some(self.values.len() as u32);
+        self.values.push(value);
+        self.last = Some(keys);
+
+        Ok(())
+    }
+
+    fn finish(self) -> ArenaTrie<K, V> {
+        let mut nodes = Vec::with_capacity(self.nodes.len());
+        let mut edges = Vec::with_capacity(self.nodes.len() - 1);
+
+        for node in self.nodes {
+            let first = edges.len() as u32;
+            let count = node.children.len() as u32;
+
+            edges.extend(node.children.iter().map(|&(label, child)| [label, child]));
+            nodes.push([first, count, node.value.unwrap_or(NONE)]);
+        }
+
+        let mut labels = self.labels;
+        let mut values = self.values;
+        labels.shrink_to_fit();
+        values.shrink_to_fit();
+
+        ArenaTrie {
+            labels,
+            nodes,
+            edges,
+            values,
+        }
+    }
+}
+
+impl<K, V> ArenaTrie<K, V> {
+    /// Build from `(path, value)` pairs sorted by path, as yielded by
+    /// [`Trie::iter`]. The last value given for a path wins.
+    pub fn from_sorted(entries: impl IntoIterator<Item = (Vec<Key<K>>, V)>) -> Result<Self, Report>
+    where
+        K: Clone + Ord,
+    {
+        let mut builder = Builder::new();
+
+        for (keys, value) in entries {
+            builder.push(keys, value)?;
+        }
+
+        Ok(builder.finish())
+    }
+
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    /// Look up a key, with the same wildcard semantics as [`Trie::lookup`].
+    pub fn lookup(&self, keys: &[Key<K>]) -> Option<&V>
+    where
+        K: Ord,
+    {
+        let index = self.lookup_index(0, keys)?;
+        self.values.get(index as usize)
+    }
+
+    /// Look up a key, reporting how far the match got, as [`Trie::find`].
+    pub fn find<'a, 'k>(&'a self, keys: &'k [Key<K>]) -> Match<'a, 'k, K, V>
+    where
+        K: Ord,
+    {
+        let mut node = 0;
+
+        for (depth, key) in keys.iter().enumerate() {
+            match self.child(node, key) {
+                Some(child) => node = child,
+                None => {
+                    let (encloser, remaining) = keys.split_at(depth);
+                    let wildcard = self.child(node, &Key::Wildcard);
+
+                    return match wildcard.and_then(|node| self.value(node)) {
+                        Some(value) => Match::Wildcard {
+                            source: encloser,
+                            value,
+                        },
+                        None => Match::Miss {
+                            encloser,
+                            value: self.value(node),
+                            remaining,
+                        },
+                    };
+                }
+            }
+        }
+
+        match self.value(node) {
+            Some(value) => Match::Exact(value),
+            None => Match::EmptyNonTerminal,
+        }
+    }
+
+    /// Get the value at exactly the given key, without wildcard matching.
+    pub fn get(&self, keys: &[Key<K>]) -> Option<&V>
+    where
+        K: Ord,
+    {
+        self.value(self.node_at(keys)?)
+    }
+
+    pub fn iter(&self) -> Iter<'_, K, V> {
+        self.iter_from(0, vec![])
+    }
+
+    /// Iterate over the values at or below the given prefix.
+    pub fn subtree(&self, prefix: &[Key<K>]) -> Iter<'_, K, V>
+    where
+        K: Clone + Ord,
+    {
+        match self.node_at(prefix) {
+            Some(node) => self.iter_from(node, prefix.to_vec()),
+            None => Iter {
+                arena: self,
+                path: vec![],
+                stack: vec![],
+                pending: None,
+            },
+        }
+    }
+
+    /// Approximate number of bytes used by the trie structure, excluding
+    /// values and whatever labels own on the heap.
+    pub fn heap_size(&self) -> usize {
+        self.labels.capacity() * mem::size_of::<K>()
+            + self.nodes.capacity() * mem::size_of::<[u32; 3]>()
+            + self.edges.capacity() * mem::size_of::<[u32; 2]>()
+    }
+
+    fn value(&self, node: u32) -> Option<&V> {
+        let [_, _, value] = self.nodes[node as usize];
+        (value != NONE).then(|| &self.values[value as usize])
+    }
+
+    fn edges(&self, node: u32) -> Range<u32> {
+        let [first, count, _] = self.nodes[node as usize];
+        first..first + count
+    }
+
+    fn iter_from(&self, node: u32, path: Vec<Key<K>>) -> Iter<'_, K, V> {
+        Iter {
+            arena: self,
+            path,
+            stack: vec![self.edges(node)],
+            pending: self.value(node),
+        }
+    }
+}
+
+impl<K: AsRef<[u8]>, V> ArenaTrie<K, V> {
+    /// Serialize the trie structure for [`MappedTrie`], which will return the
+    /// index of each value in path order instead of the value itself.
+    ///
+    /// The mapped trie compares labels byte by byte, so `K` must sort like its
+    /// bytes, as [`Label`] and `str` do.
+    pub fn write_to(&self, mut out: impl Write) -> io::Result<()> {
+        let mut spans = Vec::with_capacity(self.labels.len());
+        let mut start = 0;
+
+        for label in &self.labels {
+            let len = label.as_ref().len() as u32;
+            spans.push([start, len]);
+            start += len;
+        }
+
+        out.write_all(MAGIC)?;
+
+        for len in [
+            start as usize,
+            spans.len(),
+            self.nodes.len(),
+            self.edges.len(),
+        ] {
+            out.write_all(&(len as u32).to_le_bytes())?;
+        }
+
+        let words = spans.iter().flatten();
+        let words = words.chain(self.nodes.iter().flatten());
+        let words = words.chain(self.edges.iter().flatten());
+
+        for word in words {
+            out.write_all(&word.to_le_bytes())?;
+        }
+
+        for label in &self.labels {
+            out.write_all(label.as_ref())?;
+        }
+
+        Ok(())
+    }
+}
+
+impl<K, V> From<&Trie<K, V>> for ArenaTrie<K, V>
+where
+    K: Clone + Ord,
+    V: Clone,
+{
+    fn from(trie: &Trie<K, V>) -> Self {
+        Self::from_sorted(trie.iter().map(|(keys, value)| (keys, value.clone())))
+            .expect("a trie iterates in order")
+    }
+}
+
+impl ArenaTrie<Label, ()> {
+    /// Build a set of names from a file with one name per line, such as a blocklist.
+    pub fn load_names(path: impl AsRef<Path>) -> Result<Self, Report> {
+        let reader = BufReader::new(File::open(path)?);
+        let mut names = Vec::new();
+
+        for line in reader.lines() {
+            let line = line?;
+            let line = line.trim();
+
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let name = Name::from_str(line)?;
+            names.push(to_key(&name));
+        }
+
+        names.sort_unstable();
+        names.dedup();
+
+        Self::from_sorted(names.into_iter().map(|key| (key, ())))
+    }
+}
+
+fn to_key(name: &Name) -> Vec<Key<Label>> {
+    name.labels()
+        .iter()
+        .rev()
+        .map(|label| {
+            if label.as_bytes() == b"*" {
+                Key::Wildcard
+            } else {
+                Key::Exact(label.clone())
+            }
+        })
+        .collect()
+}
+
+/// Iterator over the values of an [`ArenaTrie`] and their full key paths, in order.
+#[derive(Debug)]
+pub struct Iter<'a, K, V> {
+    arena: &'a ArenaTrie<K, V>,
+    path: Vec<Key<K>>,
+    stack: Vec<Range<u32>>,
+    pending: Option<&'a V>,
+}
+
+impl<'a, K: Clone, V> Iterator for Iter<'a, K, V> {
+    type Item = (Vec<Key<K>>, &'a V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(value) = self.pending.take() {
+                return Some((self.path.clone(), value));
+            }
+
+            match self.stack.last_mut()?.next() {
+                Some(edge) => {
+                    let [label, child] = self.arena.edges[edge as usize];
+
+                    self.path.push(match label {
+                        NONE => Key::Wildcard,
+                        label => Key::Exact(self.arena.labels[label as usize].clone()),
+                    });
+                    self.stack.push(self.arena.edges(child));
+                    self.pending = self.arena.value(child);
+                }
+                None => {
+                    self.stack.pop();
+                    self.path.pop();
+                }
+            }
+        }
+    }
+}
+
+/// An [`ArenaTrie`] read in place from the output of [`ArenaTrie::write_to`],
+/// typically memory-mapped from disk.
+///
+/// It only holds the index of each value, not the values themselves, so it
+/// cannot back a [`Trie`] or a [`crate::db::Db`]: the server always loads its
+/// records into memory, compacted with [`crate::config::Config::compact`].
+#[derive(Debug)]
+pub struct MappedTrie<B> {
+    data: B,
+    nodes: usize,
+    edges: usize,
+    labels: usize,
+    len: usize,
+}
+
+impl MappedTrie<Mmap> {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, Report> {
+        let file = File::open(path)?;
+
+        // Safety: the file is only ever read, and we validate it before use.
+        // Modifying it while mapped is not supported.
+        let map = unsafe { Mmap::map(&file)? };
+
+        Self::new(map)
+    }
+}
+
+impl<B: AsRef<[u8]>> MappedTrie<B> {
+    pub fn new(data: B) -> Result<Self, Report> {
+        let bytes = data.as_ref();
+
+        if bytes.len() < HEADER_SIZE || &bytes[..MAGIC.len()] != MAGIC {
+            return Err(eyre!("Not a trie file"));
+        }
+
+        let count = |i: usize| read_u32(bytes, MAGIC.len() + i * 4) as usize;
+        let (labels_len, spans_len, nodes_len, edges_len) =
+            (count(0), count(1), count(2), count(3));
+
+        let spans = HEADER_SIZE;
+        let nodes = spans + spans_len * 8;
+        let edges = nodes + nodes_len * 12;
+        let labels = edges + edges_len * 8;
+
+        if bytes.len() != labels + labels_len || nodes_len == 0 {
+            return Err(eyre!("Truncated trie file"));
+        }
+
+        let mut len = 0;
+
+        for i in 0..spans_len {
+            let (start, end) = (
+                read_u32(bytes, spans + i * 8),
+                read_u32(bytes, spans + i * 8 + 4),
+            );
+
+            if start as usize + end as usize > labels_len {
+                return Err(eyre!("Invalid label in trie file"));
+            }
+        }
+
+        for i in 0..nodes_len {
+            let first = read_u32(bytes, nodes + i * 12) as usize;
+            let count = read_u32(bytes, nodes + i * 12 + 4) as usize;
+
+            if first + count > edges_len {
+                return Err(eyre!("Invalid node in trie file"));
+            }
+
+            if read_u32(bytes, nodes + i * 12 + 8) != NONE {
+                len += 1;
+            }
+        }
+
+        for i in 0..edges_len {
+            let label = read_u32(bytes, edges + i * 8);
+            let child = read_u32(bytes, edges + i * 8 + 4) as usize;
+
+            if (label != NONE && label as usize >= spans_len) || child >= nodes_len {
+                return Err(eyre!("Invalid edge in trie file"));
+            }
+        }
+
+        Ok(Self {
+            data,
+            nodes,
+            edges,
+            labels,
+            len,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Look up a key, returning the index of its value in path order.
+    pub fn lookup<K: AsRef<[u8]>>(&self, keys: &[Key<K>]) -> Option<u32> {
+        self.lookup_index(0, keys)
+    }
+}
+
+impl<K: AsRef<[u8]>, B: AsRef<[u8]>> Layout<K> for MappedTrie<B> {
+    fn node(&self, index: u32) -> [u32; 3] {
+        let offset = self.nodes + index as usize * 12;
+        let bytes = self.data.as_ref();
+
+        [
+            read_u32(bytes, offset),
+            read_u32(bytes, offset + 4),
+            read_u32(bytes, offset + 8),
+        ]
+    }
+
+    fn edge(&self, index: u32) -> [u32; 2] {
+        let offset = self.edges + index as usize * 8;
+        let bytes = self.data.as_ref();
+
+        [read_u32(bytes, offset), read_u32(bytes, offset + 4)]
+    }
+
+    fn compare(&self, index: u32, key: &K) -> Ordering {
+        let offset = HEADER_SIZE + index as usize * 8;
+        let bytes = self.data.as_ref();
+
+        let start = self.labels + read_u32(bytes, offset) as usize;
+        let len = read_u32(bytes, offset + 4) as usize;
+
+        bytes[start..start + len].cmp(key.as_ref())
+    }
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn trie() -> Trie<&'static str, u32> {
+        let mut trie = Trie::new();
+
+        trie.insert([Key::Exact("dev"), Key::Exact("local")], 1);
+        trie.insert([Key::Exact("dev"), Key::Exact("local"), Key::Wildcard], 2);
+        trie.insert(
+            [Key::Exact("dev"), Key::Exact("local"), Key::Exact("api")],
+            3,
+        );
+        trie.insert([Key::Exact("com"), Key::Exact("example")], 4);
+
+        trie
+    }
+
+    fn keys(labels: &[&'static str]) -> Vec<Key<&'static str>> {
+        labels
+            .iter()
+            .map(|&label| {
+                if label == "*" {
+                    Key::Wildcard
+                } else {
+                    Key::Exact(label)
+                }
+            })
+            .collect()
+    }
+
+    const QUERIES: &[&[&str]] = &[
+        &["dev", "local"],
+        &["dev", "local", "api"],
+        &["dev", "local", "foo"],
+        &["dev", "local", "foo", "bar"],
+        &["dev", "local", "*"],
+        &["dev"],
+        &["com", "example"],
+        &["com", "example", "www"],
+        &["org"],
+        &[],
+    ];
+
+    #[test]
+    fn same_as_trie() {
+        let trie = trie();
+        let arena = ArenaTrie::from(&trie);
+
+        assert_eq!(arena.len(), 4);
+
+        for query in QUERIES {
+            let key = keys(query);
+            assert_eq!(arena.lookup(&key), trie.lookup(&key), "{query:?}");
+        }
+    }
+
+    #[test]
+    fn mapped_roundtrip() {
+        let trie = trie();
+        let arena = ArenaTrie::from(&trie);
+
+        let mut data = Vec::new();
+        arena.write_to(&mut data).unwrap();
+
+        let mapped = MappedTrie::new(data.as_slice()).unwrap();
+        assert_eq!(mapped.len(), 4);
+
+        for query in QUERIES {
+            let key = keys(query);
+            let expected = arena.lookup(&key);
+            let actual = mapped.lookup(&key).map(|i| &arena.values[i as usize]);
+
+            assert_eq!(actual, expected, "{query:?}");
+        }
+
+        assert!(MappedTrie::new(&data[..data.len() - 1]).is_err());
+        assert!(MappedTrie::new(&b"DENISTR0"[..]).is_err());
+    }
+
+    #[test]
+    fn sorted_input() {
+        let entries = [
+            (keys(&["com", "example"]), 1),
+            (keys(&["com", "example"]), 2),
+            (keys(&["dev", "local"]), 3),
+        ];
+
+        let arena = ArenaTrie::from_sorted(entries.clone()).unwrap();
+        assert_eq!(arena.len(), 2);
+        assert_eq!(arena.lookup(&keys(&["com", "example"])), Some(&2));
+        assert_eq!(arena.iter().count(), 2);
+
+        let mut unsorted = entries.to_vec();
+        unsorted.reverse();
+        assert!(ArenaTrie::from_sorted(unsorted).is_err());
+    }
+
+    #[test]
+    fn load_names() {
+        let path = std::env::temp_dir().join(format!("denis-names-{}", std::process::id()));
+        std::fs::write(
+            &path,
+            "ads.example.com\n# comment\ntracker.net\nads.example.com\n",
+        )
+        .unwrap();
+
+        let names = ArenaTrie::load_names(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(names.len(), 2);
+
+        let mut data = Vec::new();
+        names.write_to(&mut data).unwrap();
+        assert_eq!(MappedTrie::new(data.as_slice()).unwrap().len(), 2);
+
+        let name = Name::from_str("ads.example.com").unwrap();
+        assert_eq!(names.lookup(&to_key(&name)), Some(&()));
+    }
+}
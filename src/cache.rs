@@ -0,0 +1,189 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    config::CacheConfig,
+    data::QType,
+    view::{rewrite_ttls, MessageView},
+};
+
+#[derive(Debug)]
+struct Entry {
+    data: Vec<u8>,
+    inserted: Instant,
+    ttl: Duration,
+}
+
+/// Cache of upstream responses, keyed by question and honoring record TTLs.
+#[derive(Debug)]
+pub struct Cache {
+    entries: Mutex<HashMap<Vec<u8>, Entry>>,
+    config: CacheConfig,
+}
+
+impl Cache {
+    pub fn new(config: CacheConfig) -> Self {
+        Self {
+            entries: Mutex::new(HashMap::new()),
+            config,
+        }
+    }
+
+    /// Look up a response to `query`, with its id and remaining TTLs filled in.
+    pub fn get(&self, query: &MessageView<'_>) -> Option<Vec<u8>> {
+        let key = key(query);
+        let mut entries = self.entries.lock().unwrap();
+
+        let entry = entries.get(&key)?;
+        let elapsed = entry.inserted.elapsed();
+
+        if elapsed >= entry.ttl {
+            entries.remove(&key);
+            return None;
+        }
+
+        let mut data = entry.data.clone();
+        drop(entries);
+
+        data[..2].copy_from_slice(&query.header().id.to_be_bytes());
+
+        let elapsed = elapsed.as_secs() as u32;
+        rewrite_ttls(&mut data, |ttl| ttl.saturating_sub(elapsed)).ok()?;
+
+        Some(data)
+    }
+
+    /// Store the response to `query`, if it is a cacheable answer.
+    pub fn insert(&self, query: &MessageView<'_>, response: &[u8]) {
+        if self.config.size == 0 {
+            return;
+        }
+
+        let Ok(view) = MessageView::parse(response) else {
+            return;
+        };
+
+        // Only cache answers and negative answers, not failures or truncated ones.
+        if !matches!(view.header().rcode(), 0 | 3) || view.header().tc() {
+            return;
+        }
+
+        let ttl = view
+            .records()
+            .filter(|record| record.rtype != QType::OPT as u16)
+            .map(|record| record.ttl)
+            .min()
+            .unwrap_or(0)
+            .clamp(self.config.min_ttl, self.config.max_ttl);
+
+        if ttl == 0 {
+            return;
+        }
+
+        let mut entries = self.entries.lock().unwrap();
+
+        if entries.len() >= self.config.size {
+            entries.retain(|_, entry| entry.inserted.elapsed() < entry.ttl);
+        }
+
+        if entries.len() >= self.config.size {
+            let oldest = entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.inserted)
+                .map(|(key, _)| key.clone());
+
+            if let Some(oldest) = oldest {
+                entries.remove(&oldest);
+            }
+        }
+
+        entries.insert(
+            key(query),
+            Entry {
+                data: response.to_vec(),
+                inserted: Instant::now(),
+                ttl: Duration::from_secs(ttl.into()),
+            },
+        );
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// The question section, case-insensitively, along with the DO bit which
+/// changes the content of responses.
+fn key(query: &MessageView<'_>) -> Vec<u8> {
+    let mut key = Vec::new();
+
+    for question in query.questions() {
+        for label in question.qname.labels() {
+            key.push(label.len() as u8);
+            key.extend(label.iter().map(u8::to_ascii_lowercase));
+        }
+
+        key.push(0);
+        key.extend(question.qtype.to_be_bytes());
+        key.extend(question.qclass.to_be_bytes());
+    }
+
+    key.push(query.edns().map_or(0, |edns| edns.dnssec_ok as u8));
+    key
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const QUERY: &[u8] = &[
+        100, 68, 1, 0, 0, 1, 0, 0, 0, 0, 0, 0, 4, 110, 101, 119, 115, 11, 121, 99, 111, 109, 98,
+        105, 110, 97, 116, 111, 114, 3, 99, 111, 109, 0, 0, 1, 0, 1,
+    ];
+
+    const RESPONSE: &[u8] = &[
+        13, 208, 129, 128, 0, 1, 0, 1, 0, 0, 0, 0, 4, 110, 101, 119, 115, 11, 121, 99, 111, 109,
+        98, 105, 110, 97, 116, 111, 114, 3, 99, 111, 109, 0, 0, 1, 0, 1, 192, 12, 0, 1, 0, 1, 0, 0,
+        0, 60, 0, 4, 209, 216, 230, 240,
+    ];
+
+    #[test]
+    fn hit_and_miss() {
+        let cache = Cache::new(CacheConfig::default());
+        let query = MessageView::parse(QUERY).unwrap();
+
+        assert_eq!(cache.get(&query), None);
+
+        cache.insert(&query, RESPONSE);
+        assert_eq!(cache.len(), 1);
+
+        let hit = cache.get(&query).unwrap();
+        assert_eq!(&hit[..2], &QUERY[..2]);
+        assert_eq!(&hit[2..], &RESPONSE[2..]);
+
+        // Same question, different case.
+        let mut upper = QUERY.to_vec();
+        upper[13..17].copy_from_slice(b"NEWS");
+        assert!(cache.get(&MessageView::parse(&upper).unwrap()).is_some());
+    }
+
+    #[test]
+    fn expiry() {
+        let cache = Cache::new(CacheConfig {
+            max_ttl: 0,
+            ..CacheConfig::default()
+        });
+
+        let query = MessageView::parse(QUERY).unwrap();
+        cache.insert(&query, RESPONSE);
+
+        assert!(cache.is_empty());
+    }
+}
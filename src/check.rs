@@ -0,0 +1,392 @@
+use core::fmt;
+use std::{
+    collections::{HashMap, HashSet},
+    io::{BufRead, BufReader, Read},
+};
+
+use color_eyre::Report;
+
+use crate::{
+    data::{Label, Name, QType},
+    db::{self, Db},
+    record::Record,
+};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ProblemKind {
+    Parse(String),
+    Duplicate(Record),
+    CnameAndOtherData,
+    MultipleCnames,
+    CnameLoop(Vec<Name>),
+    DanglingCname(Name),
+    OverlapsWildcard { wildcard: Name, types: Vec<QType> },
+    OutsideZones,
+}
+
+/// A problem found in a db file, at the given line (starting at 1).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Problem {
+    pub line: usize,
+    pub name: Option<Name>,
+    pub kind: ProblemKind,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: ", self.line)?;
+
+        if let Some(name) = &self.name {
+            write!(f, "{name}: ")?;
+        }
+
+        match &self.kind {
+            ProblemKind::Parse(err) => write!(f, "{err}"),
+            ProblemKind::Duplicate(record) => write!(f, "duplicate record {record}"),
+            ProblemKind::CnameAndOtherData => write!(f, "CNAME alongside other records"),
+            ProblemKind::MultipleCnames => write!(f, "more than one CNAME"),
+            ProblemKind::CnameLoop(names) => {
+                write!(f, "CNAME loop: ")?;
+
+                for name in names {
+                    write!(f, "{name} -> ")?;
+                }
+
+                write!(f, "{}", names[0])
+            }
+            ProblemKind::DanglingCname(target) => {
+                write!(f, "CNAME points to {target}, which has no records")
+            }
+            ProblemKind::OverlapsWildcard { wildcard, types } => {
+                write!(f, "exists, so {wildcard} will not answer for it with")?;
+
+                for qtype in types {
+                    write!(f, " {qtype}")?;
+                }
+
+                Ok(())
+            }
+            ProblemKind::OutsideZones => write!(f, "outside of every zone declared with SOA"),
+        }
+    }
+}
+
+struct Entry {
+    line: usize,
+    name: Name,
+    record: Record,
+}
+
+/// Parse a db file and report every problem found in it.
+pub fn check(reader: impl Read) -> Result<Vec<Problem>, Report> {
+    let mut problems = Vec::new();
+    let mut entries = Vec::new();
+
+    for (i, line) in BufReader::new(reader).lines().enumerate() {
+        let line = line?;
+
+        if let Some(Err(err)) = db::parse_fault(&line) {
+            problems.push(Problem {
+                line: i + 1,
+                name: None,
+                kind: ProblemKind::Parse(err.to_string()),
+            });
+        }
+
+        match db::parse_entry(&line) {
+            Some(Ok((name, record))) => entries.push(Entry {
+                line: i + 1,
+                name,
+                record,
+            }),
+            Some(Err(err)) => problems.push(Problem {
+                line: i + 1,
+                name: None,
+                kind: ProblemKind::Parse(err.to_string()),
+            }),
+            None => (),
+        }
+    }
+
+    let mut db = Db::new();
+    let mut by_name: HashMap<&Name, Vec<&Entry>> = HashMap::new();
+
+    for entry in &entries {
+        let existing = by_name.entry(&entry.name).or_default();
+
+        if existing.iter().any(|e| e.record == entry.record) {
+            problems.push(entry.problem(ProblemKind::Duplicate(entry.record.clone())));
+            continue;
+        }
+
+        existing.push(entry);
+        db.insert(&entry.name, entry.record.clone());
+    }
+
+    let zones = entries
+        .iter()
+        .filter(|e| e.record.qtype() == QType::SOA)
+        .map(|e| &e.name)
+        .collect::<Vec<_>>();
+
+    let mut loops = HashSet::new();
+
+    for entry in &entries {
+        let same_name = &by_name[&entry.name];
+
+        if !same_name.iter().any(|e| std::ptr::eq(*e, entry)) {
+            continue;
+        }
+
+        if let Record::CNAME { name: target } = &entry.record {
+            let cnames = same_name
+                .iter()
+                .filter(|e| e.record.qtype() == QType::CNAME)
+                .count();
+
+            if cnames > 1 {
+                problems.push(entry.problem(ProblemKind::MultipleCnames));
+            }
+
+            if cnames < same_name.len() {
+                problems.push(entry.problem(ProblemKind::CnameAndOtherData));
+            }
+
+            if let Some(names) = cname_loop(&db, &entry.name) {
+                let mut key = names.iter().map(|n| n.to_string()).collect::<Vec<_>>();
+                key.sort();
+
+                if loops.insert(key) {
+                    problems.push(entry.problem(ProblemKind::CnameLoop(names)));
+                }
+            } else if db.lookup_all(target, QType::ANY).is_empty() {
+                problems.push(entry.problem(ProblemKind::DanglingCname(target.clone())));
+            }
+        }
+
+        // Report each name once, on its first record.
+        if !std::ptr::eq(same_name[0], entry) {
+            continue;
+        }
+
+        // Explicit names are never answered from a wildcard, even for types
+        // they do not have themselves.
+        if let Some(wildcard) = sibling_wildcard(&entry.name) {
+            let own = same_name
+                .iter()
+                .map(|e| e.record.qtype())
+                .collect::<Vec<_>>();
+
+            let mut types = by_name
+                .get(&wildcard)
+                .into_iter()
+                .flatten()
+                .map(|e| e.record.qtype())
+                .filter(|qtype| !own.contains(qtype))
+                .collect::<Vec<_>>();
+
+            types.sort();
+            types.dedup();
+
+            if !types.is_empty() {
+                let kind = ProblemKind::OverlapsWildcard { wildcard, types };
+                problems.push(entry.problem(kind));
+            }
+        }
+
+        let labels = entry.name.labels();
+
+        if !zones.is_empty() && !zones.iter().any(|zone| labels.ends_with(zone.labels())) {
+            problems.push(entry.problem(ProblemKind::OutsideZones));
+        }
+    }
+
+    problems.sort_by_key(|problem| problem.line);
+
+    Ok(problems)
+}
+
+impl Entry {
+    fn problem(&self, kind: ProblemKind) -> Problem {
+        Problem {
+            line: self.line,
+            name: Some(self.name.clone()),
+            kind,
+        }
+    }
+}
+
+/// Follow the CNAME chain starting at `name`, returning the loop it ends in, if any.
+fn cname_loop(db: &Db, name: &Name) -> Option<Vec<Name>> {
+    let mut chain = vec![name.clone()];
+    let mut current = name.clone();
+
+    while let Some(Record::CNAME { name: target }) = db.lookup(&current, QType::CNAME) {
+        if let Some(start) = chain.iter().position(|n| n == target) {
+            // Only report loops that `name` is part of, not those it leads to.
+            return (start == 0).then_some(chain);
+        }
+
+        chain.push(target.clone());
+        current = target.clone();
+    }
+
+    None
+}
+
+/// The wildcard that would match `name` if it did not exist, eg. `*.local.dev` for `api.local.dev`.
+///
+/// Wildcards only stand for a single label in [`Db::lookup_all`], so that is
+/// the only one to check.
+fn sibling_wildcard(name: &Name) -> Option<Name> {
+    let (first, parent) = name.labels().split_first()?;
+
+    if first.as_bytes() == b"*" {
+        return None;
+    }
+
+    let wildcard = Label::new(b"*".to_vec());
+    Some(
+        std::iter::once(wildcard)
+            .chain(parent.iter().cloned())
+            .collect(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kinds(db: &str) -> Vec<(usize, ProblemKind)> {
+        check(db.as_bytes())
+            .unwrap()
+            .into_iter()
+            .map(|problem| (problem.line, problem.kind))
+            .collect()
+    }
+
+    fn name(name: &str) -> Name {
+        Name::new(name.to_string())
+    }
+
+    #[test]
+    fn clean() {
+        let db = r#"
+example.com    CNAME    www.example.com
+www.example.com A       1.2.3.4
+*.local.dev    A        127.0.0.1
+$FAULT *.local.dev delay 100ms-1s 50%
+"#;
+
+        assert_eq!(kinds(db), vec![]);
+    }
+
+    #[test]
+    fn cnames() {
+        let db = r#"
+a.dev    CNAME    b.dev
+b.dev    CNAME    c.dev
+c.dev    CNAME    a.dev
+d.dev    CNAME    a.dev
+e.dev    CNAME    nowhere.dev
+e.dev    A        1.2.3.4
+"#;
+
+        assert_eq!(
+            kinds(db),
+            vec![
+                (
+                    2,
+                    ProblemKind::CnameLoop(vec![name("a.dev"), name("b.dev"), name("c.dev")])
+                ),
+                (6, ProblemKind::CnameAndOtherData),
+                (6, ProblemKind::DanglingCname(name("nowhere.dev"))),
+            ]
+        );
+    }
+
+    #[test]
+    fn wildcard_types() {
+        let db = r#"
+*.local.dev    A        127.0.0.1
+*.local.dev    TXT      "hello"
+*.local.dev    A        127.0.0.2
+api.local.dev  MX       10 mail.local.dev
+"#;
+
+        assert_eq!(
+            kinds(db),
+            vec![(
+                5,
+                ProblemKind::OverlapsWildcard {
+                    wildcard: name("*.local.dev"),
+                    types: vec![QType::A, QType::TXT]
+                }
+            )]
+        );
+    }
+
+    #[test]
+    fn duplicates_and_zones() {
+        let db = r#"
+local.dev      SOA      ns.local.dev admin.local.dev 1 3600 600 86400 1
+ns.local.dev   A        127.0.0.1
+*.local.dev    A        127.0.0.1
+*.local.dev    TXT      "hello"
+api.local.dev  A        127.0.0.1
+api.local.dev  A        127.0.0.1
+example.com    A        1.2.3.4
+example.com    BOGUS    1.2.3.4
+$FAULT example.com explode
+"#;
+
+        assert_eq!(
+            kinds(db),
+            vec![
+                (
+                    3,
+                    ProblemKind::OverlapsWildcard {
+                        wildcard: name("*.local.dev"),
+                        types: vec![QType::TXT]
+                    }
+                ),
+                (
+                    6,
+                    ProblemKind::OverlapsWildcard {
+                        wildcard: name("*.local.dev"),
+                        types: vec![QType::TXT]
+                    }
+                ),
+                (
+                    7,
+                    ProblemKind::Duplicate(Record::A {
+                        address: [127, 0, 0, 1]
+                    })
+                ),
+                (8, ProblemKind::OutsideZones),
+                (9, ProblemKind::Parse("Invalid QType: BOGUS".to_string())),
+                (10, ProblemKind::Parse("unknown fault: explode".to_string())),
+            ]
+        );
+    }
+
+    #[test]
+    fn addresses() {
+        let db = r#"
+a.dev    A    127.0.0
+b.dev    A    1.2.3.4.5
+c.dev    A    1.2.3.400
+"#;
+
+        let invalid = |ip: &str| ProblemKind::Parse(format!("Invalid IPv4 address: {ip}"));
+
+        assert_eq!(
+            kinds(db),
+            vec![
+                (2, invalid("127.0.0")),
+                (3, invalid("1.2.3.4.5")),
+                (4, invalid("1.2.3.400")),
+            ]
+        );
+    }
+}
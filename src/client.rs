@@ -0,0 +1,406 @@
+use core::fmt;
+use std::{
+    net::{Ipv6Addr, SocketAddr},
+    time::{Duration, Instant},
+};
+
+use color_eyre::{eyre::eyre, Report};
+use serde::Serialize;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpStream, UdpSocket},
+    time::timeout,
+};
+
+use crate::{
+    data::{Edns, MessageBuilder, Name, QClass, QType, Question},
+    record::Record,
+    view::{MessageView, RecordView},
+};
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Transport {
+    Udp,
+    Tcp,
+}
+
+impl fmt::Display for Transport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Udp => write!(f, "UDP"),
+            Self::Tcp => write!(f, "TCP"),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct QueryOptions {
+    pub server: SocketAddr,
+    pub transport: Transport,
+    pub recursion_desired: bool,
+    pub dnssec_ok: bool,
+    /// Advertised UDP payload size, or `None` to send the query without EDNS.
+    pub edns_size: Option<u16>,
+    pub timeout: Duration,
+}
+
+impl QueryOptions {
+    pub fn new(server: SocketAddr) -> Self {
+        Self {
+            server,
+            transport: Transport::Udp,
+            recursion_desired: true,
+            dnssec_ok: false,
+            edns_size: Some(Edns::default().udp_payload_size),
+            timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+/// A raw response, along with how it was obtained.
+#[derive(Clone, Debug)]
+pub struct Response {
+    pub data: Vec<u8>,
+    pub server: SocketAddr,
+    pub transport: Transport,
+    pub elapsed: Duration,
+}
+
+impl Response {
+    pub fn view(&self) -> Result<MessageView<'_>, Report> {
+        MessageView::parse(&self.data)
+    }
+}
+
+/// Send a query and wait for its response, retrying over TCP if it was truncated.
+pub async fn query(name: &Name, qtype: QType, options: &QueryOptions) -> Result<Response, Report> {
+    let id = rand::random();
+
+    let mut message = MessageBuilder::query(id, options.recursion_desired).question(Question {
+        qname: name.clone(),
+        qtype,
+        qclass: QClass::IN,
+    });
+
+    if let Some(udp_payload_size) = options.edns_size {
+        message = message.edns(Edns {
+            udp_payload_size,
+            dnssec_ok: options.dnssec_ok,
+            ..Edns::default()
+        });
+    }
+
+    let request = message.to_bytes()?;
+    let mut transport = options.transport;
+
+    loop {
+        let start = Instant::now();
+
+        let exchange = exchange(&request, options.server, transport);
+        let data = timeout(options.timeout, exchange).await.map_err(|_| {
+            eyre!(
+                "No response from {} after {:?}",
+                options.server,
+                options.timeout
+            )
+        })??;
+
+        let elapsed = start.elapsed();
+        let view = MessageView::parse(&data)?;
+
+        if view.header().id != id {
+            return Err(eyre!(
+                "Response id {} does not match query id {id}",
+                view.header().id
+            ));
+        }
+
+        if view.header().tc() && transport == Transport::Udp {
+            transport = Transport::Tcp;
+            continue;
+        }
+
+        return Ok(Response {
+            data,
+            server: options.server,
+            transport,
+            elapsed,
+        });
+    }
+}
+
+async fn exchange(
+    request: &[u8],
+    server: SocketAddr,
+    transport: Transport,
+) -> Result<Vec<u8>, Report> {
+    match transport {
+        Transport::Udp => {
+            let local: SocketAddr = match server {
+                SocketAddr::V4(_) => "0.0.0.0:0".parse()?,
+                SocketAddr::V6(_) => "[::]:0".parse()?,
+            };
+
+            let socket = UdpSocket::bind(local).await?;
+            socket.connect(server).await?;
+            socket.send(request).await?;
+
+            let mut buf = vec![0; u16::MAX as usize];
+            let count = socket.recv(&mut buf).await?;
+            buf.truncate(count);
+
+            Ok(buf)
+        }
+        Transport::Tcp => {
+            let mut stream = TcpStream::connect(server).await?;
+
+            stream
+                .write_all(&(request.len() as u16).to_be_bytes())
+                .await?;
+            stream.write_all(request).await?;
+
+            let len = stream.read_u16().await?;
+            let mut buf = vec![0; len as usize];
+            stream.read_exact(&mut buf).await?;
+
+            Ok(buf)
+        }
+    }
+}
+
+/// Format a response the way `dig` does.
+pub struct DigOutput<'a>(pub &'a Response);
+
+impl fmt::Display for DigOutput<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let response = self.0;
+
+        let Ok(message) = response.view() else {
+            return writeln!(f, ";; Malformed response of {} bytes", response.data.len());
+        };
+
+        let header = message.header();
+
+        writeln!(
+            f,
+            ";; ->>HEADER<<- opcode: {}, status: {}, id: {}",
+            opcode_name(header.opcode()),
+            rcode_name(header.rcode()),
+            header.id
+        )?;
+
+        let flags = [
+            ("qr", header.qr()),
+            ("aa", header.aa()),
+            ("tc", header.tc()),
+            ("rd", header.rd()),
+            ("ra", header.ra()),
+        ];
+
+        write!(f, ";; flags:")?;
+
+        for (flag, set) in flags {
+            if set {
+                write!(f, " {flag}")?;
+            }
+        }
+
+        writeln!(
+            f,
+            "; QUERY: {}, ANSWER: {}, AUTHORITY: {}, ADDITIONAL: {}",
+            header.qdcount, header.ancount, header.nscount, header.arcount
+        )?;
+
+        if let Some(edns) = message.edns() {
+            writeln!(f, "\n;; OPT PSEUDOSECTION:")?;
+            write!(f, "; EDNS: version: {}, flags:", edns.version)?;
+
+            if edns.dnssec_ok {
+                write!(f, " do")?;
+            }
+
+            writeln!(f, "; udp: {}", edns.udp_payload_size)?;
+        }
+
+        writeln!(f, "\n;; QUESTION SECTION:")?;
+
+        for question in message.questions() {
+            writeln!(
+                f,
+                ";{}\t\t{}\t{}",
+                fqdn(&question.qname.to_string()),
+                class_name(question.qclass),
+                type_name(question.qtype)
+            )?;
+        }
+
+        let sections = [
+            ("ANSWER", message.answers()),
+            ("AUTHORITY", message.authorities()),
+            ("ADDITIONAL", message.additionals()),
+        ];
+
+        for (section, records) in sections {
+            let records = records
+                .filter(|record| record.rtype != QType::OPT as u16)
+                .collect::<Vec<_>>();
+
+            if records.is_empty() {
+                continue;
+            }
+
+            writeln!(f, "\n;; {section} SECTION:")?;
+
+            for record in records {
+                fmt_record(&message, &record, f)?;
+            }
+        }
+
+        writeln!(f, "\n;; Query time: {} msec", response.elapsed.as_millis())?;
+        writeln!(f, ";; SERVER: {} ({})", response.server, response.transport)?;
+        writeln!(f, ";; MSG SIZE  rcvd: {}", response.data.len())
+    }
+}
+
+fn fmt_record(
+    message: &MessageView<'_>,
+    record: &RecordView<'_>,
+    f: &mut fmt::Formatter<'_>,
+) -> fmt::Result {
+    writeln!(
+        f,
+        "{}\t{}\t{}\t{}\t{}",
+        fqdn(&record.name.to_string()),
+        record.ttl,
+        class_name(record.rclass),
+        type_name(record.rtype),
+        RDataView(message, record)
+    )
+}
+
+/// Format the data of a record in presentation format.
+pub(crate) struct RDataView<'a, 'b>(pub &'b MessageView<'a>, pub &'b RecordView<'a>);
+
+impl fmt::Display for RDataView<'_, '_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let RDataView(message, record) = self;
+        let rdata = record.rdata();
+
+        let parsed = QType::try_from(record.rtype)
+            .and_then(|qtype| Record::from_rdata(qtype, message.as_bytes(), record.rdata.clone()));
+
+        match parsed {
+            Ok(parsed) => write!(f, "{}", parsed.rdata()),
+            Err(_) if record.rtype == QType::AAAA as u16 && rdata.len() == 16 => {
+                let octets: [u8; 16] = rdata.try_into().unwrap();
+                write!(f, "{}", Ipv6Addr::from(octets))
+            }
+            // Unknown record types are shown in the generic format of RFC 3597.
+            Err(_) => {
+                write!(f, "\\# {}", rdata.len())?;
+
+                if !rdata.is_empty() {
+                    write!(f, " ")?;
+                }
+
+                for byte in rdata {
+                    write!(f, "{byte:02x}")?;
+                }
+
+                Ok(())
+            }
+        }
+    }
+}
+
+fn fqdn(name: &str) -> String {
+    if name.ends_with('.') {
+        name.to_string()
+    } else {
+        format!("{name}.")
+    }
+}
+
+pub(crate) fn type_name(rtype: u16) -> String {
+    match QType::try_from(rtype) {
+        Ok(qtype) => qtype.to_string(),
+        Err(_) => format!("TYPE{rtype}"),
+    }
+}
+
+fn class_name(rclass: u16) -> String {
+    match rclass {
+        1 => "IN".to_string(),
+        3 => "CH".to_string(),
+        255 => "ANY".to_string(),
+        _ => format!("CLASS{rclass}"),
+    }
+}
+
+fn opcode_name(opcode: u8) -> String {
+    match opcode {
+        0 => "QUERY".to_string(),
+        1 => "IQUERY".to_string(),
+        2 => "STATUS".to_string(),
+        4 => "NOTIFY".to_string(),
+        5 => "UPDATE".to_string(),
+        _ => format!("OPCODE{opcode}"),
+    }
+}
+
+pub(crate) fn rcode_name(rcode: u8) -> String {
+    match rcode {
+        0 => "NOERROR".to_string(),
+        1 => "FORMERR".to_string(),
+        2 => "SERVFAIL".to_string(),
+        3 => "NXDOMAIN".to_string(),
+        4 => "NOTIMP".to_string(),
+        5 => "REFUSED".to_string(),
+        _ => format!("RCODE{rcode}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dig_output() {
+        // news.ycombinator.com A, with an AAAA and an unknown record added.
+        let data = vec![
+            13, 208, 129, 128, 0, 1, 0, 3, 0, 0, 0, 0, 4, 110, 101, 119, 115, 11, 121, 99, 111,
+            109, 98, 105, 110, 97, 116, 111, 114, 3, 99, 111, 109, 0, 0, 1, 0, 1, 192, 12, 0, 1, 0,
+            1, 0, 0, 0, 1, 0, 4, 209, 216, 230, 240, 192, 12, 0, 28, 0, 1, 0, 0, 0, 60, 0, 16, 32,
+            1, 13, 184, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 192, 12, 0, 99, 0, 1, 0, 0, 0, 60, 0,
+            2, 171, 205,
+        ];
+
+        let response = Response {
+            data,
+            server: "127.0.0.1:53".parse().unwrap(),
+            transport: Transport::Udp,
+            elapsed: Duration::from_millis(12),
+        };
+
+        let output = DigOutput(&response).to_string();
+
+        assert_eq!(
+            output,
+            ";; ->>HEADER<<- opcode: QUERY, status: NOERROR, id: 3536\n\
+             ;; flags: qr rd ra; QUERY: 1, ANSWER: 3, AUTHORITY: 0, ADDITIONAL: 0\n\
+             \n\
+             ;; QUESTION SECTION:\n\
+             ;news.ycombinator.com.\t\tIN\tA\n\
+             \n\
+             ;; ANSWER SECTION:\n\
+             news.ycombinator.com.\t1\tIN\tA\t209.216.230.240\n\
+             news.ycombinator.com.\t60\tIN\tAAAA\t2001:db8::1\n\
+             news.ycombinator.com.\t60\tIN\tTYPE99\t\\# 2 abcd\n\
+             \n\
+             ;; Query time: 12 msec\n\
+             ;; SERVER: 127.0.0.1:53 (UDP)\n\
+             ;; MSG SIZE  rcvd: 96\n"
+        );
+    }
+}
@@ -0,0 +1,472 @@
+use std::{
+    collections::HashSet, net::SocketAddr, path::Path, path::PathBuf, str::FromStr, time::Duration,
+};
+
+use color_eyre::{
+    eyre::{eyre, WrapErr},
+    Report,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::data::Name;
+
+/// Server settings, read from a TOML file and overridden by command line flags.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Addresses to listen on over UDP and TCP.
+    pub listen: Vec<SocketAddr>,
+
+    /// Address to serve Prometheus metrics on, over HTTP.
+    pub metrics: Option<SocketAddr>,
+
+    /// Db files, loaded in order into a single database.
+    pub db: Vec<PathBuf>,
+
+    /// Keep the records of the db files in a compact, read-only arena, for
+    /// very large zones. Updates while running, eg. through
+    /// [`crate::server::ServerHandle::insert`], first move every record back
+    /// to regular trie nodes, until the next reload compacts them again.
+    pub compact: bool,
+
+    /// File to append every upstream response to, for `replay` to serve later.
+    pub record: Option<PathBuf>,
+
+    /// File of recorded responses to answer from, instead of any upstream.
+    pub replay: Option<PathBuf>,
+
+    /// Zones we are authoritative for. Names in these zones are never
+    /// forwarded, and get NXDOMAIN when missing from the db.
+    pub zones: Vec<String>,
+
+    /// Chain of plugins answering queries, in order. Defaults to
+    /// `faults`, `local`, `cache` and `forward`, or `replay` when replaying.
+    pub plugins: Vec<Plugin>,
+
+    #[serde(rename = "upstream")]
+    pub upstreams: Vec<Upstream>,
+
+    /// Which upstream to forward names to, by longest matching zone.
+    /// Without any rule, everything goes to the first upstream.
+    #[serde(rename = "forward")]
+    pub rules: Vec<ForwardRule>,
+
+    pub cache: CacheConfig,
+
+    pub log: LogConfig,
+
+    /// Where to log every query as a line of JSON, if anywhere.
+    pub query_log: Option<QueryLogConfig>,
+
+    /// Where to send dnstap frames, if anywhere.
+    pub dnstap: Option<DnstapConfig>,
+}
+
+/// A step of the chain answering queries, see [`crate::plugins`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Plugin {
+    /// Apply the `$FAULT` rules of the db files.
+    Faults,
+    /// Answer from the db files, and for our zones.
+    Local,
+    /// Keep upstream responses for as long as their TTLs allow.
+    Cache,
+    /// Answer from the file given by `replay`.
+    Replay,
+    /// Forward to the upstreams.
+    Forward,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Upstream {
+    pub name: String,
+    pub address: SocketAddr,
+    #[serde(default = "Upstream::default_timeout_ms")]
+    pub timeout_ms: u64,
+}
+
+impl Plugin {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Faults => "faults",
+            Self::Local => "local",
+            Self::Cache => "cache",
+            Self::Replay => "replay",
+            Self::Forward => "forward",
+        }
+    }
+}
+
+impl Upstream {
+    fn default_timeout_ms() -> u64 {
+        2000
+    }
+
+    pub fn new(name: impl Into<String>, address: SocketAddr) -> Self {
+        Self {
+            name: name.into(),
+            address,
+            timeout_ms: Self::default_timeout_ms(),
+        }
+    }
+
+    pub fn timeout(&self) -> Duration {
+        Duration::from_millis(self.timeout_ms)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ForwardRule {
+    pub zone: String,
+    pub upstream: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CacheConfig {
+    /// Maximum number of cached upstream responses, 0 to disable the cache.
+    pub size: usize,
+    pub min_ttl: u32,
+    pub max_ttl: u32,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            size: 1024,
+            min_ttl: 0,
+            max_ttl: 86400,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    /// Filter in the syntax of `RUST_LOG`, which takes precedence when set.
+    pub level: String,
+    pub ansi: bool,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            level: "info".to_string(),
+            ansi: true,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "output", rename_all = "lowercase", deny_unknown_fields)]
+pub enum QueryLogConfig {
+    Stdout,
+    /// A file, renamed to `<path>.1` once larger than `max_size` bytes,
+    /// keeping up to `max_files` of the previous ones.
+    File {
+        path: PathBuf,
+        #[serde(default = "QueryLogConfig::default_max_size")]
+        max_size: u64,
+        #[serde(default = "QueryLogConfig::default_max_files")]
+        max_files: usize,
+    },
+    /// A Unix stream socket, which we reconnect to whenever it goes away.
+    Socket {
+        path: PathBuf,
+    },
+}
+
+impl QueryLogConfig {
+    fn default_max_size() -> u64 {
+        100 * 1024 * 1024
+    }
+
+    fn default_max_files() -> usize {
+        5
+    }
+}
+
+/// `-` for stdout, `unix:<path>` for a socket, or the path of a file.
+impl FromStr for QueryLogConfig {
+    type Err = Report;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "-" {
+            return Ok(Self::Stdout);
+        }
+
+        if let Some(path) = s.strip_prefix("unix:") {
+            return Ok(Self::Socket { path: path.into() });
+        }
+
+        Ok(Self::File {
+            path: s.into(),
+            max_size: Self::default_max_size(),
+            max_files: Self::default_max_files(),
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "output", rename_all = "lowercase", deny_unknown_fields)]
+pub enum DnstapConfig {
+    File {
+        path: PathBuf,
+    },
+    /// A Frame Streams reader, eg. `dnstap -u <path>`.
+    Socket {
+        path: PathBuf,
+    },
+}
+
+/// `unix:<path>` for a socket, or the path of a file.
+impl FromStr for DnstapConfig {
+    type Err = Report;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix("unix:") {
+            Some(path) => Ok(Self::Socket { path: path.into() }),
+            None => Ok(Self::File { path: s.into() }),
+        }
+    }
+}
+
+/// Port used when no listen address is given.
+pub const DEFAULT_PORT: u16 = 7777;
+
+impl Config {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, Report> {
+        let path = path.as_ref();
+
+        let text = std::fs::read_to_string(path)
+            .wrap_err_with(|| format!("Failed to read config file {}", path.display()))?;
+
+        Self::from_str(&text).wrap_err_with(|| format!("Invalid config file {}", path.display()))
+    }
+
+    /// Fill in defaults and check that the settings are consistent.
+    pub fn validate(mut self) -> Result<Self, Report> {
+        if self.listen.is_empty() {
+            self.listen
+                .push(SocketAddr::from(([127, 0, 0, 1], DEFAULT_PORT)));
+        }
+
+        if self.db.is_empty() {
+            return Err(eyre!("No db file given, set `db` or pass --db"));
+        }
+
+        if self.record.is_some() && self.replay.is_some() {
+            return Err(eyre!("Cannot both record and replay upstream responses"));
+        }
+
+        let mut plugins = HashSet::new();
+
+        for plugin in &self.plugins {
+            if !plugins.insert(plugin) {
+                return Err(eyre!("Plugin `{}` is used twice", plugin.name()));
+            }
+        }
+
+        match (self.chain().contains(&Plugin::Replay), &self.replay) {
+            (true, None) => return Err(eyre!("The replay plugin requires `replay` to be set")),
+            (false, Some(_)) => return Err(eyre!("`replay` is set, but not the replay plugin")),
+            _ => (),
+        }
+
+        for zone in &self.zones {
+            Name::from_str(zone).wrap_err_with(|| format!("Invalid zone `{zone}`"))?;
+        }
+
+        let mut names = HashSet::new();
+
+        for upstream in &self.upstreams {
+            if !names.insert(upstream.name.as_str()) {
+                return Err(eyre!("Upstream `{}` is defined twice", upstream.name));
+            }
+
+            if upstream.timeout_ms == 0 {
+                return Err(eyre!("Upstream `{}` has a zero timeout", upstream.name));
+            }
+        }
+
+        for rule in &self.rules {
+            Name::from_str(&rule.zone)
+                .wrap_err_with(|| format!("Invalid zone `{}` in forward rule", rule.zone))?;
+
+            if !names.contains(rule.upstream.as_str()) {
+                return Err(eyre!(
+                    "Forward rule for `{}` uses unknown upstream `{}`",
+                    rule.zone,
+                    rule.upstream
+                ));
+            }
+        }
+
+        if self.cache.min_ttl > self.cache.max_ttl {
+            return Err(eyre!(
+                "Cache min_ttl ({}) is larger than max_ttl ({})",
+                self.cache.min_ttl,
+                self.cache.max_ttl
+            ));
+        }
+
+        tracing_subscriber::EnvFilter::try_new(&self.log.level)
+            .wrap_err_with(|| format!("Invalid log level `{}`", self.log.level))?;
+
+        Ok(self)
+    }
+
+    /// The plugins to answer queries with, in order.
+    pub fn chain(&self) -> Vec<Plugin> {
+        if !self.plugins.is_empty() {
+            return self.plugins.clone();
+        }
+
+        match self.replay {
+            Some(_) => vec![Plugin::Faults, Plugin::Local, Plugin::Replay],
+            None => vec![
+                Plugin::Faults,
+                Plugin::Local,
+                Plugin::Cache,
+                Plugin::Forward,
+            ],
+        }
+    }
+
+    pub fn to_toml(&self) -> Result<String, Report> {
+        Ok(toml::to_string_pretty(self)?)
+    }
+}
+
+impl FromStr for Config {
+    type Err = Report;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(toml::from_str(s)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = r#"
+listen = ["127.0.0.1:53", "[::1]:53"]
+metrics = "127.0.0.1:9153"
+db = ["test.txt"]
+zones = ["local.dev"]
+
+[[upstream]]
+name = "cloudflare"
+address = "1.1.1.1:53"
+
+[[upstream]]
+name = "corp"
+address = "10.0.0.53:53"
+timeout_ms = 500
+
+[[forward]]
+zone = "corp.example.com"
+upstream = "corp"
+
+[cache]
+size = 100
+
+[log]
+level = "debug"
+
+[query_log]
+output = "file"
+path = "queries.jsonl"
+max_files = 2
+"#;
+
+    #[test]
+    fn parse() {
+        let config = Config::from_str(CONFIG).unwrap().validate().unwrap();
+
+        assert_eq!(config.listen.len(), 2);
+        assert_eq!(
+            config.metrics,
+            Some(SocketAddr::from(([127, 0, 0, 1], 9153)))
+        );
+        assert_eq!(config.upstreams[1].timeout(), Duration::from_millis(500));
+        assert_eq!(config.upstreams[0].timeout_ms, 2000);
+        assert_eq!(config.rules[0].upstream, "corp");
+        assert_eq!(config.cache.size, 100);
+        assert_eq!(config.cache.max_ttl, 86400);
+        assert_eq!(config.log.level, "debug");
+        assert_eq!(
+            config.query_log,
+            Some(QueryLogConfig::File {
+                path: "queries.jsonl".into(),
+                max_size: 100 * 1024 * 1024,
+                max_files: 2
+            })
+        );
+
+        let printed = config.to_toml().unwrap();
+        assert_eq!(Config::from_str(&printed).unwrap(), config);
+    }
+
+    #[test]
+    fn invalid() {
+        let error = |text: &str| {
+            Config::from_str(text)
+                .and_then(Config::validate)
+                .unwrap_err()
+                .to_string()
+        };
+
+        assert_eq!(error(""), "No db file given, set `db` or pass --db");
+
+        assert_eq!(
+            error(
+                r#"
+db = ["test.txt"]
+
+[[forward]]
+zone = "corp.example.com"
+upstream = "corp"
+"#
+            ),
+            "Forward rule for `corp.example.com` uses unknown upstream `corp`"
+        );
+
+        assert_eq!(
+            error(
+                r#"
+db = ["test.txt"]
+record = "fixtures.jsonl"
+replay = "fixtures.jsonl"
+"#
+            ),
+            "Cannot both record and replay upstream responses"
+        );
+
+        assert_eq!(
+            error(
+                r#"
+db = ["test.txt"]
+plugins = ["local", "forward", "local"]
+"#
+            ),
+            "Plugin `local` is used twice"
+        );
+
+        assert_eq!(
+            error(
+                r#"
+db = ["test.txt"]
+plugins = ["local", "replay"]
+"#
+            ),
+            "The replay plugin requires `replay` to be set"
+        );
+
+        assert!(error("bogus = 1").contains("unknown field `bogus`"));
+    }
+}
@@ -80,6 +80,19 @@ pub enum Opcode {
     Status = 2,
 }
 
+impl TryFrom<u8> for Opcode {
+    type Error = Report;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Opcode::Query),
+            1 => Ok(Opcode::IQuery),
+            2 => Ok(Opcode::Status),
+            v => Err(eyre!("Unsupported opcode: {v}")),
+        }
+    }
+}
+
 #[repr(u8)]
 #[derive(Copy, Clone, Debug, PartialEq, Eq, DekuRead, DekuWrite)]
 #[deku(type = "u8", bits = "4")]
@@ -112,6 +125,10 @@ pub struct ResourceRecord {
     #[deku(cond = "*qtype != QType::OPT", default = "QClass::NONE")]
     pub qclass: QClass,
 
+    /// For OPT records, the class field holds the requestor's UDP payload size.
+    #[deku(cond = "*qtype == QType::OPT", endian = "big")]
+    pub udp_payload_size: Option<u16>,
+
     #[deku(endian = "big")]
     pub ttl: i32,
 
@@ -119,11 +136,224 @@ pub struct ResourceRecord {
     pub rdlength: u16,
     #[deku(count = "rdlength")]
     pub data: Vec<u8>,
+}
+
+impl Message {
+    /// Start building a response to the given query.
+    pub fn response_to(query: &Message) -> MessageBuilder {
+        MessageBuilder::response(
+            query.header.id,
+            query.header.flags.opcode,
+            query.header.flags.rd,
+        )
+        .questions(query.questions.iter().cloned())
+    }
+
+    pub fn edns(&self) -> Option<Edns> {
+        self.additionals.iter().find_map(Edns::from_record)
+    }
+
+    /// Recompute the section counts and the length of every record's data.
+    pub fn finalize(&mut self) {
+        self.header.qdcount = self.questions.len() as u16;
+        self.header.ancount = self.answers.len() as u16;
+        self.header.nscount = self.authorities.len() as u16;
+        self.header.arcount = self.additionals.len() as u16;
+
+        let records = self
+            .answers
+            .iter_mut()
+            .chain(self.authorities.iter_mut())
+            .chain(self.additionals.iter_mut());
+
+        for record in records {
+            record.rdlength = record.data.len() as u16;
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct MessageBuilder {
+    id: u16,
+    flags: Flags,
+    questions: Vec<Question>,
+    answers: Vec<ResourceRecord>,
+    authorities: Vec<ResourceRecord>,
+    additionals: Vec<ResourceRecord>,
+    edns: Option<Edns>,
+}
+
+impl MessageBuilder {
+    pub fn response(id: u16, opcode: Opcode, rd: bool) -> Self {
+        Self {
+            id,
+            flags: Flags {
+                rd,
+                ..Flags::answer(opcode)
+            },
+            questions: vec![],
+            answers: vec![],
+            authorities: vec![],
+            additionals: vec![],
+            edns: None,
+        }
+    }
+
+    pub fn query(id: u16, rd: bool) -> Self {
+        Self {
+            flags: Flags {
+                qr: false,
+                aa: false,
+                ..Flags::answer(Opcode::Query)
+            },
+            ..Self::response(id, Opcode::Query, rd)
+        }
+    }
+
+    pub fn question(mut self, question: Question) -> Self {
+        self.questions.push(question);
+        self
+    }
+
+    pub fn questions(mut self, questions: impl IntoIterator<Item = Question>) -> Self {
+        self.questions.extend(questions);
+        self
+    }
+
+    pub fn answer(mut self, record: ResourceRecord) -> Self {
+        self.answers.push(record);
+        self
+    }
+
+    pub fn answers(mut self, records: impl IntoIterator<Item = ResourceRecord>) -> Self {
+        self.answers.extend(records);
+        self
+    }
 
-    #[deku(cond = "*qtype == QType::OPT")]
-    pub options_code: Option<u8>,
-    #[deku(cond = "*qtype == QType::OPT")]
-    pub options_length: Option<u8>,
+    pub fn authority(mut self, record: ResourceRecord) -> Self {
+        self.authorities.push(record);
+        self
+    }
+
+    pub fn additional(mut self, record: ResourceRecord) -> Self {
+        self.additionals.push(record);
+        self
+    }
+
+    pub fn edns(mut self, edns: Edns) -> Self {
+        self.edns = Some(edns);
+        self
+    }
+
+    pub fn rcode(mut self, rcode: RCode) -> Self {
+        self.flags.rcode = rcode;
+        self
+    }
+
+    pub fn flags(mut self, flags: Flags) -> Self {
+        self.flags = flags;
+        self
+    }
+
+    pub fn authoritative(mut self, aa: bool) -> Self {
+        self.flags.aa = aa;
+        self
+    }
+
+    pub fn truncated(mut self, tc: bool) -> Self {
+        self.flags.tc = tc;
+        self
+    }
+
+    pub fn recursion_available(mut self, ra: bool) -> Self {
+        self.flags.ra = ra;
+        self
+    }
+
+    pub fn build(self) -> Message {
+        let mut additionals = self.additionals;
+        additionals.extend(self.edns.map(|edns| edns.to_record()));
+
+        let mut message = Message {
+            header: Header {
+                id: self.id,
+                flags: self.flags,
+                qdcount: 0,
+                ancount: 0,
+                nscount: 0,
+                arcount: 0,
+            },
+            questions: self.questions,
+            answers: self.answers,
+            authorities: self.authorities,
+            additionals,
+        };
+
+        message.finalize();
+        message
+    }
+
+    pub fn to_bytes(self) -> Result<Vec<u8>, Report> {
+        Ok(self.build().to_bytes()?)
+    }
+}
+
+/// EDNS(0) parameters, carried in an OPT pseudo-record (RFC 6891).
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Edns {
+    pub udp_payload_size: u16,
+    pub extended_rcode: u8,
+    pub version: u8,
+    pub dnssec_ok: bool,
+}
+
+impl Default for Edns {
+    fn default() -> Self {
+        Self {
+            udp_payload_size: 1232,
+            extended_rcode: 0,
+            version: 0,
+            dnssec_ok: false,
+        }
+    }
+}
+
+impl Edns {
+    pub fn from_parts(udp_payload_size: u16, ttl: u32) -> Self {
+        Self {
+            udp_payload_size,
+            extended_rcode: (ttl >> 24) as u8,
+            version: (ttl >> 16) as u8,
+            dnssec_ok: ttl & 0x8000 != 0,
+        }
+    }
+
+    pub fn ttl(&self) -> u32 {
+        (self.extended_rcode as u32) << 24
+            | (self.version as u32) << 16
+            | if self.dnssec_ok { 0x8000 } else { 0 }
+    }
+
+    pub fn from_record(record: &ResourceRecord) -> Option<Self> {
+        if record.qtype != QType::OPT {
+            return None;
+        }
+
+        let udp_payload_size = record.udp_payload_size.unwrap_or(512);
+        Some(Self::from_parts(udp_payload_size, record.ttl as u32))
+    }
+
+    pub fn to_record(&self) -> ResourceRecord {
+        ResourceRecord {
+            name: Name::root(),
+            qtype: QType::OPT,
+            qclass: QClass::NONE,
+            udp_payload_size: Some(self.udp_payload_size),
+            ttl: self.ttl() as i32,
+            rdlength: 0,
+            data: vec![],
+        }
+    }
 }
 
 #[derive(Clone, Hash, PartialEq, Eq)]
@@ -132,14 +362,52 @@ pub struct Name {
 }
 
 impl FromStr for Name {
-    type Err = ();
+    type Err = Report;
 
+    /// Parse a domain name, converting any non-ASCII labels to their
+    /// IDNA A-label (`xn--`) form as per UTS-46.
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        Ok(Self::new(s.to_string()))
+        let s = s.strip_suffix('.').unwrap_or(s);
+
+        if s.is_empty() {
+            return Ok(Self { labels: vec![] });
+        }
+
+        let labels = s
+            .split('.')
+            .map(|label| to_a_label(label).map_err(|e| eyre!("Invalid domain name {s}: {e}")))
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self { labels })
+    }
+}
+
+/// Convert a single label to its A-label form. ASCII labels are kept as
+/// written, case included, once checked to be valid if they are A-labels.
+fn to_a_label(label: &str) -> Result<Label, Report> {
+    let ascii = if label.is_ascii() {
+        let is_a_label = label.len() >= 4 && label[..4].eq_ignore_ascii_case("xn--");
+
+        if is_a_label {
+            let (_, result) = idna::domain_to_unicode(label);
+            result.map_err(|_| eyre!("invalid A-label {label}"))?;
+        }
+
+        label.to_string()
+    } else {
+        idna::domain_to_ascii(label).map_err(|e| eyre!("{e}"))?
+    };
+
+    match ascii.len() {
+        0 => Err(eyre!("empty label")),
+        1..=63 => Ok(Label::new(ascii.into_bytes())),
+        _ => Err(eyre!("label too long")),
     }
 }
 
 impl Name {
+    /// Build a name from its labels as given, without any conversion.
+    /// Use [`Name::from_str`] to parse user input.
     pub fn new(data: String) -> Self {
         let labels = data
             .split('.')
@@ -149,6 +417,10 @@ impl Name {
         Self { labels }
     }
 
+    pub fn root() -> Self {
+        Self { labels: vec![] }
+    }
+
     pub fn is_empty(&self) -> bool {
         self.labels.is_empty()
     }
@@ -162,14 +434,34 @@ impl Name {
         deku::DekuWrite::write(self, &mut output, ()).unwrap();
         output.into_vec()
     }
+
+    /// The name with a trailing dot, as written in zone files.
+    pub fn to_fqdn(&self) -> String {
+        if self.is_empty() {
+            ".".to_string()
+        } else {
+            format!("{self}.")
+        }
+    }
+
+    /// Render the name with its A-labels decoded to Unicode.
+    pub fn to_unicode(&self) -> String {
+        let (unicode, _) = idna::domain_to_unicode(&self.to_string());
+        unicode
+    }
 }
 
+/// Use the alternate flag (`{:#}`) to show the Unicode form of the name.
 impl fmt::Display for Name {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         if self.is_empty() {
             return write!(f, ".");
         }
 
+        if f.alternate() {
+            return write!(f, "{}", self.to_unicode());
+        }
+
         let mut labels = self.labels.iter();
 
         if let Some(label) = labels.next() {
@@ -184,6 +476,14 @@ impl fmt::Display for Name {
     }
 }
 
+impl FromIterator<Label> for Name {
+    fn from_iter<T: IntoIterator<Item = Label>>(iter: T) -> Self {
+        Self {
+            labels: iter.into_iter().collect(),
+        }
+    }
+}
+
 impl fmt::Debug for Name {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         fmt::Display::fmt(self, f)
@@ -283,6 +583,12 @@ impl Label {
     }
 }
 
+impl AsRef<[u8]> for Label {
+    fn as_ref(&self) -> &[u8] {
+        self.as_bytes()
+    }
+}
+
 impl fmt::Display for Label {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "{}", String::from_utf8_lossy(&self.0))
@@ -296,7 +602,7 @@ impl fmt::Debug for Label {
 }
 
 #[repr(u16)]
-#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, DekuRead, DekuWrite)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, DekuRead, DekuWrite)]
 #[deku(type = "u16", endian = "big")]
 pub enum QType {
     A = 1,
@@ -316,6 +622,7 @@ pub enum QType {
     MX = 15,
     TXT = 16,
     AAAA = 28,
+    SRV = 33,
     OPT = 41,
     SVCB = 64,
     HTTPS = 65,
@@ -324,6 +631,7 @@ pub enum QType {
     MAILB = 253,
     MAILA = 254,
     ANY = 255,
+    CAA = 257,
 }
 
 impl fmt::Display for QType {
@@ -354,6 +662,7 @@ impl FromStr for QType {
             "MX" => Ok(QType::MX),
             "TXT" => Ok(QType::TXT),
             "AAAA" => Ok(QType::AAAA),
+            "SRV" => Ok(QType::SRV),
             "OPT" => Ok(QType::OPT),
             "SVCB" => Ok(QType::SVCB),
             "HTTPS" => Ok(QType::HTTPS),
@@ -361,11 +670,48 @@ impl FromStr for QType {
             "MAILB" => Ok(QType::MAILB),
             "MAILA" => Ok(QType::MAILA),
             "ANY" => Ok(QType::ANY),
+            "CAA" => Ok(QType::CAA),
             s => Err(eyre!("Invalid QType: {s}")),
         }
     }
 }
 
+impl TryFrom<u16> for QType {
+    type Error = Report;
+
+    fn try_from(value: u16) -> Result<Self, Self::Error> {
+        match value {
+            1 => Ok(QType::A),
+            2 => Ok(QType::NS),
+            3 => Ok(QType::MD),
+            4 => Ok(QType::MF),
+            5 => Ok(QType::CNAME),
+            6 => Ok(QType::SOA),
+            7 => Ok(QType::MB),
+            8 => Ok(QType::MG),
+            9 => Ok(QType::MR),
+            10 => Ok(QType::NULL),
+            11 => Ok(QType::WKS),
+            12 => Ok(QType::PTR),
+            13 => Ok(QType::HINFO),
+            14 => Ok(QType::MINFO),
+            15 => Ok(QType::MX),
+            16 => Ok(QType::TXT),
+            28 => Ok(QType::AAAA),
+            33 => Ok(QType::SRV),
+            41 => Ok(QType::OPT),
+            64 => Ok(QType::SVCB),
+            65 => Ok(QType::HTTPS),
+            252 => Ok(QType::AXFR),
+            253 => Ok(QType::MAILB),
+            254 => Ok(QType::MAILA),
+            255 => Ok(QType::ANY),
+            257 => Ok(QType::CAA),
+            v => Err(eyre!("Invalid QType: {v}")),
+        }
+    }
+}
+
 #[repr(u16)]
 #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, DekuRead, DekuWrite)]
 #[deku(type = "u16", endian = "big")]
@@ -379,6 +725,22 @@ pub enum QClass {
     ANY = 255,
 }
 
+impl TryFrom<u16> for QClass {
+    type Error = Report;
+
+    fn try_from(value: u16) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(QClass::NONE),
+            1 => Ok(QClass::IN),
+            2 => Ok(QClass::CS),
+            3 => Ok(QClass::CH),
+            4 => Ok(QClass::HS),
+            255 => Ok(QClass::ANY),
+            v => Err(eyre!("Invalid QClass: {v}")),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -431,4 +793,94 @@ mod tests {
         let (_, message) = Message::from_bytes((data, 0)).unwrap();
         println!("Message: {message:#?}");
     }
+
+    #[test]
+    fn build_response() {
+        let query = Message {
+            header: Header {
+                id: 1234,
+                flags: Flags {
+                    qr: false,
+                    rd: true,
+                    ..Flags::answer(Opcode::Query)
+                },
+                qdcount: 1,
+                ancount: 0,
+                nscount: 0,
+                arcount: 0,
+            },
+            questions: vec![Question {
+                qname: Name::new("denis.local.dev".to_string()),
+                qtype: QType::A,
+                qclass: QClass::IN,
+            }],
+            answers: vec![],
+            authorities: vec![],
+            additionals: vec![],
+        };
+
+        let answer = ResourceRecord {
+            name: Name::new("denis.local.dev".to_string()),
+            qtype: QType::A,
+            qclass: QClass::IN,
+            udp_payload_size: None,
+            ttl: 1,
+            rdlength: 0,
+            data: vec![127, 0, 0, 1],
+        };
+
+        let response = Message::response_to(&query)
+            .answer(answer)
+            .edns(Edns::default())
+            .rcode(RCode::NoError)
+            .recursion_available(true)
+            .build();
+
+        assert_eq!(response.header.id, 1234);
+        assert!(response.header.flags.qr);
+        assert!(response.header.flags.rd);
+        assert!(response.header.flags.ra);
+        assert_eq!(response.header.qdcount, 1);
+        assert_eq!(response.header.ancount, 1);
+        assert_eq!(response.header.nscount, 0);
+        assert_eq!(response.header.arcount, 1);
+        assert_eq!(response.answers[0].rdlength, 4);
+        assert_eq!(response.edns(), Some(Edns::default()));
+    }
+
+    #[test]
+    fn edns_ttl() {
+        let edns = Edns {
+            udp_payload_size: 4096,
+            extended_rcode: 1,
+            version: 0,
+            dnssec_ok: true,
+        };
+
+        assert_eq!(edns.ttl(), 0x0100_8000);
+        assert_eq!(Edns::from_parts(4096, edns.ttl()), edns);
+        assert_eq!(Edns::from_record(&edns.to_record()), Some(edns));
+    }
+
+    #[test]
+    fn name_idna() {
+        let unicode = Name::from_str("bücher.local.dev").unwrap();
+        let ascii = Name::from_str("xn--bcher-kva.local.dev").unwrap();
+
+        assert_eq!(unicode, ascii);
+        assert_eq!(unicode.to_string(), "xn--bcher-kva.local.dev");
+        assert_eq!(format!("{unicode:#}"), "bücher.local.dev");
+
+        // Only the labels that need converting are touched.
+        let mixed = Name::from_str("Bücher.API.local.dev").unwrap();
+        assert_eq!(mixed.to_string(), "xn--bcher-kva.API.local.dev");
+    }
+
+    #[test]
+    fn name_invalid() {
+        assert!(Name::from_str("foo..dev").is_err());
+        assert!(Name::from_str(&"a".repeat(64)).is_err());
+        assert!(Name::from_str("xn--a-ecp.dev").is_err());
+        assert!(Name::from_str(".").unwrap().is_empty());
+    }
 }
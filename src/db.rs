@@ -1,22 +1,50 @@
 use core::fmt;
-use std::{path::Path, str::FromStr};
+use std::{net::Ipv4Addr, path::Path, str::FromStr};
 
-use color_eyre::{eyre::eyre, Report};
+use bytes::Bytes;
+use color_eyre::{
+    eyre::{eyre, WrapErr},
+    Report,
+};
 
 use crate::{
     data::{Label, Name, QType},
-    record::Record,
-    trie::{Key, Trie},
+    fault::Fault,
+    record::{Record, RecordMap, MAX_STRING_LENGTH},
+    svcb::SvcParams,
+    trie::{Key, Match, Trie},
 };
 
+/// Whether a name is known to the database, regardless of record types.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Presence {
+    /// The name exists, possibly only through a wildcard or as the parent of other names.
+    Exists,
+    /// The name does not exist, but one of its ancestors does.
+    Missing,
+    /// Neither the name nor any of its ancestors exist.
+    Unknown,
+}
+
+/// TTL of every record served from the database, and the only one db files
+/// may give.
+pub const TTL: u32 = 1;
+
 #[derive(Clone, Debug, Default)]
 pub struct Db {
-    trie: Trie<Label, Record>,
+    trie: Trie<Label, RecordMap>,
+    faults: Vec<Fault>,
 }
 
 impl fmt::Display for Db {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        fmt::Display::fmt(&self.trie, f)
+        fmt::Display::fmt(&self.trie, f)?;
+
+        for fault in &self.faults {
+            write!(f, "\n{fault}")?;
+        }
+
+        Ok(())
     }
 }
 
@@ -26,35 +54,156 @@ impl Db {
     }
 
     pub fn insert(&mut self, name: &Name, record: Record) {
-        let key = name
-            .labels()
-            .iter()
-            .map(|label| {
-                if label.as_bytes() == b"*" {
-                    Key::Wildcard
-                } else {
-                    Key::Exact(label.clone())
-                }
-            })
-            .rev();
+        self.trie.get_or_insert_default(to_key(name)).insert(record);
+    }
+
+    /// Remove the records of the given type at exactly `name`, or all of them for `ANY`.
+    pub fn remove(&mut self, name: &Name, qtype: QType) -> Vec<Record> {
+        let key = to_key(name).collect::<Vec<_>>();
 
-        self.trie.insert(key, record);
+        if qtype == QType::ANY {
+            return self
+                .trie
+                .remove(&key)
+                .map(|records| records.iter().map(|(_, r)| r.clone()).collect())
+                .unwrap_or_default();
+        }
+
+        let Some(records) = self.trie.get_mut(&key) else {
+            return vec![];
+        };
+
+        let removed = records.remove(qtype);
+
+        if records.is_empty() {
+            self.trie.remove(&key);
+        }
+
+        removed
     }
 
-    pub fn lookup(&self, name: &Name, qtype: QType) -> Option<&Record> {
-        let key = name
-            .labels()
+    pub fn add_fault(&mut self, fault: Fault) {
+        self.faults.push(fault);
+    }
+
+    pub fn faults(&self) -> &[Fault] {
+        &self.faults
+    }
+
+    /// The fault rules matching `name`, in the order of the db file.
+    pub fn faults_for<'a>(&'a self, name: &'a Name) -> impl Iterator<Item = &'a Fault> {
+        self.faults.iter().filter(move |fault| fault.matches(name))
+    }
+
+    /// Number of names with records.
+    pub fn len(&self) -> usize {
+        self.trie.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.trie.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (Name, &RecordMap)> {
+        self.trie
             .iter()
-            .map(|label| Key::Exact(label.clone()))
-            .rev()
-            .collect::<Vec<_>>();
+            .map(|(key, records)| (to_name(key), records))
+    }
+
+    /// Iterate over `name` and every name below it.
+    pub fn subtree(&self, name: &Name) -> impl Iterator<Item = (Name, &RecordMap)> {
+        let key = to_key(name).collect::<Vec<_>>();
 
         self.trie
-            .lookup(&key)
-            .filter(|record| qtype == QType::ANY || record.qtype() == qtype)
+            .subtree(&key)
+            .map(|(key, records)| (to_name(key), records))
+    }
+
+    /// Store the records in a compact, read-only arena, for very large zones.
+    /// The next update moves them back to regular trie nodes, which takes
+    /// time and memory proportional to the whole db.
+    pub fn compact(&mut self) {
+        self.trie.compact();
+    }
+
+    pub fn is_compact(&self) -> bool {
+        self.trie.is_compact()
+    }
+
+    /// Render the underlying trie as a Graphviz DOT graph.
+    pub fn to_dot(&self) -> String {
+        self.trie.to_dot()
+    }
+
+    pub fn presence(&self, name: &Name) -> Presence {
+        let key = to_query_key(name);
+
+        // Wildcards only stand for a single label, as in `lookup_all`.
+        let encloser = match self.trie.find(&key) {
+            Match::Exact(_) | Match::EmptyNonTerminal => return Presence::Exists,
+            _ if self.trie.lookup(&key).is_some() => return Presence::Exists,
+            Match::Wildcard { source, .. } => source,
+            Match::Miss { encloser, .. } => encloser,
+        };
+
+        if encloser.is_empty() {
+            Presence::Unknown
+        } else {
+            Presence::Missing
+        }
+    }
+
+    pub fn lookup(&self, name: &Name, qtype: QType) -> Option<&Record> {
+        self.lookup_all(name, qtype).into_iter().next()
+    }
+
+    pub fn lookup_all(&self, name: &Name, qtype: QType) -> Vec<&Record> {
+        let key = to_query_key(name);
+
+        let Some(records) = self.trie.lookup(&key) else {
+            return vec![];
+        };
+
+        if qtype == QType::ANY {
+            records.iter().map(|(_, record)| record).collect()
+        } else {
+            records.get_all(qtype).iter().collect()
+        }
     }
 }
 
+fn to_key(name: &Name) -> impl Iterator<Item = Key<Label>> + '_ {
+    name.labels()
+        .iter()
+        .map(|label| {
+            if label.as_bytes() == b"*" {
+                Key::Wildcard
+            } else {
+                Key::Exact(label.clone())
+            }
+        })
+        .rev()
+}
+
+/// Key of a queried name, where `*` is not a wildcard.
+fn to_query_key(name: &Name) -> Vec<Key<Label>> {
+    name.labels()
+        .iter()
+        .map(|label| Key::Exact(label.clone()))
+        .rev()
+        .collect()
+}
+
+fn to_name(key: Vec<Key<Label>>) -> Name {
+    key.into_iter()
+        .rev()
+        .map(|key| match key {
+            Key::Wildcard => Label::new(b"*".to_vec()),
+            Key::Exact(label) => label,
+        })
+        .collect()
+}
+
 pub fn load(path: impl AsRef<Path>) -> Result<Db, Report> {
     use std::fs::File;
 
@@ -62,61 +211,387 @@ pub fn load(path: impl AsRef<Path>) -> Result<Db, Report> {
     from_reader(file)
 }
 
-pub fn from_reader(reader: impl std::io::Read) -> Result<Db, Report> {
-    use std::io::{BufRead, BufReader};
+/// Load several db files, in order, into a single database.
+pub fn load_all(paths: &[impl AsRef<Path>]) -> Result<Db, Report> {
+    use std::fs::File;
+
+    let mut db = Db::new();
+
+    for path in paths {
+        let path = path.as_ref();
+
+        File::open(path)
+            .map_err(Report::from)
+            .and_then(|file| read_into(&mut db, file))
+            .wrap_err_with(|| format!("Failed to load {}", path.display()))?;
+    }
 
+    Ok(db)
+}
+
+pub fn from_reader(reader: impl std::io::Read) -> Result<Db, Report> {
     let mut db = Db::new();
+    read_into(&mut db, reader)?;
+
+    Ok(db)
+}
+
+fn read_into(db: &mut Db, reader: impl std::io::Read) -> Result<(), Report> {
+    use std::io::{BufRead, BufReader};
 
     let reader = BufReader::new(reader);
     for line in reader.lines() {
         let line = line?;
-        let line = line.trim();
 
-        if line.starts_with('#') || line.is_empty() {
-            continue;
+        if let Some(fault) = parse_fault(&line) {
+            db.add_fault(fault?);
+        } else if let Some(entry) = parse_entry(&line) {
+            let (name, record) = entry?;
+            db.insert(&name, record);
         }
+    }
 
-        let (name, record) = parse_line(line)?;
-        db.insert(&name, record);
+    Ok(())
+}
+
+/// Parse a line of a db file, or return `None` if it holds no record.
+pub(crate) fn parse_entry(line: &str) -> Option<Result<(Name, Record), Report>> {
+    let line = line.trim();
+
+    if line.starts_with('#') || line.starts_with(';') || line.is_empty() {
+        return None;
     }
 
-    Ok(db)
+    if let Some(ttl) = line.strip_prefix("$TTL") {
+        return parse_ttl(ttl.trim()).err().map(Err);
+    }
+
+    if line.starts_with("$FAULT") {
+        return None;
+    }
+
+    Some(parse_line(line))
+}
+
+/// Parse a `$FAULT` line of a db file, or return `None` if it is something else.
+pub(crate) fn parse_fault(line: &str) -> Option<Result<Fault, Report>> {
+    let line = line.trim();
+
+    line.starts_with("$FAULT").then(|| Fault::from_str(line))
 }
 
 fn parse_line(line: &str) -> Result<(Name, Record), Report> {
-    let mut parts = line.split_whitespace();
+    let Some((name, rest)) = next_token(line) else {
+        return Err(eyre!("invalid line: {}", line));
+    };
+
+    // The TTL and class are optional, in either order as in zone files, and
+    // only IN is supported.
+    let mut rest = rest;
+    let (mut ttl, mut class) = (false, false);
+    let qtype = loop {
+        let Some((token, tail)) = next_token(rest) else {
+            return Err(eyre!("invalid line: {}", line));
+        };
+
+        rest = tail;
+
+        if token.eq_ignore_ascii_case("IN") {
+            if class {
+                return Err(eyre!("More than one class: {line}"));
+            }
+
+            class = true;
+        } else if token.starts_with(|c: char| c.is_ascii_digit()) {
+            if ttl {
+                return Err(eyre!("More than one TTL: {line}"));
+            }
 
-    let name = parts.next().unwrap();
-    let qtype = parts.next().unwrap();
-    let data = parts.next().unwrap();
+            parse_ttl(token)?;
+            ttl = true;
+        } else {
+            break token;
+        }
+    };
 
-    let name = Name::new(name.to_string());
+    let name = Name::from_str(name)?;
     let qtype = QType::from_str(qtype)?;
+    let data = rest.split_whitespace().collect::<Vec<_>>();
 
     let record = match qtype {
         QType::A => Record::A {
-            address: parse_ip(data)?,
+            address: parse_ip(single(&data)?)?,
         },
         QType::CNAME => Record::CNAME {
-            name: Name::new(data.to_string()),
+            name: Name::from_str(single(&data)?)?,
+        },
+        QType::NS => Record::NS {
+            name: Name::from_str(single(&data)?)?,
         },
+        QType::PTR => Record::PTR {
+            name: Name::from_str(single(&data)?)?,
+        },
+        QType::MX => {
+            let [preference, exchange] = data[..] else {
+                return Err(eyre!("MX record requires a preference and an exchange"));
+            };
+
+            Record::MX {
+                preference: preference.parse()?,
+                exchange: Name::from_str(exchange)?,
+            }
+        }
+        QType::SRV => {
+            let [priority, weight, port, target] = data[..] else {
+                return Err(eyre!(
+                    "SRV record requires a priority, a weight, a port and a target"
+                ));
+            };
+
+            Record::SRV {
+                priority: priority.parse()?,
+                weight: weight.parse()?,
+                port: port.parse()?,
+                target: Name::from_str(target)?,
+            }
+        }
+        QType::SOA => {
+            let [mname, rname, serial, refresh, retry, expire, minimum] = data[..] else {
+                return Err(eyre!(
+                    "SOA record requires mname, rname, serial, refresh, retry, expire and minimum"
+                ));
+            };
+
+            Record::SOA {
+                mname: Name::from_str(mname)?,
+                rname: Name::from_str(rname)?,
+                serial: serial.parse()?,
+                refresh: refresh.parse()?,
+                retry: retry.parse()?,
+                expire: expire.parse()?,
+                minimum: minimum.parse()?,
+            }
+        }
+        QType::TXT => Record::TXT {
+            strings: parse_character_strings(rest)?,
+        },
+        QType::CAA => parse_caa(rest)?,
+        QType::SVCB | QType::HTTPS => parse_svcb(qtype, rest)?,
         other => return Err(eyre!("unsupported record type: {}", other)),
     };
 
     Ok((name, record))
 }
 
-fn parse_ip(ip: &str) -> Result<[u8; 4], Report> {
-    let mut parts = ip.split('.');
+/// Records are always served with [`TTL`], so reject any other.
+fn parse_ttl(ttl: &str) -> Result<(), Report> {
+    match ttl.parse::<u32>() {
+        Ok(TTL) => Ok(()),
+        Ok(_) => Err(eyre!(
+            "Unsupported TTL {ttl}, records are served with a TTL of {TTL}"
+        )),
+        Err(_) => Err(eyre!("Invalid TTL: {ttl}")),
+    }
+}
+
+fn next_token(input: &str) -> Option<(&str, &str)> {
+    let input = input.trim_start();
+
+    if input.is_empty() {
+        return None;
+    }
+
+    let end = input.find(char::is_whitespace).unwrap_or(input.len());
+    Some(input.split_at(end))
+}
+
+/// Parse the character-strings of a TXT record, splitting those longer than
+/// 255 bytes.
+fn parse_character_strings(input: &str) -> Result<Vec<Bytes>, Report> {
+    let mut strings = Vec::new();
+
+    for string in parse_strings(input)? {
+        if string.is_empty() {
+            strings.push(Bytes::new());
+        }
+
+        strings.extend(string.chunks(MAX_STRING_LENGTH).map(Bytes::copy_from_slice));
+    }
+
+    if strings.is_empty() {
+        return Err(eyre!("TXT record requires at least one string"));
+    }
+
+    Ok(strings)
+}
+
+/// Parse a sequence of strings, either bare or double-quoted, with `\X` and
+/// `\DDD` escapes.
+fn parse_strings(input: &str) -> Result<Vec<Vec<u8>>, Report> {
+    let mut strings = Vec::new();
+    let mut chars = input.trim().chars().peekable();
+
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+            continue;
+        }
+
+        let quoted = c == '"';
+        if quoted {
+            chars.next();
+        }
+
+        let mut string = Vec::new();
+        let mut closed = !quoted;
+
+        while let Some(c) = chars.next() {
+            match c {
+                '"' if quoted => {
+                    closed = true;
+                    break;
+                }
+                c if c.is_whitespace() && !quoted => break,
+                '\\' => {
+                    let Some(c) = chars.next() else {
+                        return Err(eyre!("dangling escape in: {}", input));
+                    };
+
+                    if let Some(d0) = c.to_digit(10) {
+                        let (Some(d1), Some(d2)) = (
+                            chars.next().and_then(|c| c.to_digit(10)),
+                            chars.next().and_then(|c| c.to_digit(10)),
+                        ) else {
+                            return Err(eyre!("invalid \\DDD escape in: {}", input));
+                        };
+
+                        let byte = u8::try_from(d0 * 100 + d1 * 10 + d2)
+                            .map_err(|_| eyre!("invalid \\DDD escape in: {}", input))?;
+
+                        string.push(byte);
+                    } else {
+                        string.extend(c.encode_utf8(&mut [0; 4]).as_bytes());
+                    }
+                }
+                c => string.extend(c.encode_utf8(&mut [0; 4]).as_bytes()),
+            }
+        }
+
+        if !closed {
+            return Err(eyre!("unterminated string in: {}", input));
+        }
+
+        strings.push(string);
+    }
+
+    Ok(strings)
+}
+
+fn single<'a>(data: &[&'a str]) -> Result<&'a str, Report> {
+    match data {
+        [value] => Ok(value),
+        _ => Err(eyre!("expected a single value, found: {}", data.join(" "))),
+    }
+}
+
+/// Parse the flags, tag and value of a CAA record, the value being a single
+/// string as in TXT records.
+fn parse_caa(input: &str) -> Result<Record, Report> {
+    let invalid = || eyre!("CAA record requires flags, a tag and a value");
+
+    let (flags, rest) = next_token(input).ok_or_else(invalid)?;
+    let (tag, value) = next_token(rest).ok_or_else(invalid)?;
+
+    if tag.is_empty() || tag.len() > 15 || !tag.chars().all(|c| c.is_ascii_alphanumeric()) {
+        return Err(eyre!("invalid CAA tag: {}", tag));
+    }
+
+    let [value] = &parse_strings(value)?[..] else {
+        return Err(eyre!(
+            "CAA record requires a single value, quoted if it has spaces"
+        ));
+    };
+
+    let value = String::from_utf8(value.clone())
+        .map_err(|_| eyre!("CAA value is not valid UTF-8: {}", input.trim()))?;
+
+    Ok(Record::CAA {
+        flags: flags.parse()?,
+        tag: tag.to_ascii_lowercase(),
+        value,
+    })
+}
+
+fn parse_svcb(qtype: QType, input: &str) -> Result<Record, Report> {
+    let data = split_quoted(input)?;
+
+    let [priority, target, params @ ..] = &data[..] else {
+        return Err(eyre!("{} record requires a priority and a target", qtype));
+    };
 
-    let address = [
-        parts.next().unwrap().parse()?,
-        parts.next().unwrap().parse()?,
-        parts.next().unwrap().parse()?,
-        parts.next().unwrap().parse()?,
-    ];
+    let priority = priority.parse()?;
+    let target = Name::from_str(target)?;
+    let params = SvcParams::parse(params.iter().copied())?;
 
-    Ok(address)
+    if priority == 0 && !params.is_empty() {
+        return Err(eyre!(
+            "{} record in AliasMode must not have SvcParams",
+            qtype
+        ));
+    }
+
+    let record = if qtype == QType::SVCB {
+        Record::SVCB {
+            priority,
+            target,
+            params,
+        }
+    } else {
+        Record::HTTPS {
+            priority,
+            target,
+            params,
+        }
+    };
+
+    Ok(record)
+}
+
+/// Split on whitespace, except within double quotes, eg. for `key667="a b"`.
+fn split_quoted(input: &str) -> Result<Vec<&str>, Report> {
+    let mut tokens = Vec::new();
+    let mut start = None;
+    let mut quoted = false;
+    let mut escaped = false;
+
+    for (i, c) in input.char_indices() {
+        if escaped {
+            escaped = false;
+            continue;
+        }
+
+        match c {
+            c if c.is_whitespace() && !quoted => tokens.extend(start.take().map(|s| &input[s..i])),
+            c => {
+                start.get_or_insert(i);
+                escaped = c == '\\';
+                quoted ^= c == '"';
+            }
+        }
+    }
+
+    if quoted {
+        return Err(eyre!("unterminated string in: {}", input));
+    }
+
+    tokens.extend(start.map(|s| &input[s..]));
+    Ok(tokens)
+}
+
+fn parse_ip(ip: &str) -> Result<[u8; 4], Report> {
+    Ipv4Addr::from_str(ip)
+        .map(|ip| ip.octets())
+        .map_err(|_| eyre!("Invalid IPv4 address: {ip}"))
 }
 
 #[cfg(test)]
@@ -185,6 +660,126 @@ mod tests {
         );
     }
 
+    #[test]
+    fn wildcard_below_existing() {
+        let mut db = Db::new();
+
+        let record = Record::A {
+            address: [127, 0, 0, 1],
+        };
+
+        db.insert(&Name::new("*.local.dev".to_string()), record.clone());
+        db.insert(&Name::new("v1.api.local.dev".to_string()), record.clone());
+
+        let lookup = |name: &str| db.lookup(&Name::new(name.to_string()), QType::A);
+
+        assert_eq!(lookup("a.local.dev"), Some(&record));
+        assert_eq!(lookup("a.b.local.dev"), None);
+        assert_eq!(lookup("api.local.dev"), None);
+        assert_eq!(lookup("foo.api.local.dev"), None);
+
+        let presence = |name: &str| db.presence(&Name::new(name.to_string()));
+
+        assert_eq!(presence("a.local.dev"), Presence::Exists);
+        assert_eq!(presence("a.b.local.dev"), Presence::Missing);
+    }
+
+    #[test]
+    fn presence() {
+        let mut db = Db::new();
+
+        let record = Record::A {
+            address: [127, 0, 0, 1],
+        };
+
+        db.insert(&Name::new("v1.api.local.dev".to_string()), record);
+
+        let presence = |name: &str| db.presence(&Name::new(name.to_string()));
+
+        assert_eq!(presence("v1.api.local.dev"), Presence::Exists);
+        assert_eq!(presence("api.local.dev"), Presence::Exists);
+        assert_eq!(presence("v2.api.local.dev"), Presence::Missing);
+        assert_eq!(presence("example.com"), Presence::Unknown);
+    }
+
+    #[test]
+    fn compact() {
+        let content = r#"
+            *.local.dev         A      127.0.0.1
+            v1.api.local.dev    A      127.0.0.2
+            v1.api.local.dev    TXT    "hello"
+            "#;
+
+        let mut db = from_reader(Cursor::new(content)).unwrap();
+        let before = db.to_string();
+        db.compact();
+
+        assert_eq!(db.to_string(), before);
+        assert_eq!(db.len(), 2);
+
+        let name = |name: &str| Name::new(name.to_string());
+
+        assert_eq!(
+            db.lookup(&name("denis.local.dev"), QType::A),
+            Some(&Record::A {
+                address: [127, 0, 0, 1]
+            })
+        );
+        assert_eq!(
+            db.lookup_all(&name("v1.api.local.dev"), QType::ANY).len(),
+            2
+        );
+        assert_eq!(db.presence(&name("api.local.dev")), Presence::Exists);
+
+        db.remove(&name("v1.api.local.dev"), QType::TXT);
+        assert_eq!(
+            db.lookup_all(&name("v1.api.local.dev"), QType::ANY).len(),
+            1
+        );
+    }
+
+    #[test]
+    fn remove() {
+        let mut db = Db::new();
+
+        let name = Name::new("example.com".to_string());
+        let a = Record::A {
+            address: [1, 1, 1, 1],
+        };
+        let txt = Record::txt("hello");
+
+        db.insert(&name, a.clone());
+        db.insert(&name, txt.clone());
+
+        assert_eq!(db.remove(&name, QType::A), vec![a]);
+        assert_eq!(db.lookup(&name, QType::A), None);
+        assert_eq!(db.len(), 1);
+
+        assert_eq!(db.remove(&name, QType::TXT), vec![txt]);
+        assert!(db.is_empty());
+    }
+
+    #[test]
+    fn subtree() {
+        let mut db = Db::new();
+
+        let record = Record::A {
+            address: [127, 0, 0, 1],
+        };
+
+        db.insert(&Name::new("*.local.dev".to_string()), record.clone());
+        db.insert(&Name::new("api.local.dev".to_string()), record.clone());
+        db.insert(&Name::new("example.com".to_string()), record);
+
+        let names = db
+            .subtree(&Name::new("local.dev".to_string()))
+            .map(|(name, _)| name.to_string())
+            .collect::<Vec<_>>();
+
+        assert_eq!(names, vec!["*.local.dev", "api.local.dev"]);
+        assert_eq!(db.iter().count(), 3);
+    }
+
     #[test]
     fn parse_db() {
         let content = r#"
@@ -212,4 +807,263 @@ mod tests {
             })
         );
     }
+
+    #[test]
+    fn parse_db_https() {
+        let content = r#"
+            *.local.dev    HTTPS    1 . alpn=h2,h3 port=8443 ipv4hint=127.0.0.1
+            "#;
+
+        let db = from_reader(Cursor::new(content)).unwrap();
+
+        let record = db
+            .lookup(&Name::new("denis.local.dev".to_string()), QType::HTTPS)
+            .unwrap();
+
+        assert_eq!(
+            record.to_string(),
+            "HTTPS 1 . alpn=h2,h3 port=8443 ipv4hint=127.0.0.1"
+        );
+
+        let invalid = "*.local.dev    HTTPS    1 . mandatory=port alpn=h2";
+        assert!(from_reader(Cursor::new(invalid)).is_err());
+
+        let quoted = r#"api.local.dev    SVCB    1 . mandatory=port,alpn alpn=h2 port=443 key667="hello world""#;
+        let db = from_reader(Cursor::new(quoted)).unwrap();
+
+        assert_eq!(
+            db.lookup(&Name::new("api.local.dev".to_string()), QType::SVCB)
+                .unwrap()
+                .to_string(),
+            r#"SVCB 1 . mandatory=alpn,port alpn=h2 port=443 key667="hello world""#
+        );
+
+        let unterminated = r#"api.local.dev    SVCB    1 . key667="hello"#;
+        assert!(from_reader(Cursor::new(unterminated)).is_err());
+    }
+
+    #[test]
+    fn caa_escapes() {
+        let line = r#"a.dev CAA 0 issue "ca.example; account=\"a b\"\\é""#;
+        let (name, record) = parse_entry(line).unwrap().unwrap();
+
+        assert_eq!(
+            record,
+            Record::CAA {
+                flags: 0,
+                tag: "issue".to_string(),
+                value: "ca.example; account=\"a b\"\\\u{e9}".to_string(),
+            }
+        );
+
+        let printed = format!("{name} {record}");
+        assert_eq!(
+            printed,
+            r#"a.dev CAA 0 issue "ca.example; account=\"a b\"\\\195\169""#
+        );
+        assert_eq!(parse_entry(&printed).unwrap().unwrap(), (name, record));
+
+        assert!(parse_entry("a.dev CAA 0 issue two values")
+            .unwrap()
+            .is_err());
+        assert!(parse_entry("a.dev CAA 0 issue \"\\255\"").unwrap().is_err());
+    }
+
+    #[test]
+    fn ttl_and_class() {
+        let parse = |line: &str| parse_entry(line).map(|entry| entry.map_err(|e| e.to_string()));
+        let a = |name: &str| {
+            Some(Ok((
+                Name::new(name.to_string()),
+                Record::A {
+                    address: [1, 2, 3, 4],
+                },
+            )))
+        };
+
+        assert_eq!(parse("a.dev A 1.2.3.4"), a("a.dev"));
+        assert_eq!(parse("a.dev 1 A 1.2.3.4"), a("a.dev"));
+        assert_eq!(parse("a.dev IN A 1.2.3.4"), a("a.dev"));
+        assert_eq!(parse("a.dev 1 IN A 1.2.3.4"), a("a.dev"));
+        assert_eq!(parse("a.dev in 1 A 1.2.3.4"), a("a.dev"));
+        assert_eq!(parse("$TTL 1"), None);
+
+        assert_eq!(
+            parse("a.dev 60 IN A 1.2.3.4"),
+            Some(Err(
+                "Unsupported TTL 60, records are served with a TTL of 1".to_string()
+            ))
+        );
+        assert_eq!(
+            parse("$TTL 3600"),
+            Some(Err(
+                "Unsupported TTL 3600, records are served with a TTL of 1".to_string()
+            ))
+        );
+        assert_eq!(parse("$TTL 1h"), Some(Err("Invalid TTL: 1h".to_string())));
+        assert_eq!(
+            parse("a.dev 1 IN 1 A 1.2.3.4"),
+            Some(Err("More than one TTL: a.dev 1 IN 1 A 1.2.3.4".to_string()))
+        );
+        assert_eq!(
+            parse("a.dev IN 1 IN A 1.2.3.4"),
+            Some(Err(
+                "More than one class: a.dev IN 1 IN A 1.2.3.4".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn parse_db_mail() {
+        let content = r#"
+            local.dev                  A      127.0.0.1
+            local.dev                  MX     10 mail1.local.dev
+            local.dev                  MX     20 mail2.local.dev
+            local.dev                  SOA    ns1.local.dev hostmaster.local.dev 1 7200 3600 1209600 300
+            local.dev                  CAA    0 issue "letsencrypt.org"
+            _sip._tcp.local.dev        SRV    10 5 5060 sip.local.dev
+            1.0.0.127.in-addr.arpa     PTR    local.dev
+            "#;
+
+        let db = from_reader(Cursor::new(content)).unwrap();
+        println!("{db}");
+
+        let local = Name::new("local.dev".to_string());
+
+        assert_eq!(
+            db.lookup(&local, QType::A),
+            Some(&Record::A {
+                address: [127, 0, 0, 1],
+            })
+        );
+
+        let mx = db.lookup_all(&local, QType::MX);
+        assert_eq!(mx.len(), 2);
+        assert_eq!(mx[1].to_string(), "MX 20 mail2.local.dev");
+
+        assert_eq!(db.lookup_all(&local, QType::ANY).len(), 5);
+
+        assert_eq!(
+            db.lookup(&local, QType::CAA),
+            Some(&Record::CAA {
+                flags: 0,
+                tag: "issue".to_string(),
+                value: "letsencrypt.org".to_string(),
+            })
+        );
+
+        assert_eq!(
+            db.lookup(&Name::new("_sip._tcp.local.dev".to_string()), QType::SRV)
+                .map(|r| r.to_string()),
+            Some("SRV 10 5 5060 sip.local.dev".to_string())
+        );
+
+        assert_eq!(
+            db.lookup(&Name::new("1.0.0.127.in-addr.arpa".to_string()), QType::PTR),
+            Some(&Record::PTR { name: local })
+        );
+    }
+
+    #[test]
+    fn parse_db_txt() {
+        let long = "a".repeat(300);
+        let content = format!(
+            r#"
+            local.dev            TXT    "v=spf1 include:_spf.local.dev -all"
+            multi.local.dev      TXT    "first string" second "say \"hi\"" "\065\066"
+            long.local.dev       TXT    "{long}"
+            "#
+        );
+
+        let db = from_reader(Cursor::new(content)).unwrap();
+
+        let strings = |name: &str| match db.lookup(&Name::new(name.to_string()), QType::TXT) {
+            Some(Record::TXT { strings }) => strings.clone(),
+            other => panic!("unexpected record: {other:?}"),
+        };
+
+        assert_eq!(
+            strings("local.dev"),
+            vec![Bytes::from_static(b"v=spf1 include:_spf.local.dev -all")]
+        );
+
+        assert_eq!(
+            strings("multi.local.dev"),
+            vec![
+                Bytes::from_static(b"first string"),
+                Bytes::from_static(b"second"),
+                Bytes::from_static(b"say \"hi\""),
+                Bytes::from_static(b"AB"),
+            ]
+        );
+
+        let long = strings("long.local.dev");
+        assert_eq!(long.len(), 2);
+        assert_eq!(long[0].len(), 255);
+        assert_eq!(long[1].len(), 45);
+
+        let unterminated = r#"local.dev    TXT    "oops"#;
+        assert!(from_reader(Cursor::new(unterminated)).is_err());
+    }
+
+    #[test]
+    fn parse_db_idna() {
+        let content = r#"
+            bücher.local.dev           A    127.0.0.1
+            xn--caf-dma.local.dev      A    127.0.0.2
+            "#;
+
+        let db = from_reader(Cursor::new(content)).unwrap();
+
+        let record = Record::A {
+            address: [127, 0, 0, 1],
+        };
+
+        assert_eq!(
+            db.lookup(&Name::new("xn--bcher-kva.local.dev".to_string()), QType::A),
+            Some(&record)
+        );
+        assert_eq!(
+            db.lookup(&Name::from_str("bücher.local.dev").unwrap(), QType::A),
+            Some(&record)
+        );
+
+        assert_eq!(
+            db.lookup(&Name::from_str("café.local.dev").unwrap(), QType::A),
+            Some(&Record::A {
+                address: [127, 0, 0, 2],
+            })
+        );
+    }
+
+    #[test]
+    fn parse_db_faults() {
+        let content = r#"
+            api.local.dev    A    127.0.0.1
+
+            $FAULT api.local.dev    delay 200ms
+            $FAULT *.local.dev      servfail 10%
+            "#;
+
+        let db = from_reader(Cursor::new(content)).unwrap();
+        let name = Name::new("api.local.dev".to_string());
+
+        let faults = db
+            .faults_for(&name)
+            .map(|f| f.to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            faults,
+            vec![
+                "$FAULT api.local.dev delay 200ms",
+                "$FAULT *.local.dev servfail 10%"
+            ]
+        );
+
+        assert!(db.lookup(&name, QType::A).is_some());
+        assert!(db
+            .to_string()
+            .ends_with("\n$FAULT api.local.dev delay 200ms\n$FAULT *.local.dev servfail 10%"));
+        assert!(from_reader(Cursor::new("$FAULT api.local.dev explode")).is_err());
+    }
 }
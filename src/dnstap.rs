@@ -0,0 +1,386 @@
+use std::{
+    fs::File,
+    io::{self, Write},
+    net::{IpAddr, SocketAddr},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+#[cfg(unix)]
+use std::{
+    io::Read,
+    os::unix::net::UnixStream,
+    path::{Path, PathBuf},
+};
+
+use color_eyre::{eyre::WrapErr, Report};
+use tokio::sync::mpsc;
+use tracing::{debug, error, warn};
+
+use crate::{client::Transport, config::DnstapConfig};
+
+/// Content type of the Frame Streams we write.
+const CONTENT_TYPE: &[u8] = b"protobuf:dnstap.Dnstap";
+
+/// Number of frames waiting to be written before we start dropping them.
+const BUFFER_SIZE: usize = 4096;
+
+/// How long to wait for a reader to accept our stream.
+#[cfg(unix)]
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Frame Streams control frame types.
+#[cfg(unix)]
+const CONTROL_ACCEPT: u32 = 0x01;
+const CONTROL_START: u32 = 0x02;
+const CONTROL_STOP: u32 = 0x03;
+#[cfg(unix)]
+const CONTROL_READY: u32 = 0x04;
+const CONTROL_FIELD_CONTENT_TYPE: u32 = 0x01;
+
+/// The kinds of dnstap messages we emit, with their protobuf values.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum MessageType {
+    ClientQuery = 5,
+    ClientResponse = 6,
+    ForwarderQuery = 7,
+    ForwarderResponse = 8,
+}
+
+impl MessageType {
+    pub fn is_query(self) -> bool {
+        matches!(self, Self::ClientQuery | Self::ForwarderQuery)
+    }
+}
+
+/// A DNS message seen by denis, along with who sent it to whom.
+#[derive(Clone, Debug)]
+pub struct Event<'a> {
+    pub kind: MessageType,
+    pub transport: Transport,
+    /// Address of the side which sent the query.
+    pub query_address: SocketAddr,
+    /// Address of the side which answers it.
+    pub response_address: SocketAddr,
+    pub query_time: SystemTime,
+    /// Only for responses.
+    pub response_time: Option<SystemTime>,
+    pub message: &'a [u8],
+}
+
+impl Event<'_> {
+    /// Encode as a `dnstap.Dnstap` protobuf message.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut message = Encoder::default();
+        message.varint(1, self.kind as u64);
+
+        let family = match self.query_address {
+            SocketAddr::V4(_) => 1,
+            SocketAddr::V6(_) => 2,
+        };
+
+        let protocol = match self.transport {
+            Transport::Udp => 1,
+            Transport::Tcp => 2,
+        };
+
+        message.varint(2, family);
+        message.varint(3, protocol);
+        message.bytes(4, &ip_bytes(self.query_address.ip()));
+        message.bytes(5, &ip_bytes(self.response_address.ip()));
+        message.varint(6, self.query_address.port().into());
+        message.varint(7, self.response_address.port().into());
+
+        let (secs, nanos) = timestamp(self.query_time);
+        message.varint(8, secs);
+        message.fixed32(9, nanos);
+
+        if self.kind.is_query() {
+            message.bytes(10, self.message);
+        } else {
+            let (secs, nanos) = timestamp(self.response_time.unwrap_or(self.query_time));
+            message.varint(12, secs);
+            message.fixed32(13, nanos);
+            message.bytes(14, self.message);
+        }
+
+        let mut dnstap = Encoder::default();
+        dnstap.bytes(2, format!("denis {}", env!("CARGO_PKG_VERSION")).as_bytes());
+        dnstap.bytes(14, &message.0);
+        // Type MESSAGE, the only one there is.
+        dnstap.varint(15, 1);
+
+        dnstap.0
+    }
+}
+
+fn ip_bytes(ip: IpAddr) -> Vec<u8> {
+    match ip {
+        IpAddr::V4(ip) => ip.octets().to_vec(),
+        IpAddr::V6(ip) => ip.octets().to_vec(),
+    }
+}
+
+fn timestamp(time: SystemTime) -> (u64, u32) {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or(Duration::ZERO);
+    (since_epoch.as_secs(), since_epoch.subsec_nanos())
+}
+
+/// Just enough of the protobuf wire format for dnstap.
+#[derive(Default)]
+struct Encoder(Vec<u8>);
+
+impl Encoder {
+    fn key(&mut self, field: u32, wire_type: u32) {
+        self.raw_varint(((field << 3) | wire_type).into());
+    }
+
+    fn raw_varint(&mut self, mut value: u64) {
+        while value >= 0x80 {
+            self.0.push(value as u8 | 0x80);
+            value >>= 7;
+        }
+
+        self.0.push(value as u8);
+    }
+
+    fn varint(&mut self, field: u32, value: u64) {
+        self.key(field, 0);
+        self.raw_varint(value);
+    }
+
+    fn fixed32(&mut self, field: u32, value: u32) {
+        self.key(field, 5);
+        self.0.extend(value.to_le_bytes());
+    }
+
+    fn bytes(&mut self, field: u32, value: &[u8]) {
+        self.key(field, 2);
+        self.raw_varint(value.len() as u64);
+        self.0.extend(value);
+    }
+}
+
+/// Handle to the dnstap output, whose frames are written out by a background thread.
+#[derive(Clone, Debug)]
+pub struct Dnstap {
+    frames: mpsc::Sender<Vec<u8>>,
+}
+
+impl Dnstap {
+    pub fn open(config: &DnstapConfig) -> Result<Self, Report> {
+        let mut output = match config {
+            DnstapConfig::File { path } => {
+                let file = File::create(path)
+                    .wrap_err_with(|| format!("Failed to create {}", path.display()))?;
+
+                Output::File(FrameWriter::start(file)?)
+            }
+            #[cfg(unix)]
+            DnstapConfig::Socket { path } => Output::Socket {
+                path: path.clone(),
+                writer: None,
+            },
+            #[cfg(not(unix))]
+            DnstapConfig::Socket { .. } => {
+                return Err(color_eyre::eyre::eyre!(
+                    "dnstap to a socket is only supported on Unix"
+                ))
+            }
+        };
+
+        let (frames, mut receiver) = mpsc::channel::<Vec<u8>>(BUFFER_SIZE);
+
+        std::thread::Builder::new()
+            .name("dnstap".to_string())
+            .spawn(move || {
+                while let Some(frame) = receiver.blocking_recv() {
+                    if let Err(err) = output.write(&frame) {
+                        error!("Failed to write dnstap frame: {err}");
+                    }
+                }
+
+                output.stop();
+            })?;
+
+        Ok(Self { frames })
+    }
+
+    /// Queue an event, dropping it if the writer cannot keep up.
+    pub fn log(&self, event: &Event<'_>) {
+        if self.frames.try_send(event.encode()).is_err() {
+            debug!("dnstap output is full, dropping frame");
+        }
+    }
+}
+
+enum Output {
+    File(FrameWriter<File>),
+    /// Connected lazily, and again whenever the reader goes away.
+    #[cfg(unix)]
+    Socket {
+        path: PathBuf,
+        writer: Option<FrameWriter<UnixStream>>,
+    },
+}
+
+impl Output {
+    fn write(&mut self, frame: &[u8]) -> io::Result<()> {
+        match self {
+            Self::File(writer) => writer.frame(frame),
+            #[cfg(unix)]
+            Self::Socket { path, writer } => {
+                if writer.is_none() {
+                    *writer = Some(FrameWriter::connect(path)?);
+                }
+
+                let result = writer.as_mut().unwrap().frame(frame);
+
+                if result.is_err() {
+                    *writer = None;
+                }
+
+                result
+            }
+        }
+    }
+
+    fn stop(self) {
+        let result = match self {
+            Self::File(writer) => writer.stop(),
+            #[cfg(unix)]
+            Self::Socket {
+                writer: Some(writer),
+                ..
+            } => writer.stop(),
+            #[cfg(unix)]
+            Self::Socket { writer: None, .. } => Ok(()),
+        };
+
+        if let Err(err) = result {
+            warn!("Failed to close dnstap output: {err}");
+        }
+    }
+}
+
+/// Writes a unidirectional Frame Stream, or the writer side of a bidirectional one.
+struct FrameWriter<W: Write> {
+    inner: W,
+}
+
+impl<W: Write> FrameWriter<W> {
+    fn start(mut inner: W) -> io::Result<Self> {
+        inner.write_all(&control(CONTROL_START, true))?;
+        Ok(Self { inner })
+    }
+
+    fn frame(&mut self, data: &[u8]) -> io::Result<()> {
+        self.inner.write_all(&(data.len() as u32).to_be_bytes())?;
+        self.inner.write_all(data)?;
+        self.inner.flush()
+    }
+
+    fn stop(mut self) -> io::Result<()> {
+        self.inner.write_all(&control(CONTROL_STOP, false))?;
+        self.inner.flush()
+    }
+}
+
+#[cfg(unix)]
+impl FrameWriter<UnixStream> {
+    /// Connect to a reader, and agree on the content type before starting.
+    fn connect(path: &Path) -> io::Result<Self> {
+        let mut stream = UnixStream::connect(path)?;
+        stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+
+        stream.write_all(&control(CONTROL_READY, true))?;
+
+        let mut header = [0; 8];
+        stream.read_exact(&mut header)?;
+
+        let (escape, len) = header.split_at(4);
+        let len = u32::from_be_bytes(len.try_into().unwrap()) as usize;
+
+        let mut payload = vec![0; len];
+        stream.read_exact(&mut payload)?;
+
+        if escape != [0; 4] || payload.get(..4) != Some(&CONTROL_ACCEPT.to_be_bytes()) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "dnstap reader did not accept the stream",
+            ));
+        }
+
+        Self::start(stream)
+    }
+}
+
+/// A control frame, optionally carrying our content type.
+fn control(kind: u32, content_type: bool) -> Vec<u8> {
+    let mut payload = kind.to_be_bytes().to_vec();
+
+    if content_type {
+        payload.extend(CONTROL_FIELD_CONTENT_TYPE.to_be_bytes());
+        payload.extend((CONTENT_TYPE.len() as u32).to_be_bytes());
+        payload.extend(CONTENT_TYPE);
+    }
+
+    let mut frame = vec![0; 4];
+    frame.extend((payload.len() as u32).to_be_bytes());
+    frame.extend(payload);
+    frame
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode() {
+        let event = Event {
+            kind: MessageType::ClientQuery,
+            transport: Transport::Udp,
+            query_address: "127.0.0.1:5353".parse().unwrap(),
+            response_address: "127.0.0.1:53".parse().unwrap(),
+            query_time: UNIX_EPOCH + Duration::new(1, 5),
+            response_time: None,
+            message: &[1, 2, 3],
+        };
+
+        let version = format!("denis {}", env!("CARGO_PKG_VERSION"));
+
+        let mut expected = vec![0x12, version.len() as u8];
+        expected.extend(version.as_bytes());
+        expected.extend([
+            0x72, 35, // message
+            0x08, 5, // type: CLIENT_QUERY
+            0x10, 1, // socket_family: INET
+            0x18, 1, // socket_protocol: UDP
+            0x22, 4, 127, 0, 0, 1, // query_address
+            0x2a, 4, 127, 0, 0, 1, // response_address
+            0x30, 0xe9, 0x29, // query_port: 5353
+            0x38, 53, // response_port
+            0x40, 1, // query_time_sec
+            0x4d, 5, 0, 0, 0, // query_time_nsec
+            0x52, 3, 1, 2, 3, // query_message
+            0x78, 1, // type: MESSAGE
+        ]);
+
+        assert_eq!(event.encode(), expected);
+    }
+
+    #[test]
+    fn frames() {
+        let mut out = Vec::new();
+
+        let mut writer = FrameWriter::start(&mut out).unwrap();
+        writer.frame(&[0xab, 0xcd]).unwrap();
+        writer.stop().unwrap();
+
+        let mut expected = vec![0, 0, 0, 0, 0, 0, 0, 34, 0, 0, 0, 2, 0, 0, 0, 1, 0, 0, 0, 22];
+        expected.extend(CONTENT_TYPE);
+        expected.extend([0, 0, 0, 2, 0xab, 0xcd]);
+        expected.extend([0, 0, 0, 0, 0, 0, 0, 4, 0, 0, 0, 3]);
+
+        assert_eq!(out, expected);
+    }
+}
@@ -0,0 +1,148 @@
+use std::io::{self, Write};
+
+use color_eyre::Report;
+use serde_json::{json, Value};
+
+use crate::db::{Db, TTL};
+
+/// Write the database as an RFC 1035 zone file, in canonical order.
+///
+/// Other servers do not know about `$FAULT` rules, which are only kept as comments.
+pub fn zone(db: &Db, mut out: impl Write) -> io::Result<()> {
+    writeln!(out, "$TTL {TTL}")?;
+
+    for fault in db.faults() {
+        writeln!(out, "; {fault}")?;
+    }
+
+    for (name, records) in db.iter() {
+        let name = name.to_fqdn();
+
+        for (qtype, record) in records.iter() {
+            writeln!(out, "{name}\t{TTL}\tIN\t{qtype}\t{}", record.rdata())?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Build a JSON document with one entry per RRset, and the `$FAULT` rules.
+pub fn json(db: &Db) -> Value {
+    let mut rrsets = Vec::new();
+
+    for (name, records) in db.iter() {
+        let name = name.to_fqdn();
+        let mut current: Option<(String, Vec<String>)> = None;
+
+        for (qtype, record) in records.iter() {
+            let qtype = qtype.to_string();
+
+            match &mut current {
+                Some((ty, data)) if *ty == qtype => data.push(record.rdata().to_string()),
+                _ => {
+                    if let Some((ty, data)) = current.take() {
+                        rrsets.push(rrset(&name, ty, data));
+                    }
+
+                    current = Some((qtype, vec![record.rdata().to_string()]));
+                }
+            }
+        }
+
+        if let Some((ty, data)) = current {
+            rrsets.push(rrset(&name, ty, data));
+        }
+    }
+
+    let faults = db
+        .faults()
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>();
+
+    json!({ "rrsets": rrsets, "faults": faults })
+}
+
+fn rrset(name: &str, qtype: String, data: Vec<String>) -> Value {
+    json!({
+        "name": name,
+        "type": qtype,
+        "ttl": TTL,
+        "records": data,
+    })
+}
+
+/// Write the database as a pretty-printed JSON document, see [`json`].
+pub fn write_json(db: &Db, mut out: impl Write) -> Result<(), Report> {
+    serde_json::to_writer_pretty(&mut out, &json(db))?;
+    writeln!(out)?;
+    Ok(())
+}
+
+/// Write the trie backing the database as a Graphviz DOT graph.
+pub fn dot(db: &Db, mut out: impl Write) -> io::Result<()> {
+    out.write_all(db.to_dot().as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DB: &str = r#"
+example.com A 93.184.216.34
+example.com MX 10 mail.example.com
+example.com MX 20 mail2.example.com.
+example.com TXT "v=spf1 -all" "say \"hi\""
+*.local.dev A 127.0.0.1
+_https._tcp.local.dev SRV 10 5 443 web.local.dev
+local.dev HTTPS 1 . alpn=h2,h3 port=8443
+local.dev CAA 0 issue "letsencrypt.org"
+$FAULT *.local.dev servfail 10%
+"#;
+
+    #[test]
+    fn zone_roundtrip() {
+        let db = crate::db::from_reader(DB.as_bytes()).unwrap();
+
+        let mut out = Vec::new();
+        zone(&db, &mut out).unwrap();
+        let text = String::from_utf8(out).unwrap();
+
+        assert!(text.starts_with("$TTL 1\n"));
+        assert!(text.contains("example.com.\t1\tIN\tMX\t10 mail.example.com.\n"));
+        assert!(text.contains("\n; $FAULT *.local.dev servfail 10%\n"));
+
+        let parsed = crate::db::from_reader(text.as_bytes()).unwrap();
+        assert!(parsed.faults().is_empty());
+
+        let mut again = Vec::new();
+        zone(&parsed, &mut again).unwrap();
+
+        let records = text.replace("; $FAULT *.local.dev servfail 10%\n", "");
+        assert_eq!(String::from_utf8(again).unwrap(), records);
+    }
+
+    #[test]
+    fn json_rrsets() {
+        let db = crate::db::from_reader(DB.as_bytes()).unwrap();
+        let value = json(&db);
+
+        let mx = value["rrsets"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|rrset| rrset["type"] == "MX")
+            .unwrap();
+
+        assert_eq!(
+            mx,
+            &json!({
+                "name": "example.com.",
+                "type": "MX",
+                "ttl": 1,
+                "records": ["10 mail.example.com.", "20 mail2.example.com."],
+            })
+        );
+        assert_eq!(value["faults"], json!(["$FAULT *.local.dev servfail 10%"]));
+    }
+}
@@ -0,0 +1,254 @@
+use core::fmt;
+use std::{str::FromStr, time::Duration};
+
+use color_eyre::{eyre::eyre, Report};
+use rand::Rng;
+
+use crate::data::Name;
+
+/// Misbehaviour injected into the responses to some names, to test clients.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Action {
+    /// Wait for a duration picked uniformly between `min` and `max` before answering.
+    Delay {
+        min: Duration,
+        max: Duration,
+    },
+    /// Never answer.
+    Drop,
+    ServFail,
+    Refused,
+    /// Answer with TC=1 and no record, so that clients retry over TCP.
+    Truncate,
+    /// Answer with an id other than the query's.
+    WrongId,
+    /// Replace the answer with random bytes.
+    Garbage,
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Delay { min, max } if min == max => write!(f, "delay {}ms", min.as_millis()),
+            Self::Delay { min, max } => {
+                write!(f, "delay {}ms-{}ms", min.as_millis(), max.as_millis())
+            }
+            Self::Drop => write!(f, "drop"),
+            Self::ServFail => write!(f, "servfail"),
+            Self::Refused => write!(f, "refused"),
+            Self::Truncate => write!(f, "truncate"),
+            Self::WrongId => write!(f, "wrong-id"),
+            Self::Garbage => write!(f, "garbage"),
+        }
+    }
+}
+
+/// A `$FAULT` rule of a db file:
+///
+/// ```text
+/// $FAULT <name> <action> [<probability>%]
+/// ```
+///
+/// where `<name>` may start with `*.` to match every name below, and
+/// `<action>` is one of `delay <duration>[-<duration>]`, `drop`, `servfail`,
+/// `refused`, `truncate`, `wrong-id` or `garbage`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Fault {
+    pub name: Name,
+    pub action: Action,
+    /// Chance of the rule applying to a matching query, between 0 and 1.
+    pub probability: f64,
+}
+
+impl Fault {
+    pub fn matches(&self, name: &Name) -> bool {
+        let (name, pattern) = (name.labels(), self.name.labels());
+
+        let (pattern, below) = match pattern.split_first() {
+            Some((first, rest)) if first.as_bytes() == b"*" => (rest, true),
+            _ => (pattern, false),
+        };
+
+        let len_ok = if below {
+            name.len() > pattern.len()
+        } else {
+            name.len() == pattern.len()
+        };
+
+        len_ok
+            && name[name.len() - pattern.len()..]
+                .iter()
+                .zip(pattern)
+                .all(|(a, b)| a.as_bytes().eq_ignore_ascii_case(b.as_bytes()))
+    }
+
+    /// Roll the dice to decide whether the rule applies this time.
+    pub fn fires(&self) -> bool {
+        self.probability >= 1.0 || rand::thread_rng().gen_bool(self.probability.max(0.0))
+    }
+
+    /// How long to wait, for a delay.
+    pub fn delay(&self) -> Duration {
+        match self.action {
+            Action::Delay { min, max } if min < max => rand::thread_rng().gen_range(min..=max),
+            Action::Delay { min, .. } => min,
+            _ => Duration::ZERO,
+        }
+    }
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "$FAULT {} {}", self.name, self.action)?;
+
+        if self.probability < 1.0 {
+            write!(f, " {}%", self.probability * 100.0)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl FromStr for Fault {
+    type Err = Report;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut tokens = s.split_whitespace().collect::<Vec<_>>();
+
+        if tokens.first() == Some(&"$FAULT") {
+            tokens.remove(0);
+        }
+
+        let probability = match tokens.last().and_then(|t| t.strip_suffix('%')) {
+            Some(percent) => {
+                let percent = percent
+                    .parse::<f64>()
+                    .map_err(|_| eyre!("invalid probability: {percent}%"))?;
+
+                if !(0.0..=100.0).contains(&percent) {
+                    return Err(eyre!("probability must be between 0% and 100%"));
+                }
+
+                tokens.pop();
+                percent / 100.0
+            }
+            None => 1.0,
+        };
+
+        let [name, action, args @ ..] = &tokens[..] else {
+            return Err(eyre!("$FAULT requires a name and an action"));
+        };
+
+        let action = match (action.to_ascii_lowercase().as_str(), args) {
+            ("delay", [delay]) => {
+                let (min, max) = match delay.split_once('-') {
+                    Some((min, max)) => (parse_duration(min)?, parse_duration(max)?),
+                    None => (parse_duration(delay)?, parse_duration(delay)?),
+                };
+
+                if min > max {
+                    return Err(eyre!("invalid delay range: {delay}"));
+                }
+
+                Action::Delay { min, max }
+            }
+            ("delay", _) => return Err(eyre!("delay requires a duration, eg. 200ms")),
+            ("drop", []) => Action::Drop,
+            ("servfail", []) => Action::ServFail,
+            ("refused", []) => Action::Refused,
+            ("truncate", []) => Action::Truncate,
+            ("wrong-id", []) => Action::WrongId,
+            ("garbage", []) => Action::Garbage,
+            ("drop" | "servfail" | "refused" | "truncate" | "wrong-id" | "garbage", [arg, ..]) => {
+                return Err(eyre!("unexpected argument to {action}: {arg}"))
+            }
+            _ => return Err(eyre!("unknown fault: {action}")),
+        };
+
+        Ok(Self {
+            name: Name::from_str(name)?,
+            action,
+            probability,
+        })
+    }
+}
+
+/// Parse a duration such as `250ms` or `2s`.
+fn parse_duration(s: &str) -> Result<Duration, Report> {
+    let invalid = || eyre!("invalid duration: {s}, expected eg. 200ms or 2s");
+
+    if let Some(ms) = s.strip_suffix("ms") {
+        return Ok(Duration::from_millis(ms.parse().map_err(|_| invalid())?));
+    }
+
+    if let Some(secs) = s.strip_suffix('s') {
+        return Ok(Duration::from_secs(secs.parse().map_err(|_| invalid())?));
+    }
+
+    Err(invalid())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn name(s: &str) -> Name {
+        Name::from_str(s).unwrap()
+    }
+
+    #[test]
+    fn parse() {
+        let fault = Fault::from_str("$FAULT *.flaky.dev delay 100ms-2s 25%").unwrap();
+
+        assert_eq!(fault.name, name("*.flaky.dev"));
+        assert_eq!(
+            fault.action,
+            Action::Delay {
+                min: Duration::from_millis(100),
+                max: Duration::from_secs(2)
+            }
+        );
+        assert_eq!(fault.probability, 0.25);
+        assert_eq!(
+            fault.to_string(),
+            "$FAULT *.flaky.dev delay 100ms-2000ms 25%"
+        );
+
+        let fault = Fault::from_str("$FAULT api.local.dev WRONG-ID").unwrap();
+        assert_eq!(fault.action, Action::WrongId);
+        assert_eq!(fault.probability, 1.0);
+
+        let error = |s: &str| Fault::from_str(s).unwrap_err().to_string();
+
+        assert_eq!(
+            error("$FAULT a.dev"),
+            "$FAULT requires a name and an action"
+        );
+        assert_eq!(error("$FAULT a.dev explode"), "unknown fault: explode");
+        assert_eq!(
+            error("$FAULT a.dev drop 1"),
+            "unexpected argument to drop: 1"
+        );
+        assert_eq!(
+            error("$FAULT a.dev delay 5"),
+            "invalid duration: 5, expected eg. 200ms or 2s"
+        );
+        assert_eq!(
+            error("$FAULT a.dev drop 150%"),
+            "probability must be between 0% and 100%"
+        );
+    }
+
+    #[test]
+    fn matches() {
+        let exact = Fault::from_str("$FAULT api.local.dev drop").unwrap();
+        assert!(exact.matches(&name("API.local.dev")));
+        assert!(!exact.matches(&name("v1.api.local.dev")));
+        assert!(!exact.matches(&name("local.dev")));
+
+        let below = Fault::from_str("$FAULT *.local.dev drop").unwrap();
+        assert!(below.matches(&name("api.local.dev")));
+        assert!(below.matches(&name("v1.api.local.dev")));
+        assert!(!below.matches(&name("local.dev")));
+    }
+}
@@ -0,0 +1,246 @@
+use std::{
+    collections::HashMap,
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::Path,
+    thread::JoinHandle,
+};
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use color_eyre::{
+    eyre::{eyre, WrapErr},
+    Report,
+};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+use tracing::{error, warn};
+
+use crate::{
+    client::type_name,
+    data::QType,
+    db,
+    view::{rewrite_ttls, MessageView, HEADER_SIZE},
+};
+
+/// A line of a fixtures file: an upstream response, along with its question.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct Fixture {
+    name: String,
+    #[serde(rename = "type")]
+    qtype: String,
+    /// The raw response, in base64.
+    response: String,
+}
+
+/// Questions are matched case-insensitively, by name and type.
+type Key = (String, u16);
+
+fn key(query: &MessageView<'_>) -> Option<Key> {
+    let question = query.questions().next()?;
+    let name = question.qname.to_string().to_ascii_lowercase();
+
+    Some((name, question.qtype))
+}
+
+/// Number of responses waiting to be written before we start dropping them.
+const BUFFER_SIZE: usize = 1024;
+
+/// Appends every upstream response to a fixtures file, for [`Replayer`] to serve later.
+///
+/// Responses are written out by a background thread, which is waited for on drop.
+#[derive(Debug)]
+pub struct Recorder {
+    lines: Option<mpsc::Sender<Vec<u8>>>,
+    writer: Option<JoinHandle<()>>,
+}
+
+impl Recorder {
+    pub fn create(path: impl AsRef<Path>) -> Result<Self, Report> {
+        let path = path.as_ref();
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .wrap_err_with(|| format!("Failed to open {}", path.display()))?;
+
+        let path = path.to_path_buf();
+        let (lines, mut receiver) = mpsc::channel::<Vec<u8>>(BUFFER_SIZE);
+
+        let writer = std::thread::Builder::new()
+            .name("recorder".to_string())
+            .spawn(move || {
+                while let Some(line) = receiver.blocking_recv() {
+                    if let Err(err) = file.write_all(&line) {
+                        error!("Failed to write to {}: {err}", path.display());
+                    }
+                }
+            })?;
+
+        Ok(Self {
+            lines: Some(lines),
+            writer: Some(writer),
+        })
+    }
+
+    pub fn record(&self, query: &MessageView<'_>, response: &[u8]) -> Result<(), Report> {
+        let question = query
+            .questions()
+            .next()
+            .ok_or_else(|| eyre!("Cannot record a query without any question"))?;
+
+        let fixture = Fixture {
+            name: question.qname.to_string(),
+            qtype: type_name(question.qtype),
+            response: BASE64.encode(response),
+        };
+
+        let mut line = serde_json::to_vec(&fixture)?;
+        line.push(b'\n');
+
+        let lines = self.lines.as_ref().expect("only taken on drop");
+        if lines.try_send(line).is_err() {
+            warn!("Recorder is full, dropping response");
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for Recorder {
+    fn drop(&mut self) {
+        // Let the writer finish the responses queued so far.
+        drop(self.lines.take());
+
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+/// Serves the responses of a fixtures file, in place of an upstream.
+#[derive(Debug)]
+pub struct Replayer {
+    responses: HashMap<Key, Vec<u8>>,
+}
+
+impl Replayer {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, Report> {
+        let path = path.as_ref();
+
+        let file =
+            File::open(path).wrap_err_with(|| format!("Failed to open {}", path.display()))?;
+
+        let mut responses = HashMap::new();
+
+        for (i, line) in BufReader::new(file).lines().enumerate() {
+            let line = line?;
+
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let (key, response) = parse_fixture(&line)
+                .wrap_err_with(|| format!("{}: line {}", path.display(), i + 1))?;
+
+            // Later recordings of the same question win.
+            responses.insert(key, response);
+        }
+
+        Ok(Self { responses })
+    }
+
+    pub fn len(&self) -> usize {
+        self.responses.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.responses.is_empty()
+    }
+
+    /// The recorded response to `query`, with its id and question, and the
+    /// TTLs we serve local records with, so that replays are deterministic.
+    pub fn answer(&self, query: &MessageView<'_>) -> Option<Vec<u8>> {
+        let mut response = self.responses.get(&key(query)?)?.clone();
+
+        response[..2].copy_from_slice(&query.header().id.to_be_bytes());
+
+        // Keep the case of the question, which clients may check (0x20 encoding).
+        let question = query.question_bytes();
+        let recorded = MessageView::parse(&response).ok()?.question_bytes().len();
+
+        if recorded == question.len() {
+            response[HEADER_SIZE..HEADER_SIZE + recorded].copy_from_slice(question);
+        }
+
+        rewrite_ttls(&mut response, |_| db::TTL).ok()?;
+
+        Some(response)
+    }
+}
+
+fn parse_fixture(line: &str) -> Result<(Key, Vec<u8>), Report> {
+    let fixture: Fixture = serde_json::from_str(line)?;
+
+    let qtype = match fixture.qtype.strip_prefix("TYPE") {
+        Some(value) => value.parse()?,
+        None => fixture.qtype.parse::<QType>()? as u16,
+    };
+
+    let response = BASE64.decode(&fixture.response)?;
+    MessageView::parse(&response)?;
+
+    let name = fixture.name.to_ascii_lowercase();
+    Ok(((name, qtype), response))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const QUERY: &[u8] = &[
+        100, 68, 1, 0, 0, 1, 0, 0, 0, 0, 0, 0, 4, 110, 101, 119, 115, 11, 121, 99, 111, 109, 98,
+        105, 110, 97, 116, 111, 114, 3, 99, 111, 109, 0, 0, 1, 0, 1,
+    ];
+
+    const RESPONSE: &[u8] = &[
+        13, 208, 129, 128, 0, 1, 0, 1, 0, 0, 0, 0, 4, 110, 101, 119, 115, 11, 121, 99, 111, 109,
+        98, 105, 110, 97, 116, 111, 114, 3, 99, 111, 109, 0, 0, 1, 0, 1, 192, 12, 0, 1, 0, 1, 0, 0,
+        0, 60, 0, 4, 209, 216, 230, 240,
+    ];
+
+    #[test]
+    fn record_and_replay() {
+        let path = std::env::temp_dir().join(format!("denis-fixtures-{}", std::process::id()));
+        let recorder = Recorder::create(&path).unwrap();
+
+        recorder
+            .record(&MessageView::parse(QUERY).unwrap(), RESPONSE)
+            .unwrap();
+        drop(recorder);
+
+        let replayer = Replayer::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(replayer.len(), 1);
+
+        let mut query = QUERY.to_vec();
+        query[..2].copy_from_slice(&[1, 2]);
+        query[13..17].copy_from_slice(b"NEWS");
+
+        let response = replayer
+            .answer(&MessageView::parse(&query).unwrap())
+            .unwrap();
+
+        let mut expected = RESPONSE.to_vec();
+        expected[..2].copy_from_slice(&[1, 2]);
+        expected[13..17].copy_from_slice(b"NEWS");
+        expected[44..48].copy_from_slice(&db::TTL.to_be_bytes());
+
+        assert_eq!(response, expected);
+
+        // Same name, other type.
+        query[35] = QType::AAAA as u8;
+        assert_eq!(replayer.answer(&MessageView::parse(&query).unwrap()), None);
+    }
+}
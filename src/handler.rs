@@ -0,0 +1,217 @@
+use core::fmt;
+use std::{future::Future, net::SocketAddr, pin::Pin};
+
+use crate::{
+    client::Transport,
+    data::{Name, RCode},
+    querylog::Source,
+    view::{HeaderView, MessageView, QuestionView, HEADER_SIZE},
+};
+
+/// A well-formed query with a single question, as passed down the chain.
+#[derive(Clone, Debug)]
+pub struct Request<'a> {
+    /// The query, as received.
+    pub data: &'a [u8],
+    pub message: MessageView<'a>,
+    pub question: QuestionView<'a>,
+    /// The name asked about.
+    pub name: Name,
+    pub client: SocketAddr,
+    pub transport: Transport,
+}
+
+impl Request<'_> {
+    /// A response without any record, with the given rcode.
+    pub fn reply(&self, rcode: RCode, source: Source) -> Response {
+        Response::Answer {
+            message: error_response(self.data, rcode).expect("request is well-formed"),
+            source,
+        }
+    }
+}
+
+/// What a handler made of a request.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Response {
+    /// Send `message` back to the client.
+    Answer { message: Vec<u8>, source: Source },
+    /// Send nothing at all, as if the query got lost.
+    Drop,
+}
+
+pub type HandlerFuture<'a> = Pin<Box<dyn Future<Output = Option<Response>> + Send + 'a>>;
+
+/// A step of the chain answering queries, in the style of CoreDNS plugins.
+///
+/// A handler either answers a request itself, or passes it on to the rest of
+/// the chain with [`Next::run`], possibly acting on the response it gets back.
+/// `None` means that no handler answered.
+pub trait Handler: fmt::Debug + Send + Sync {
+    fn handle<'a>(&'a self, request: &'a Request<'a>, next: Next<'a>) -> HandlerFuture<'a>;
+}
+
+/// The handlers after the current one.
+#[derive(Copy, Clone, Debug)]
+pub struct Next<'a> {
+    handlers: &'a [Box<dyn Handler>],
+}
+
+impl<'a> Next<'a> {
+    pub fn run(self, request: &'a Request<'a>) -> HandlerFuture<'a> {
+        match self.handlers.split_first() {
+            Some((handler, rest)) => handler.handle(request, Next { handlers: rest }),
+            None => Box::pin(async { None }),
+        }
+    }
+}
+
+/// Handlers, run in order until one answers.
+#[derive(Debug, Default)]
+pub struct Chain {
+    handlers: Vec<Box<dyn Handler>>,
+}
+
+impl Chain {
+    pub fn new(handlers: Vec<Box<dyn Handler>>) -> Self {
+        Self { handlers }
+    }
+
+    pub fn push(&mut self, handler: impl Handler + 'static) {
+        self.handlers.push(Box::new(handler));
+    }
+
+    pub fn len(&self) -> usize {
+        self.handlers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.handlers.is_empty()
+    }
+
+    pub fn run<'a>(&'a self, request: &'a Request<'a>) -> HandlerFuture<'a> {
+        Next {
+            handlers: &self.handlers,
+        }
+        .run(request)
+    }
+}
+
+/// A response to `query` without any record, echoing its id, opcode, RD flag
+/// and question section, if that much of it can be parsed.
+pub fn error_response(query: &[u8], rcode: RCode) -> Option<Vec<u8>> {
+    let header = HeaderView::parse(query).ok()?;
+
+    // The question section can be copied as is, since it starts at the same
+    // offset in both messages and can only point back into itself.
+    let questions = match MessageView::parse(query) {
+        Ok(message) => message.question_bytes(),
+        Err(_) => &[],
+    };
+
+    let qdcount = if questions.is_empty() {
+        0
+    } else {
+        header.qdcount
+    };
+    let flags = 0x8000 | (header.flags & 0x7900) | rcode as u16;
+
+    let mut response = Vec::with_capacity(HEADER_SIZE + questions.len());
+    response.extend(header.id.to_be_bytes());
+    response.extend(flags.to_be_bytes());
+    response.extend(qdcount.to_be_bytes());
+    response.extend([0; 6]);
+    response.extend(questions);
+
+    Some(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // news.ycombinator.com A
+    const QUERY: &[u8] = &[
+        100, 68, 1, 0, 0, 1, 0, 0, 0, 0, 0, 0, 4, 110, 101, 119, 115, 11, 121, 99, 111, 109, 98,
+        105, 110, 97, 116, 111, 114, 3, 99, 111, 109, 0, 0, 1, 0, 1,
+    ];
+
+    /// Refuses the names below a zone, passing on the others.
+    #[derive(Debug)]
+    struct Block(&'static str);
+
+    impl Handler for Block {
+        fn handle<'a>(&'a self, request: &'a Request<'a>, next: Next<'a>) -> HandlerFuture<'a> {
+            Box::pin(async move {
+                if request.name.to_string().ends_with(self.0) {
+                    return Some(request.reply(RCode::Refused, Source::Blocked));
+                }
+
+                next.run(request).await
+            })
+        }
+    }
+
+    /// Sets the AA flag of whatever the rest of the chain answers.
+    #[derive(Debug)]
+    struct Authoritative;
+
+    impl Handler for Authoritative {
+        fn handle<'a>(&'a self, request: &'a Request<'a>, next: Next<'a>) -> HandlerFuture<'a> {
+            Box::pin(async move {
+                match next.run(request).await {
+                    Some(Response::Answer {
+                        mut message,
+                        source,
+                    }) => {
+                        message[2] |= 0x04;
+                        Some(Response::Answer { message, source })
+                    }
+                    response => response,
+                }
+            })
+        }
+    }
+
+    fn request(data: &[u8]) -> Request<'_> {
+        let message = MessageView::parse(data).unwrap();
+        let question = message.questions().next().unwrap();
+
+        Request {
+            data,
+            message,
+            question,
+            name: question.qname.to_name(),
+            client: "127.0.0.1:5353".parse().unwrap(),
+            transport: Transport::Udp,
+        }
+    }
+
+    fn rcode(response: Option<Response>) -> Option<(u8, bool)> {
+        match response? {
+            Response::Answer { message, .. } => {
+                let header = HeaderView::parse(&message).unwrap();
+                Some((header.rcode(), header.aa()))
+            }
+            Response::Drop => None,
+        }
+    }
+
+    #[tokio::test]
+    async fn chain() {
+        let request = request(QUERY);
+
+        assert_eq!(Chain::default().run(&request).await, None);
+
+        let mut chain = Chain::default();
+        chain.push(Authoritative);
+        chain.push(Block("example.com"));
+        assert_eq!(chain.run(&request).await, None);
+
+        chain.push(Block("ycombinator.com"));
+        assert_eq!(rcode(chain.run(&request).await), Some((5, true)));
+
+        let chain = Chain::new(vec![Box::new(Block("com")), Box::new(Authoritative)]);
+        assert_eq!(rcode(chain.run(&request).await), Some((5, false)));
+    }
+}
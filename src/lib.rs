@@ -0,0 +1,21 @@
+pub mod arena;
+pub mod cache;
+pub mod check;
+pub mod client;
+pub mod config;
+pub mod data;
+pub mod db;
+pub mod dnstap;
+pub mod export;
+pub mod fault;
+pub mod fixtures;
+pub mod handler;
+pub mod metrics;
+pub mod plugins;
+pub mod querylog;
+pub mod record;
+pub mod server;
+pub mod store;
+pub mod svcb;
+pub mod trie;
+pub mod view;
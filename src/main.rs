@@ -1,59 +1,340 @@
-use std::{net::SocketAddr, path::PathBuf};
+use std::{
+    fs::File,
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    process::ExitCode,
+    str::FromStr,
+    time::Duration,
+};
 
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use color_eyre::Report;
 
-pub mod data;
-pub mod db;
-pub mod record;
-pub mod server;
-pub mod trie;
+use denis::{
+    check,
+    client::{self, DigOutput, QueryOptions, Transport},
+    config::{Config, DnstapConfig, LogConfig, QueryLogConfig, Upstream},
+    data::{Name, QType},
+    export, server,
+};
 
+/// DNS server for local development, running `serve` when no subcommand is given.
 #[derive(Debug, Parser)]
+#[command(version, args_conflicts_with_subcommands = true)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    #[command(flatten)]
+    serve: ServeArgs,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Answer queries from a db file, forwarding the others to an upstream server
+    Serve(ServeArgs),
+
+    /// Check a db file for problems, exiting with an error if any is found
+    Check { db: PathBuf },
+
+    /// Print the parsed contents of a db file
+    Dump {
+        db: PathBuf,
+
+        #[clap(short, long, value_enum, default_value = "tree")]
+        format: DumpFormat,
+    },
+
+    /// Send a query to a DNS server and print the response, like dig
+    Query(QueryArgs),
+
+    /// Inspect the server configuration
+    #[command(subcommand)]
+    Config(ConfigCommand),
+
+    /// Print the version of denis
+    Version,
+}
+
+#[derive(Debug, Subcommand)]
+enum ConfigCommand {
+    /// Print the effective configuration, after applying command line flags
+    Print(ServeArgs),
+}
+
+#[derive(Debug, clap::Args)]
+struct ServeArgs {
+    /// Path to a TOML config file, whose settings are overridden by the flags below
+    #[clap(short, long)]
+    config: Option<PathBuf>,
+
+    /// Path to the db file
+    #[clap(short, long)]
+    db: Option<PathBuf>,
+
+    /// Keep the records in a compact, read-only arena, for very large zones
+    #[clap(long)]
+    compact: bool,
+
+    /// Server to forward unknown names to, replacing the upstreams of the config.
+    /// Without any, they get REFUSED or NXDOMAIN
+    #[clap(short, long)]
+    upstream: Option<SocketAddr>,
+
+    /// Address to listen on over UDP and TCP, can be repeated. Defaults to 127.0.0.1 on --port
     #[clap(short, long)]
-    db: PathBuf,
+    listen: Vec<SocketAddr>,
 
+    /// Port to listen on, replacing the port of every listen address. Defaults to 7777
     #[clap(short, long)]
-    upstream: SocketAddr,
+    port: Option<u16>,
 
-    #[clap(short, long, default_value = "7777")]
-    port: u16,
+    /// Address to serve Prometheus metrics on, at /metrics
+    #[clap(long)]
+    metrics: Option<SocketAddr>,
+
+    /// Log every query as JSON to a file, to stdout with `-`, or to a socket with `unix:<path>`
+    #[clap(long)]
+    query_log: Option<QueryLogConfig>,
+
+    /// Append every upstream question and response to a fixtures file
+    #[clap(long, conflicts_with = "replay")]
+    record: Option<PathBuf>,
+
+    /// Answer from a fixtures file made with --record instead of any upstream,
+    /// with SERVFAIL for the questions it does not have
+    #[clap(long)]
+    replay: Option<PathBuf>,
+
+    /// Write dnstap frames to a file, or to a Frame Streams socket with `unix:<path>`
+    #[clap(long)]
+    dnstap: Option<DnstapConfig>,
+}
+
+#[derive(Copy, Clone, Debug, clap::ValueEnum)]
+enum DumpFormat {
+    Tree,
+    Zone,
+    Json,
+    Dot,
 }
 
-impl Args {
-    fn listen_addr(&self) -> (&str, u16) {
-        ("127.0.0.1", self.port)
+#[derive(Debug, clap::Args)]
+struct QueryArgs {
+    /// Name to look up
+    name: Name,
+
+    /// Record type to ask for
+    #[clap(default_value = "A")]
+    qtype: QType,
+
+    /// Server to query, on port 53 unless given
+    #[clap(short, long, default_value = "127.0.0.1:7777", value_parser = parse_server)]
+    server: SocketAddr,
+
+    /// Query over TCP instead of UDP
+    #[clap(long)]
+    tcp: bool,
+
+    /// Do not set the RD (recursion desired) flag
+    #[clap(long)]
+    norecurse: bool,
+
+    /// Set the DO (DNSSEC OK) flag
+    #[clap(long)]
+    dnssec: bool,
+
+    /// Advertised EDNS UDP payload size
+    #[clap(long, default_value = "1232")]
+    edns_size: u16,
+
+    /// Send the query without EDNS
+    #[clap(long, conflicts_with_all = ["dnssec", "edns_size"])]
+    no_edns: bool,
+
+    /// Seconds to wait for a response
+    #[clap(long, default_value = "5")]
+    timeout: u64,
+}
+
+fn parse_server(s: &str) -> Result<SocketAddr, String> {
+    SocketAddr::from_str(s)
+        .or_else(|_| IpAddr::from_str(s).map(|ip| SocketAddr::new(ip, 53)))
+        .map_err(|_| format!("invalid server address: {s}"))
+}
+
+impl ServeArgs {
+    /// The config file, if any, with the command line flags applied.
+    fn config(&self) -> Result<Config, Report> {
+        let mut config = match &self.config {
+            Some(path) => Config::load(path)?,
+            None => Config::default(),
+        };
+
+        if !self.listen.is_empty() {
+            config.listen = self.listen.clone();
+        }
+
+        if let Some(metrics) = self.metrics {
+            config.metrics = Some(metrics);
+        }
+
+        if let Some(query_log) = &self.query_log {
+            config.query_log = Some(query_log.clone());
+        }
+
+        if let Some(dnstap) = &self.dnstap {
+            config.dnstap = Some(dnstap.clone());
+        }
+
+        if let Some(record) = &self.record {
+            config.record = Some(record.clone());
+        }
+
+        if let Some(replay) = &self.replay {
+            config.replay = Some(replay.clone());
+        }
+
+        if let Some(db) = &self.db {
+            config.db = vec![db.clone()];
+        }
+
+        if self.compact {
+            config.compact = true;
+        }
+
+        if let Some(upstream) = self.upstream {
+            config.upstreams = vec![Upstream::new("default", upstream)];
+            config.rules.clear();
+        }
+
+        let mut config = config.validate()?;
+
+        if let Some(port) = self.port {
+            for addr in &mut config.listen {
+                addr.set_port(port);
+            }
+        }
+
+        Ok(config)
     }
 }
 
 #[tokio::main]
-async fn main() -> Result<(), Report> {
-    setup()?;
+async fn main() -> Result<ExitCode, Report> {
+    color_eyre::install()?;
 
     let args = Args::parse();
-    server::run(&args.db, args.listen_addr(), args.upstream).await?;
 
-    Ok(())
+    let serve = match &args.command {
+        None => Some(&args.serve),
+        Some(Command::Serve(serve)) => Some(serve),
+        _ => None,
+    };
+
+    let config = serve.map(ServeArgs::config).transpose()?;
+    setup(config.as_ref());
+
+    match &args.command {
+        None | Some(Command::Serve(_)) => run_serve(config.as_ref().unwrap()).await,
+        Some(Command::Config(ConfigCommand::Print(serve))) => {
+            print!("{}", serve.config()?.to_toml()?);
+            Ok(ExitCode::SUCCESS)
+        }
+        Some(Command::Check { db }) => run_check(db),
+        Some(Command::Dump { db, format }) => run_dump(db, *format),
+        Some(Command::Query(query)) => run_query(query).await,
+        Some(Command::Version) => {
+            println!("denis {}", env!("CARGO_PKG_VERSION"));
+            Ok(ExitCode::SUCCESS)
+        }
+    }
+}
+
+async fn run_serve(config: &Config) -> Result<ExitCode, Report> {
+    server::run(config).await?;
+
+    Ok(ExitCode::SUCCESS)
 }
 
-fn setup() -> Result<(), Report> {
+fn run_dump(db: &Path, format: DumpFormat) -> Result<ExitCode, Report> {
+    let db = denis::db::load(db)?;
+    let stdout = std::io::stdout().lock();
+
+    match format {
+        DumpFormat::Tree => println!("{db}"),
+        DumpFormat::Zone => export::zone(&db, stdout)?,
+        DumpFormat::Json => export::write_json(&db, stdout)?,
+        DumpFormat::Dot => export::dot(&db, stdout)?,
+    }
+
+    Ok(ExitCode::SUCCESS)
+}
+
+fn run_check(db: &Path) -> Result<ExitCode, Report> {
+    let problems = check::check(File::open(db)?)?;
+
+    for problem in &problems {
+        println!("{}: {problem}", db.display());
+    }
+
+    if problems.is_empty() {
+        Ok(ExitCode::SUCCESS)
+    } else {
+        eprintln!("Found {} problem(s) in {}", problems.len(), db.display());
+        Ok(ExitCode::FAILURE)
+    }
+}
+
+async fn run_query(args: &QueryArgs) -> Result<ExitCode, Report> {
+    let options = QueryOptions {
+        transport: if args.tcp {
+            Transport::Tcp
+        } else {
+            Transport::Udp
+        },
+        recursion_desired: !args.norecurse,
+        dnssec_ok: args.dnssec,
+        edns_size: (!args.no_edns).then_some(args.edns_size),
+        timeout: Duration::from_secs(args.timeout),
+        ..QueryOptions::new(args.server)
+    };
+
+    let response = client::query(&args.name, args.qtype, &options).await?;
+    print!("{}", DigOutput(&response));
+
+    Ok(ExitCode::SUCCESS)
+}
+
+fn setup(config: Option<&Config>) {
     use tracing_subscriber::EnvFilter;
 
+    let default = LogConfig::default();
+    let log = config.map_or(&default, |config| &config.log);
+
+    // Keep stdout for the query log when it goes there.
+    let stderr = matches!(
+        config.and_then(|config| config.query_log.as_ref()),
+        Some(QueryLogConfig::Stdout)
+    );
+
     // if std::env::var("RUST_LIB_BACKTRACE").is_err() {
     //     std::env::set_var("RUST_LIB_BACKTRACE", "0")
     // }
 
-    color_eyre::install()?;
-
-    if std::env::var("RUST_LOG").is_err() {
-        std::env::set_var("RUST_LOG", "info")
-    }
+    // RUST_LOG takes precedence over the configured level.
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(&log.level));
 
     tracing_subscriber::fmt::fmt()
-        .with_env_filter(EnvFilter::from_default_env())
+        .with_env_filter(filter)
+        .with_ansi(log.ansi)
         .with_target(false)
+        .with_writer(move || -> Box<dyn std::io::Write> {
+            if stderr {
+                Box::new(std::io::stderr())
+            } else {
+                Box::new(std::io::stdout())
+            }
+        })
         .init();
-
-    Ok(())
 }
@@ -0,0 +1,442 @@
+use std::{
+    fmt::Write,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicI64, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use color_eyre::{owo_colors::OwoColorize, Report};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+use tracing::{debug, info, warn};
+
+use crate::{client::rcode_name, data::QType, view::HeaderView};
+
+/// Query types are counted by value up to CAA, and together above it.
+const QTYPES: usize = QType::CAA as usize + 2;
+
+/// Upper bounds of the latency histogram buckets, in seconds.
+const BUCKETS: [f64; 12] = [
+    0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0,
+];
+
+/// Counters for the Prometheus endpoint.
+///
+/// Everything updated while answering queries is a relaxed atomic, so that
+/// collecting metrics costs next to nothing on the hot path.
+#[derive(Debug)]
+pub struct Metrics {
+    queries: [AtomicU64; QTYPES],
+    responses: [AtomicU64; 16],
+    local: AtomicU64,
+    forwarded: AtomicU64,
+    cache_hits: AtomicU64,
+    cache_misses: AtomicU64,
+    in_flight: AtomicI64,
+    malformed: AtomicU64,
+    reloads: AtomicU64,
+    reload_errors: AtomicU64,
+    upstreams: Mutex<Vec<Arc<UpstreamMetrics>>>,
+}
+
+/// Latency and failures of queries forwarded to a given upstream.
+#[derive(Debug)]
+pub struct UpstreamMetrics {
+    name: String,
+    buckets: [AtomicU64; BUCKETS.len() + 1],
+    sum_micros: AtomicU64,
+    errors: AtomicU64,
+}
+
+/// Counts a request as in flight until dropped.
+#[derive(Debug)]
+pub struct InFlight<'a>(&'a AtomicI64);
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self {
+            queries: std::array::from_fn(|_| AtomicU64::new(0)),
+            responses: std::array::from_fn(|_| AtomicU64::new(0)),
+            local: AtomicU64::new(0),
+            forwarded: AtomicU64::new(0),
+            cache_hits: AtomicU64::new(0),
+            cache_misses: AtomicU64::new(0),
+            in_flight: AtomicI64::new(0),
+            malformed: AtomicU64::new(0),
+            reloads: AtomicU64::new(0),
+            reload_errors: AtomicU64::new(0),
+            upstreams: Mutex::new(Vec::new()),
+        }
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn in_flight(&self) -> InFlight<'_> {
+        self.in_flight.fetch_add(1, Ordering::Relaxed);
+        InFlight(&self.in_flight)
+    }
+
+    pub fn query(&self, qtype: u16) {
+        let index = (qtype as usize).min(QTYPES - 1);
+        self.queries[index].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a response sent back to a client, by rcode.
+    pub fn response(&self, response: &[u8]) {
+        if let Ok(header) = HeaderView::parse(response) {
+            self.responses[header.rcode() as usize].fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn local(&self) {
+        self.local.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn forwarded(&self) {
+        self.forwarded.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn cache(&self, hit: bool) {
+        let counter = if hit {
+            &self.cache_hits
+        } else {
+            &self.cache_misses
+        };
+
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn malformed(&self) {
+        self.malformed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn reload(&self, ok: bool) {
+        let counter = if ok {
+            &self.reloads
+        } else {
+            &self.reload_errors
+        };
+
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Register an upstream, returning the metrics to update when forwarding to it.
+    pub fn upstream(&self, name: &str) -> Arc<UpstreamMetrics> {
+        let upstream = Arc::new(UpstreamMetrics {
+            name: name.to_string(),
+            buckets: std::array::from_fn(|_| AtomicU64::new(0)),
+            sum_micros: AtomicU64::new(0),
+            errors: AtomicU64::new(0),
+        });
+
+        self.upstreams.lock().unwrap().push(upstream.clone());
+        upstream
+    }
+
+    /// Render the metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let get = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+
+        header(
+            &mut out,
+            "denis_queries_total",
+            "counter",
+            "Queries received, by type",
+        );
+        for (qtype, counter) in self.queries.iter().enumerate() {
+            let count = get(counter);
+
+            if count > 0 {
+                let name = match QType::try_from(qtype as u16) {
+                    Ok(qtype) => qtype.to_string(),
+                    Err(_) if qtype == QTYPES - 1 => "OTHER".to_string(),
+                    Err(_) => format!("TYPE{qtype}"),
+                };
+
+                writeln!(out, "denis_queries_total{{type=\"{name}\"}} {count}").unwrap();
+            }
+        }
+
+        header(
+            &mut out,
+            "denis_responses_total",
+            "counter",
+            "Responses sent, by rcode",
+        );
+        for (rcode, counter) in self.responses.iter().enumerate() {
+            let count = get(counter);
+
+            if count > 0 {
+                let name = rcode_name(rcode as u8);
+                writeln!(out, "denis_responses_total{{rcode=\"{name}\"}} {count}").unwrap();
+            }
+        }
+
+        header(
+            &mut out,
+            "denis_answers_total",
+            "counter",
+            "Queries answered, by source",
+        );
+        let sources = [
+            ("local", &self.local),
+            ("upstream", &self.forwarded),
+            ("cache", &self.cache_hits),
+        ];
+        for (source, counter) in sources {
+            writeln!(
+                out,
+                "denis_answers_total{{source=\"{source}\"}} {}",
+                get(counter)
+            )
+            .unwrap();
+        }
+
+        let counters = [
+            (
+                "denis_cache_hits_total",
+                "Forwarded queries answered from the cache",
+                &self.cache_hits,
+            ),
+            (
+                "denis_cache_misses_total",
+                "Forwarded queries missing from the cache",
+                &self.cache_misses,
+            ),
+            (
+                "denis_malformed_total",
+                "Requests which could not be parsed",
+                &self.malformed,
+            ),
+            (
+                "denis_db_reloads_total",
+                "Successful reloads of the db files",
+                &self.reloads,
+            ),
+            (
+                "denis_db_reload_errors_total",
+                "Failed reloads of the db files",
+                &self.reload_errors,
+            ),
+        ];
+
+        for (name, help, counter) in counters {
+            header(&mut out, name, "counter", help);
+            writeln!(out, "{name} {}", get(counter)).unwrap();
+        }
+
+        header(
+            &mut out,
+            "denis_in_flight_requests",
+            "gauge",
+            "Requests being answered",
+        );
+        let in_flight = self.in_flight.load(Ordering::Relaxed);
+        writeln!(out, "denis_in_flight_requests {in_flight}").unwrap();
+
+        let upstreams = self.upstreams.lock().unwrap();
+
+        let name = "denis_upstream_duration_seconds";
+        header(
+            &mut out,
+            name,
+            "histogram",
+            "Time to get a response from each upstream",
+        );
+
+        for upstream in upstreams.iter() {
+            let label = &upstream.name;
+            let mut total = 0;
+
+            for (i, counter) in upstream.buckets.iter().enumerate() {
+                total += get(counter);
+
+                let le = match BUCKETS.get(i) {
+                    Some(bound) => bound.to_string(),
+                    None => "+Inf".to_string(),
+                };
+
+                writeln!(
+                    out,
+                    "{name}_bucket{{upstream=\"{label}\",le=\"{le}\"}} {total}"
+                )
+                .unwrap();
+            }
+
+            let sum = get(&upstream.sum_micros) as f64 / 1e6;
+            writeln!(out, "{name}_sum{{upstream=\"{label}\"}} {sum}").unwrap();
+            writeln!(out, "{name}_count{{upstream=\"{label}\"}} {total}").unwrap();
+        }
+
+        let name = "denis_upstream_errors_total";
+        header(
+            &mut out,
+            name,
+            "counter",
+            "Queries which an upstream failed to answer",
+        );
+
+        for upstream in upstreams.iter() {
+            let errors = get(&upstream.errors);
+            writeln!(out, "{name}{{upstream=\"{}\"}} {errors}", upstream.name).unwrap();
+        }
+
+        out
+    }
+}
+
+impl UpstreamMetrics {
+    pub fn observe(&self, elapsed: Duration) {
+        let seconds = elapsed.as_secs_f64();
+        let bucket = BUCKETS
+            .iter()
+            .position(|&bound| seconds <= bound)
+            .unwrap_or(BUCKETS.len());
+
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    pub fn error(&self) {
+        self.errors.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    writeln!(out, "# HELP {name} {help}").unwrap();
+    writeln!(out, "# TYPE {name} {kind}").unwrap();
+}
+
+/// Serve the metrics over HTTP at `/metrics`.
+pub async fn serve(addr: SocketAddr, metrics: Arc<Metrics>) -> Result<(), Report> {
+    let listener = TcpListener::bind(addr).await?;
+
+    info!(
+        "Serving metrics on {}",
+        format!("http://{}/metrics", listener.local_addr()?)
+            .cyan()
+            .underline(),
+    );
+
+    loop {
+        let (stream, addr) = match listener.accept().await {
+            Ok(conn) => conn,
+            Err(err) => {
+                warn!("Failed to accept metrics connection: {err}");
+                continue;
+            }
+        };
+
+        let metrics = metrics.clone();
+
+        tokio::spawn(async move {
+            if let Err(err) = handle_http(stream, &metrics).await {
+                debug!("Metrics connection from {addr} failed: {err}");
+            }
+        });
+    }
+}
+
+async fn handle_http(mut stream: TcpStream, metrics: &Metrics) -> Result<(), Report> {
+    let mut request = Vec::new();
+    let mut buf = [0; 1024];
+
+    // We only look at the request line, but wait for the whole head so that
+    // the client does not get a reset for writing into a closed socket.
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") && request.len() < 8192 {
+        let count = stream.read(&mut buf).await?;
+
+        if count == 0 {
+            break;
+        }
+
+        request.extend_from_slice(&buf[..count]);
+    }
+
+    let request = String::from_utf8_lossy(&request);
+    let mut parts = request.split_whitespace();
+
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", metrics.render()),
+        (Some("GET"), _) => ("404 Not Found", "Not found\n".to_string()),
+        _ => ("405 Method Not Allowed", "Method not allowed\n".to_string()),
+    };
+
+    let response = format!(
+        "HTTP/1.1 {status}\r\n\
+         Content-Type: text/plain; version=0.0.4\r\n\
+         Content-Length: {}\r\n\
+         Connection: close\r\n\
+         \r\n\
+         {body}",
+        body.len()
+    );
+
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render() {
+        let metrics = Metrics::new();
+
+        metrics.query(QType::A as u16);
+        metrics.query(QType::A as u16);
+        metrics.query(QType::CAA as u16);
+        metrics.query(4242);
+        metrics.response(&[0, 1, 0x81, 0x83, 0, 0, 0, 0, 0, 0, 0, 0]);
+        metrics.cache(true);
+
+        let upstream = metrics.upstream("cloudflare");
+        upstream.observe(Duration::from_millis(3));
+        upstream.observe(Duration::from_secs(10));
+
+        {
+            let _in_flight = metrics.in_flight();
+            assert!(metrics.render().contains("denis_in_flight_requests 1\n"));
+        }
+
+        let out = metrics.render();
+
+        let expected = [
+            "denis_queries_total{type=\"A\"} 2\n",
+            "denis_queries_total{type=\"CAA\"} 1\n",
+            "denis_queries_total{type=\"OTHER\"} 1\n",
+            "denis_responses_total{rcode=\"NXDOMAIN\"} 1\n",
+            "denis_answers_total{source=\"cache\"} 1\n",
+            "denis_in_flight_requests 0\n",
+            "denis_upstream_duration_seconds_bucket{upstream=\"cloudflare\",le=\"0.0025\"} 0\n",
+            "denis_upstream_duration_seconds_bucket{upstream=\"cloudflare\",le=\"0.005\"} 1\n",
+            "denis_upstream_duration_seconds_bucket{upstream=\"cloudflare\",le=\"+Inf\"} 2\n",
+            "denis_upstream_duration_seconds_sum{upstream=\"cloudflare\"} 10.003\n",
+            "denis_upstream_duration_seconds_count{upstream=\"cloudflare\"} 2\n",
+        ];
+
+        for line in expected {
+            assert!(out.contains(line), "missing {line:?} in:\n{out}");
+        }
+    }
+}
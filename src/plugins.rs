@@ -0,0 +1,724 @@
+use std::{
+    net::SocketAddr,
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
+};
+
+use color_eyre::{eyre::eyre, owo_colors::OwoColorize, Report};
+use deku::DekuContainerWrite;
+use rand::Rng;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpStream, UdpSocket},
+    time::timeout,
+};
+use tracing::{debug, error, info, trace};
+
+use crate::{
+    cache,
+    client::{type_name, Transport},
+    config::{CacheConfig, Config, Upstream},
+    data::{Edns, MessageBuilder, Name, Opcode, QType, RCode, ResourceRecord},
+    db::{self, Db, Presence},
+    dnstap::{Dnstap, Event as DnstapEvent, MessageType},
+    fault::Action,
+    fixtures::{Recorder, Replayer},
+    handler::{error_response, Handler, HandlerFuture, Next, Request, Response},
+    metrics::{Metrics, UpstreamMetrics},
+    querylog::Source,
+    server::MAX_MESSAGE_SIZE,
+    store::Store,
+    view::{HeaderView, MessageView, QuestionView, HEADER_SIZE},
+};
+
+/// Applies the `$FAULT` rules of the database, before the rest of the chain.
+#[derive(Debug)]
+pub struct Faults {
+    store: Arc<Store>,
+}
+
+impl Faults {
+    pub fn new(store: Arc<Store>) -> Self {
+        Self { store }
+    }
+}
+
+impl Handler for Faults {
+    fn handle<'a>(&'a self, request: &'a Request<'a>, next: Next<'a>) -> HandlerFuture<'a> {
+        Box::pin(async move {
+            let db = self.store.load();
+            let injected = inject_faults(&db, &request.name).await;
+
+            match injected {
+                Some(Action::Drop) => {
+                    debug!("Dropping query with id {}", request.message.header().id);
+                    return Some(Response::Drop);
+                }
+                Some(Action::ServFail) => {
+                    return Some(request.reply(RCode::ServerFailure, Source::Fault))
+                }
+                Some(Action::Refused) => return Some(request.reply(RCode::Refused, Source::Fault)),
+                Some(Action::Truncate) => {
+                    let mut message = error_response(request.data, RCode::NoError)?;
+                    message[2] |= 0x02;
+
+                    return Some(Response::Answer {
+                        message,
+                        source: Source::Fault,
+                    });
+                }
+                _ => (),
+            }
+
+            let response = next.run(request).await;
+
+            let faulty = |message| {
+                Some(Response::Answer {
+                    message,
+                    source: Source::Fault,
+                })
+            };
+
+            match (injected, response) {
+                (Some(Action::WrongId), Some(Response::Answer { mut message, .. })) => {
+                    let id =
+                        request.message.header().id ^ rand::thread_rng().gen_range(1..=u16::MAX);
+                    message[..2].copy_from_slice(&id.to_be_bytes());
+                    faulty(message)
+                }
+                (Some(Action::Garbage), Some(Response::Answer { mut message, .. })) => {
+                    rand::thread_rng().fill(&mut message[..]);
+                    faulty(message)
+                }
+                (_, response) => response,
+            }
+        })
+    }
+}
+
+/// Sleep for the delays of the fault rules matching `name` which fire this
+/// time, until one with another action fires, which is then returned.
+async fn inject_faults(db: &Db, name: &Name) -> Option<Action> {
+    for fault in db.faults_for(name).filter(|fault| fault.fires()) {
+        match fault.action {
+            Action::Delay { .. } => {
+                let delay = fault.delay();
+                debug!("Delaying answer for {name} by {delay:?}");
+                tokio::time::sleep(delay).await;
+            }
+            action => {
+                debug!("Injecting {action} for {name}");
+                return Some(action);
+            }
+        }
+    }
+
+    None
+}
+
+/// Answers from the database, passing on the names it has no record for,
+/// unless they belong to one of our `zones`.
+///
+/// When the rest of the chain does not answer either, names unknown to the
+/// database get REFUSED, and missing names below known ones or in one of our
+/// `zones` get NXDOMAIN.
+#[derive(Debug)]
+pub struct Local {
+    store: Arc<Store>,
+    /// Zones we are authoritative for.
+    zones: Vec<Name>,
+    metrics: Arc<Metrics>,
+}
+
+impl Local {
+    pub fn new(store: Arc<Store>, zones: Vec<Name>, metrics: Arc<Metrics>) -> Self {
+        Self {
+            store,
+            zones,
+            metrics,
+        }
+    }
+
+    fn is_authoritative(&self, name: &Name) -> bool {
+        self.zones.iter().any(|zone| in_zone(name, zone))
+    }
+
+    fn respond(
+        &self,
+        request: &Request<'_>,
+        answers: Vec<ResourceRecord>,
+        rcode: RCode,
+    ) -> Option<Response> {
+        let source = match rcode {
+            RCode::Refused => Source::Blocked,
+            _ => Source::Local,
+        };
+
+        match build_response(&request.message, answers, rcode, request.transport) {
+            Ok(message) => {
+                self.metrics.local();
+                Some(Response::Answer { message, source })
+            }
+            Err(err) => {
+                error!("Failed to build response: {err}");
+                Some(request.reply(RCode::ServerFailure, Source::Local))
+            }
+        }
+    }
+}
+
+impl Handler for Local {
+    fn handle<'a>(&'a self, request: &'a Request<'a>, next: Next<'a>) -> HandlerFuture<'a> {
+        Box::pin(async move {
+            let db = self.store.load();
+
+            match answer_question(&db, &request.question) {
+                Ok(Some(records)) => return self.respond(request, records, RCode::NoError),
+                Ok(None) => (),
+                Err(err) => {
+                    error!("Failed to handle message: {err}");
+                    return Some(request.reply(RCode::ServerFailure, Source::Local));
+                }
+            }
+
+            let authoritative = self.is_authoritative(&request.name);
+
+            if !authoritative {
+                if let Some(response) = next.run(request).await {
+                    return Some(response);
+                }
+            }
+
+            let rcode = match db.presence(&request.name) {
+                Presence::Exists => RCode::NoError,
+                Presence::Missing => RCode::NameError,
+                Presence::Unknown if authoritative => RCode::NameError,
+                Presence::Unknown => RCode::Refused,
+            };
+
+            self.respond(request, vec![], rcode)
+        })
+    }
+}
+
+/// A response to the single-question `message`, with the given answers,
+/// truncated should it be too large for a client over UDP.
+fn build_response(
+    message: &MessageView<'_>,
+    answers: Vec<ResourceRecord>,
+    rcode: RCode,
+    transport: Transport,
+) -> Result<Vec<u8>, Report> {
+    let header = message.header();
+    let opcode = Opcode::try_from(header.opcode())?;
+
+    let Some(question) = message.questions().next() else {
+        return Err(eyre!("Message without any question"));
+    };
+
+    let question = question.to_question()?;
+
+    let response = |answers| {
+        let mut response = MessageBuilder::response(header.id, opcode, header.rd())
+            .question(question.clone())
+            .answers(answers)
+            .rcode(rcode)
+            .authoritative(rcode != RCode::Refused);
+
+        if let Some(edns) = message.edns() {
+            response = response.edns(Edns {
+                dnssec_ok: edns.dnssec_ok,
+                ..Edns::default()
+            });
+        }
+
+        response
+    };
+
+    let data = response(answers).to_bytes()?;
+
+    // The client is to retry over TCP, seeing TC set.
+    if transport == Transport::Udp && data.len() > payload_size(message) {
+        return response(vec![]).truncated(true).to_bytes();
+    }
+
+    Ok(data)
+}
+
+fn answer_question(
+    db: &Db,
+    question: &QuestionView<'_>,
+) -> Result<Option<Vec<ResourceRecord>>, Report> {
+    let now = Instant::now();
+
+    let Ok(qtype) = QType::try_from(question.qtype) else {
+        return Ok(None);
+    };
+
+    let qname = question.qname.to_name();
+
+    info!(
+        "<== {:<50}    {:?}",
+        format!("{qname:#}").blue().bold().to_string(),
+        qtype.green().bold(),
+    );
+
+    let records = db.lookup_all(&qname, qtype);
+
+    if records.is_empty() {
+        return Ok(None);
+    }
+
+    let elapsed = now.elapsed().as_millis();
+
+    let answers = records
+        .into_iter()
+        .map(|record| {
+            info!(
+                "==> {:<50}    {:#}          {}",
+                format!("{qname:#}").blue().bold().to_string(),
+                record,
+                format!("{elapsed}ms").dimmed()
+            );
+
+            let data = record.to_bytes();
+
+            ResourceRecord {
+                name: qname.clone(),
+                qtype: record.qtype(),
+                qclass: record.qclass(),
+                udp_payload_size: None,
+                ttl: db::TTL as i32,
+                rdlength: data.len() as u16,
+                data,
+            }
+        })
+        .collect();
+
+    Ok(Some(answers))
+}
+
+/// Answers from the upstream responses given by the rest of the chain, for
+/// as long as their TTLs allow.
+#[derive(Debug)]
+pub struct Cache {
+    cache: cache::Cache,
+    metrics: Arc<Metrics>,
+}
+
+impl Cache {
+    pub fn new(config: CacheConfig, metrics: Arc<Metrics>) -> Self {
+        Self {
+            cache: cache::Cache::new(config),
+            metrics,
+        }
+    }
+}
+
+impl Handler for Cache {
+    fn handle<'a>(&'a self, request: &'a Request<'a>, next: Next<'a>) -> HandlerFuture<'a> {
+        Box::pin(async move {
+            if let Some(mut message) = self.cache.get(&request.message) {
+                debug!("Answering from cache");
+                self.metrics.cache(true);
+
+                // It may have been cached for a client taking larger responses.
+                let size = payload_size(&request.message);
+                if request.transport == Transport::Udp && message.len() > size {
+                    message = truncate(&message).unwrap_or(message);
+                }
+
+                return Some(Response::Answer {
+                    message,
+                    source: Source::Cache,
+                });
+            }
+
+            let response = next.run(request).await;
+
+            if let Some(Response::Answer {
+                message,
+                source: Source::Upstream,
+            }) = &response
+            {
+                self.metrics.cache(false);
+                self.cache.insert(&request.message, message);
+            }
+
+            response
+        })
+    }
+}
+
+/// Answers from the responses recorded with `--record`, instead of any
+/// upstream, and with SERVFAIL for the questions it does not have.
+#[derive(Debug)]
+pub struct Replay {
+    replayer: Replayer,
+    metrics: Arc<Metrics>,
+}
+
+impl Replay {
+    pub fn new(replayer: Replayer, metrics: Arc<Metrics>) -> Self {
+        Self { replayer, metrics }
+    }
+}
+
+impl Handler for Replay {
+    fn handle<'a>(&'a self, request: &'a Request<'a>, _next: Next<'a>) -> HandlerFuture<'a> {
+        Box::pin(async move {
+            self.metrics.forwarded();
+
+            let Some(message) = self.replayer.answer(&request.message) else {
+                error!(
+                    "No recorded response for {} {}",
+                    request.name,
+                    type_name(request.question.qtype)
+                );
+                return Some(request.reply(RCode::ServerFailure, Source::Upstream));
+            };
+
+            debug!("Answering from recorded response");
+
+            Some(Response::Answer {
+                message,
+                source: Source::Upstream,
+            })
+        })
+    }
+}
+
+/// Forwards to upstream servers, passing on the names none of them handles.
+#[derive(Debug)]
+pub struct Forward {
+    /// Forwarding rules, most specific zone first.
+    routes: Vec<(Name, Forwarder)>,
+    recorder: Option<Recorder>,
+    metrics: Arc<Metrics>,
+}
+
+impl Forward {
+    pub fn new(
+        mut routes: Vec<(Name, Forwarder)>,
+        recorder: Option<Recorder>,
+        metrics: Arc<Metrics>,
+    ) -> Self {
+        routes.sort_by_key(|(zone, _)| std::cmp::Reverse(zone.labels().len()));
+
+        Self {
+            routes,
+            recorder,
+            metrics,
+        }
+    }
+
+    /// Connect to the upstreams of `config`, and route to them by its rules.
+    pub async fn connect(
+        config: &Config,
+        metrics: Arc<Metrics>,
+        dnstap: Option<Dnstap>,
+    ) -> Result<Self, Report> {
+        let mut forwarders = Vec::with_capacity(config.upstreams.len());
+
+        for upstream in &config.upstreams {
+            forwarders.push(Forwarder::connect(upstream, &metrics, dnstap.clone()).await?);
+        }
+
+        let forwarder = |name: &str| {
+            let forwarder = forwarders.iter().find(|forwarder| forwarder.name == name);
+            forwarder
+                .cloned()
+                .ok_or_else(|| eyre!("Forward rule uses unknown upstream `{name}`"))
+        };
+
+        let mut routes = config
+            .rules
+            .iter()
+            .map(|rule| Ok((Name::from_str(&rule.zone)?, forwarder(&rule.upstream)?)))
+            .collect::<Result<Vec<_>, Report>>()?;
+
+        // Without any rule, everything goes to the first upstream.
+        if let (true, Some(first)) = (routes.is_empty(), forwarders.first()) {
+            routes.push((Name::root(), first.clone()));
+        }
+
+        if routes.is_empty() {
+            info!("No upstream, answering for local names only");
+        }
+
+        let recorder = config.record.as_ref().map(Recorder::create).transpose()?;
+
+        Ok(Self::new(routes, recorder, metrics))
+    }
+
+    /// The upstream to forward questions about `name` to, if any.
+    fn route(&self, name: &Name) -> Option<&Forwarder> {
+        self.routes
+            .iter()
+            .find(|(zone, _)| in_zone(name, zone))
+            .map(|(_, forwarder)| forwarder)
+    }
+}
+
+impl Handler for Forward {
+    fn handle<'a>(&'a self, request: &'a Request<'a>, next: Next<'a>) -> HandlerFuture<'a> {
+        Box::pin(async move {
+            let Some(forwarder) = self.route(&request.name) else {
+                return next.run(request).await;
+            };
+
+            self.metrics.forwarded();
+            debug!("Forwarding request to upstream {}", forwarder.name);
+
+            match forward(forwarder, request.data, request.transport).await {
+                Ok(message) => {
+                    if let Some(recorder) = &self.recorder {
+                        if let Err(err) = recorder.record(&request.message, &message) {
+                            error!("Failed to record response: {err}");
+                        }
+                    }
+
+                    Some(Response::Answer {
+                        message,
+                        source: Source::Upstream,
+                    })
+                }
+                Err(err) => {
+                    error!("Failed to forward request: {err}");
+                    Some(request.reply(RCode::ServerFailure, Source::Upstream))
+                }
+            }
+        })
+    }
+}
+
+/// A client of an upstream server.
+///
+/// Each query goes out from a socket of its own, so that concurrent queries
+/// never get each other's responses, and late responses find no one to read
+/// them.
+#[derive(Clone, Debug)]
+pub struct Forwarder {
+    name: String,
+    address: SocketAddr,
+    timeout: Duration,
+    metrics: Arc<UpstreamMetrics>,
+    dnstap: Option<Dnstap>,
+}
+
+impl Forwarder {
+    pub async fn connect(
+        upstream: &Upstream,
+        metrics: &Metrics,
+        dnstap: Option<Dnstap>,
+    ) -> Result<Self, Report> {
+        // Check early that we can reach the upstream at all.
+        Self::socket(upstream.address).await?;
+
+        info!(
+            "Forwarding to upstream {} at {}",
+            upstream.name.bold(),
+            upstream.address.to_string().cyan().underline(),
+        );
+
+        Ok(Self {
+            name: upstream.name.clone(),
+            address: upstream.address,
+            timeout: upstream.timeout(),
+            metrics: metrics.upstream(&upstream.name),
+            dnstap,
+        })
+    }
+
+    async fn socket(address: SocketAddr) -> Result<UdpSocket, Report> {
+        let local = match address {
+            SocketAddr::V4(_) => "0.0.0.0:0",
+            SocketAddr::V6(_) => "[::]:0",
+        };
+
+        let socket = UdpSocket::bind(local).await?;
+        socket.connect(address).await?;
+        Ok(socket)
+    }
+
+    /// Forward a query sent over `transport`, and return the response.
+    ///
+    /// Queries sent over UDP go out over UDP, and again over TCP when the
+    /// response is truncated, unless the full one is too large for the client.
+    pub async fn forward(&self, data: &[u8], transport: Transport) -> Result<Vec<u8>, Report> {
+        let start = Instant::now();
+        let result = self.resolve(data, transport).await;
+
+        match &result {
+            Ok(_) => self.metrics.observe(start.elapsed()),
+            Err(_) => self.metrics.error(),
+        }
+
+        result
+    }
+
+    async fn resolve(&self, data: &[u8], transport: Transport) -> Result<Vec<u8>, Report> {
+        let query = MessageView::parse(data)?;
+
+        if transport == Transport::Tcp {
+            return self.exchange(&query, Transport::Tcp).await;
+        }
+
+        let response = self.exchange(&query, Transport::Udp).await?;
+
+        if !HeaderView::parse(&response)?.tc() {
+            return Ok(response);
+        }
+
+        debug!("Truncated response from {}, retrying over TCP", self.name);
+
+        match self.exchange(&query, Transport::Tcp).await {
+            Ok(full) if full.len() <= payload_size(&query) => Ok(full),
+            Ok(_) => Ok(response),
+            Err(err) => {
+                debug!("Failed to retry over TCP: {err}");
+                Ok(response)
+            }
+        }
+    }
+
+    fn tap(
+        &self,
+        kind: MessageType,
+        transport: Transport,
+        local: SocketAddr,
+        query_time: SystemTime,
+        message: &[u8],
+    ) {
+        if let Some(dnstap) = &self.dnstap {
+            dnstap.log(&DnstapEvent {
+                kind,
+                transport,
+                query_address: local,
+                response_address: self.address,
+                query_time,
+                response_time: (!kind.is_query()).then(SystemTime::now),
+                message,
+            });
+        }
+    }
+
+    async fn exchange(
+        &self,
+        query: &MessageView<'_>,
+        transport: Transport,
+    ) -> Result<Vec<u8>, Report> {
+        let data = query.as_bytes();
+        let sent = SystemTime::now();
+
+        let receive = async {
+            match transport {
+                Transport::Udp => {
+                    let socket = Self::socket(self.address).await?;
+                    let local = socket.local_addr()?;
+
+                    self.tap(MessageType::ForwarderQuery, transport, local, sent, data);
+                    socket.send(data).await?;
+
+                    let mut buf = vec![0; payload_size(query)];
+                    loop {
+                        let count = socket.recv(&mut buf).await?;
+
+                        if answers(query, &buf[..count]) {
+                            buf.truncate(count);
+                            return Ok::<_, Report>((local, buf));
+                        }
+
+                        debug!("Ignoring a response from {} to another query", self.name);
+                    }
+                }
+                Transport::Tcp => {
+                    let mut stream = TcpStream::connect(self.address).await?;
+                    let local = stream.local_addr()?;
+
+                    self.tap(MessageType::ForwarderQuery, transport, local, sent, data);
+                    stream.write_all(&(data.len() as u16).to_be_bytes()).await?;
+                    stream.write_all(data).await?;
+
+                    loop {
+                        let mut buf = vec![0; stream.read_u16().await? as usize];
+                        stream.read_exact(&mut buf).await?;
+
+                        if answers(query, &buf) {
+                            return Ok((local, buf));
+                        }
+
+                        debug!("Ignoring a response from {} to another query", self.name);
+                    }
+                }
+            }
+        };
+
+        let (local, response) = timeout(self.timeout, receive)
+            .await
+            .map_err(|_| eyre!("No response from {} after {:?}", self.name, self.timeout))??;
+
+        self.tap(
+            MessageType::ForwarderResponse,
+            transport,
+            local,
+            sent,
+            &response,
+        );
+
+        Ok(response)
+    }
+}
+
+/// The header and question of `response` alone, with TC set.
+fn truncate(response: &[u8]) -> Option<Vec<u8>> {
+    let question = MessageView::parse(response).ok()?.question_bytes().len();
+
+    let mut truncated = response[..HEADER_SIZE + question].to_vec();
+    truncated[2] |= 0x02;
+    truncated[6..HEADER_SIZE].fill(0);
+
+    Some(truncated)
+}
+
+/// The largest UDP response the sender of `query` accepts.
+fn payload_size(query: &MessageView) -> usize {
+    let edns = query.edns().map_or(0, |edns| edns.udp_payload_size);
+    usize::from(edns).max(MAX_MESSAGE_SIZE)
+}
+
+/// Whether `response` is a response to `query`, with the same id and question.
+fn answers(query: &MessageView, response: &[u8]) -> bool {
+    let Ok(response) = MessageView::parse(response) else {
+        return false;
+    };
+
+    response.header().qr()
+        && response.header().id == query.header().id
+        && response
+            .question_bytes()
+            .eq_ignore_ascii_case(query.question_bytes())
+}
+
+async fn forward(
+    forwarder: &Forwarder,
+    data: &[u8],
+    transport: Transport,
+) -> Result<Vec<u8>, Report> {
+    let data = forwarder.forward(data, transport).await?;
+    trace!("Data received from upstream: {data:?}");
+
+    MessageView::parse(&data)?;
+    Ok(data)
+}
+
+fn in_zone(name: &Name, zone: &Name) -> bool {
+    let (name, zone) = (name.labels(), zone.labels());
+
+    name.len() >= zone.len()
+        && name[name.len() - zone.len()..]
+            .iter()
+            .zip(zone)
+            .all(|(a, b)| a.as_bytes().eq_ignore_ascii_case(b.as_bytes()))
+}
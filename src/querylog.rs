@@ -0,0 +1,373 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, ErrorKind, Write},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+#[cfg(unix)]
+use {std::os::unix::net::UnixStream, tracing::warn};
+
+use color_eyre::{eyre::WrapErr, Report};
+use serde::Serialize;
+use tokio::sync::mpsc;
+use tracing::{debug, error};
+
+use crate::{
+    client::{rcode_name, type_name, RDataView, Transport},
+    config::QueryLogConfig,
+    data::QType,
+    view::MessageView,
+};
+
+/// Number of entries waiting to be written before we start dropping them.
+const BUFFER_SIZE: usize = 4096;
+
+/// Where the answer to a query came from.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Source {
+    Local,
+    Cache,
+    Upstream,
+    /// Refused, as the name is neither ours nor handled by any upstream.
+    Blocked,
+    /// Made up by a `$FAULT` rule of the db file.
+    Fault,
+}
+
+/// A line of the query log.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Entry {
+    pub timestamp: String,
+    pub client: SocketAddr,
+    pub transport: Transport,
+    pub id: u16,
+    pub name: String,
+    #[serde(rename = "type")]
+    pub qtype: String,
+    pub source: Source,
+    pub rcode: String,
+    pub answers: Vec<Answer>,
+    pub latency_ms: f64,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Answer {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub rtype: String,
+    pub ttl: u32,
+    pub data: String,
+}
+
+impl Entry {
+    /// Describe the `response` sent to `client` for `query`, received at `start`.
+    pub fn new(
+        start: SystemTime,
+        client: SocketAddr,
+        transport: Transport,
+        query: &MessageView<'_>,
+        response: &[u8],
+        source: Source,
+    ) -> Self {
+        let question = query.questions().next();
+        let response = MessageView::parse(response).ok();
+
+        let answers = response
+            .iter()
+            .flat_map(|response| {
+                response
+                    .answers()
+                    .filter(|record| record.rtype != QType::OPT as u16)
+                    .map(|record| Answer {
+                        name: record.name.to_string(),
+                        rtype: type_name(record.rtype),
+                        ttl: record.ttl,
+                        data: RDataView(response, &record).to_string(),
+                    })
+            })
+            .collect();
+
+        Self {
+            timestamp: rfc3339(start),
+            client,
+            transport,
+            id: query.header().id,
+            name: question.map_or(String::new(), |q| q.qname.to_string()),
+            qtype: question.map_or(String::new(), |q| type_name(q.qtype)),
+            source,
+            rcode: rcode_name(response.map_or(0, |r| r.header().rcode())),
+            answers,
+            latency_ms: start.elapsed().unwrap_or_default().as_secs_f64() * 1000.0,
+        }
+    }
+}
+
+/// Handle to the query log, whose entries are written out by a background thread.
+#[derive(Debug)]
+pub struct QueryLog {
+    entries: mpsc::Sender<Entry>,
+}
+
+impl QueryLog {
+    pub fn open(config: &QueryLogConfig) -> Result<Self, Report> {
+        let mut sink = Sink::open(config)?;
+        let (entries, mut receiver) = mpsc::channel::<Entry>(BUFFER_SIZE);
+
+        std::thread::Builder::new()
+            .name("query-log".to_string())
+            .spawn(move || {
+                while let Some(entry) = receiver.blocking_recv() {
+                    let mut line = serde_json::to_vec(&entry).expect("entry is valid JSON");
+                    line.push(b'\n');
+
+                    if let Err(err) = sink.write(&line) {
+                        error!("Failed to write query log: {err}");
+                    }
+                }
+            })?;
+
+        Ok(Self { entries })
+    }
+
+    /// Queue an entry, dropping it if the writer cannot keep up.
+    pub fn log(&self, entry: Entry) {
+        if self.entries.try_send(entry).is_err() {
+            debug!("Query log is full, dropping entry");
+        }
+    }
+}
+
+enum Sink {
+    Stdout,
+    File(RotatingFile),
+    #[cfg(unix)]
+    Socket {
+        path: PathBuf,
+        stream: Option<UnixStream>,
+    },
+}
+
+impl Sink {
+    fn open(config: &QueryLogConfig) -> Result<Self, Report> {
+        match config {
+            QueryLogConfig::Stdout => Ok(Self::Stdout),
+            QueryLogConfig::File {
+                path,
+                max_size,
+                max_files,
+            } => {
+                let file = RotatingFile::open(path, *max_size, *max_files)
+                    .wrap_err_with(|| format!("Failed to open {}", path.display()))?;
+
+                Ok(Self::File(file))
+            }
+            // Do not fail if nobody listens yet, we try again on every entry.
+            #[cfg(unix)]
+            QueryLogConfig::Socket { path } => Ok(Self::Socket {
+                path: path.clone(),
+                stream: UnixStream::connect(path)
+                    .map_err(|err| warn!("Cannot connect to {}: {err}", path.display()))
+                    .ok(),
+            }),
+            #[cfg(not(unix))]
+            QueryLogConfig::Socket { .. } => Err(color_eyre::eyre::eyre!(
+                "Logging queries to a socket is only supported on Unix"
+            )),
+        }
+    }
+
+    fn write(&mut self, line: &[u8]) -> io::Result<()> {
+        match self {
+            Self::Stdout => io::stdout().lock().write_all(line),
+            Self::File(file) => file.write(line),
+            #[cfg(unix)]
+            Self::Socket { path, stream } => {
+                if stream.is_none() {
+                    *stream = Some(UnixStream::connect(path)?);
+                }
+
+                let result = stream.as_mut().unwrap().write_all(line);
+
+                if result.is_err() {
+                    *stream = None;
+                }
+
+                result
+            }
+        }
+    }
+}
+
+struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_size: u64,
+    max_files: usize,
+}
+
+impl RotatingFile {
+    fn open(path: &Path, max_size: u64, max_files: usize) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            file,
+            size,
+            max_size,
+            max_files,
+        })
+    }
+
+    fn write(&mut self, line: &[u8]) -> io::Result<()> {
+        if self.size > 0 && self.size + line.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+
+        self.file.write_all(line)?;
+        self.size += line.len() as u64;
+
+        Ok(())
+    }
+
+    /// Shift `path.1` to `path.2` and so on, dropping the oldest, then start afresh.
+    fn rotate(&mut self) -> io::Result<()> {
+        let rotated = |i: usize| {
+            let mut path = self.path.clone().into_os_string();
+            path.push(format!(".{i}"));
+            PathBuf::from(path)
+        };
+
+        let mut renames = (1..self.max_files)
+            .rev()
+            .map(|i| (rotated(i), rotated(i + 1)))
+            .collect::<Vec<_>>();
+
+        if self.max_files > 0 {
+            renames.push((self.path.clone(), rotated(1)));
+        }
+
+        for (from, to) in renames {
+            match fs::rename(from, to) {
+                Err(err) if err.kind() != ErrorKind::NotFound => return Err(err),
+                _ => (),
+            }
+        }
+
+        self.file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(&self.path)?;
+
+        self.size = 0;
+
+        Ok(())
+    }
+}
+
+/// Format a time as eg. `2023-04-01T12:34:56.789Z`.
+fn rfc3339(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or(Duration::ZERO);
+    let secs = since_epoch.as_secs();
+    let (days, secs) = (secs / 86400, secs % 86400);
+
+    // Civil date from days since the epoch, after Howard Hinnant's algorithm.
+    let z = days as i64 + 719468;
+    let era = z / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + (month <= 2) as i64;
+
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}.{:03}Z",
+        secs / 3600,
+        secs % 3600 / 60,
+        secs % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const QUERY: &[u8] = &[
+        13, 208, 1, 0, 0, 1, 0, 0, 0, 0, 0, 0, 4, 110, 101, 119, 115, 11, 121, 99, 111, 109, 98,
+        105, 110, 97, 116, 111, 114, 3, 99, 111, 109, 0, 0, 1, 0, 1,
+    ];
+
+    const RESPONSE: &[u8] = &[
+        13, 208, 129, 128, 0, 1, 0, 1, 0, 0, 0, 0, 4, 110, 101, 119, 115, 11, 121, 99, 111, 109,
+        98, 105, 110, 97, 116, 111, 114, 3, 99, 111, 109, 0, 0, 1, 0, 1, 192, 12, 0, 1, 0, 1, 0, 0,
+        0, 60, 0, 4, 209, 216, 230, 240,
+    ];
+
+    #[test]
+    fn entry() {
+        let start = UNIX_EPOCH + Duration::from_millis(1_680_352_496_789);
+        let query = MessageView::parse(QUERY).unwrap();
+
+        let entry = Entry::new(
+            start,
+            "127.0.0.1:5353".parse().unwrap(),
+            Transport::Udp,
+            &query,
+            RESPONSE,
+            Source::Upstream,
+        );
+
+        let mut json = serde_json::to_value(&entry).unwrap();
+        json.as_object_mut().unwrap().remove("latency_ms");
+
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "timestamp": "2023-04-01T12:34:56.789Z",
+                "client": "127.0.0.1:5353",
+                "transport": "udp",
+                "id": 3536,
+                "name": "news.ycombinator.com",
+                "type": "A",
+                "source": "upstream",
+                "rcode": "NOERROR",
+                "answers": [{
+                    "name": "news.ycombinator.com",
+                    "type": "A",
+                    "ttl": 60,
+                    "data": "209.216.230.240"
+                }]
+            })
+        );
+    }
+
+    #[test]
+    fn rotation() {
+        let dir = std::env::temp_dir().join(format!("denis-querylog-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let path = dir.join("queries.jsonl");
+        let mut file = RotatingFile::open(&path, 10, 2).unwrap();
+
+        for line in ["aaaaaa\n", "bbbbbb\n", "cccccc\n", "dddddd\n"] {
+            file.write(line.as_bytes()).unwrap();
+        }
+
+        let read = |name: &str| fs::read_to_string(dir.join(name)).unwrap();
+
+        assert_eq!(read("queries.jsonl"), "dddddd\n");
+        assert_eq!(read("queries.jsonl.1"), "cccccc\n");
+        assert_eq!(read("queries.jsonl.2"), "bbbbbb\n");
+        assert!(!dir.join("queries.jsonl.3").exists());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,23 +1,102 @@
 use core::fmt;
-use std::collections::HashMap;
+use std::{collections::BTreeMap, ops::Range};
 
-use color_eyre::owo_colors::OwoColorize;
+use bytes::Bytes;
+use color_eyre::{eyre::eyre, owo_colors::OwoColorize, Report};
 
-use crate::data::{Name, QClass, QType};
+use crate::{
+    data::{Name, QClass, QType},
+    svcb::SvcParams,
+    view::NameView,
+};
+
+/// Maximum length of a single character-string within a record.
+pub const MAX_STRING_LENGTH: usize = 255;
 
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub enum Record {
-    A { address: [u8; 4] },
-    CNAME { name: Name },
-    TXT { text: String },
+    A {
+        address: [u8; 4],
+    },
+    NS {
+        name: Name,
+    },
+    CNAME {
+        name: Name,
+    },
+    SOA {
+        mname: Name,
+        rname: Name,
+        serial: u32,
+        refresh: u32,
+        retry: u32,
+        expire: u32,
+        minimum: u32,
+    },
+    PTR {
+        name: Name,
+    },
+    MX {
+        preference: u16,
+        exchange: Name,
+    },
+    TXT {
+        strings: Vec<Bytes>,
+    },
+    SRV {
+        priority: u16,
+        weight: u16,
+        port: u16,
+        target: Name,
+    },
+    SVCB {
+        priority: u16,
+        target: Name,
+        params: SvcParams,
+    },
+    HTTPS {
+        priority: u16,
+        target: Name,
+        params: SvcParams,
+    },
+    CAA {
+        flags: u8,
+        tag: String,
+        value: String,
+    },
 }
 
 impl Record {
+    /// The record data alone, to display as in zone files.
+    pub fn rdata(&self) -> RData<'_> {
+        RData(self)
+    }
+
+    /// Build a TXT record, splitting the text into as many character-strings
+    /// as needed.
+    pub fn txt(text: impl AsRef<[u8]>) -> Self {
+        let strings = text
+            .as_ref()
+            .chunks(MAX_STRING_LENGTH)
+            .map(Bytes::copy_from_slice)
+            .collect();
+
+        Record::TXT { strings }
+    }
+
     pub fn qtype(&self) -> QType {
         match self {
             Record::A { .. } => QType::A,
+            Record::NS { .. } => QType::NS,
             Record::CNAME { .. } => QType::CNAME,
+            Record::SOA { .. } => QType::SOA,
+            Record::PTR { .. } => QType::PTR,
+            Record::MX { .. } => QType::MX,
             Record::TXT { .. } => QType::TXT,
+            Record::SRV { .. } => QType::SRV,
+            Record::SVCB { .. } => QType::SVCB,
+            Record::HTTPS { .. } => QType::HTTPS,
+            Record::CAA { .. } => QType::CAA,
         }
     }
 
@@ -28,28 +107,351 @@ impl Record {
     pub fn to_bytes(&self) -> Vec<u8> {
         match self {
             Record::A { address } => address.to_vec(),
-            Record::CNAME { name } => name.to_bytes(),
-            Record::TXT { text } => {
-                let mut bytes = vec![text.len() as u8];
-                bytes.extend(text.as_bytes());
+            Record::NS { name } | Record::CNAME { name } | Record::PTR { name } => name.to_bytes(),
+            Record::SOA {
+                mname,
+                rname,
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum,
+            } => {
+                let mut bytes = mname.to_bytes();
+                bytes.extend(rname.to_bytes());
+
+                for value in [serial, refresh, retry, expire, minimum] {
+                    bytes.extend(value.to_be_bytes());
+                }
+
+                bytes
+            }
+            Record::MX {
+                preference,
+                exchange,
+            } => {
+                let mut bytes = preference.to_be_bytes().to_vec();
+                bytes.extend(exchange.to_bytes());
+                bytes
+            }
+            Record::TXT { strings } => {
+                let mut bytes = Vec::new();
+
+                for string in strings {
+                    if string.is_empty() {
+                        bytes.push(0);
+                    }
+
+                    for chunk in string.chunks(MAX_STRING_LENGTH) {
+                        bytes.push(chunk.len() as u8);
+                        bytes.extend(chunk);
+                    }
+                }
+
+                if bytes.is_empty() {
+                    bytes.push(0);
+                }
+
+                bytes
+            }
+            Record::SRV {
+                priority,
+                weight,
+                port,
+                target,
+            } => {
+                let mut bytes = Vec::new();
+
+                for value in [priority, weight, port] {
+                    bytes.extend(value.to_be_bytes());
+                }
+
+                bytes.extend(target.to_bytes());
+                bytes
+            }
+            Record::SVCB {
+                priority,
+                target,
+                params,
+            }
+            | Record::HTTPS {
+                priority,
+                target,
+                params,
+            } => {
+                let mut bytes = priority.to_be_bytes().to_vec();
+                bytes.extend(target.to_bytes());
+                params.encode(&mut bytes);
+                bytes
+            }
+            Record::CAA { flags, tag, value } => {
+                let mut bytes = vec![*flags, tag.len() as u8];
+                bytes.extend(tag.as_bytes());
+                bytes.extend(value.as_bytes());
                 bytes
             }
         }
     }
+
+    /// Decode the data of a record found at `rdata` within `message`.
+    ///
+    /// The whole message is needed to follow compression pointers in
+    /// embedded names.
+    pub fn from_rdata(qtype: QType, message: &[u8], rdata: Range<usize>) -> Result<Self, Report> {
+        if rdata.end > message.len() {
+            return Err(eyre!("Record data out of bounds"));
+        }
+
+        let mut r = RDataReader {
+            qtype,
+            message,
+            pos: rdata.start,
+            end: rdata.end,
+        };
+
+        let record = match qtype {
+            QType::A => Record::A {
+                address: r.array()?,
+            },
+            QType::NS => Record::NS { name: r.name()? },
+            QType::CNAME => Record::CNAME { name: r.name()? },
+            QType::SOA => Record::SOA {
+                mname: r.name()?,
+                rname: r.name()?,
+                serial: r.u32()?,
+                refresh: r.u32()?,
+                retry: r.u32()?,
+                expire: r.u32()?,
+                minimum: r.u32()?,
+            },
+            QType::PTR => Record::PTR { name: r.name()? },
+            QType::MX => Record::MX {
+                preference: r.u16()?,
+                exchange: r.name()?,
+            },
+            QType::TXT => {
+                let mut strings = Vec::new();
+
+                while !r.is_empty() {
+                    let len = r.u8()?;
+                    strings.push(Bytes::copy_from_slice(r.bytes(len as usize)?));
+                }
+
+                Record::TXT { strings }
+            }
+            QType::SRV => Record::SRV {
+                priority: r.u16()?,
+                weight: r.u16()?,
+                port: r.u16()?,
+                target: r.name()?,
+            },
+            QType::SVCB => Record::SVCB {
+                priority: r.u16()?,
+                target: r.name()?,
+                params: SvcParams::decode(r.rest())?,
+            },
+            QType::HTTPS => Record::HTTPS {
+                priority: r.u16()?,
+                target: r.name()?,
+                params: SvcParams::decode(r.rest())?,
+            },
+            QType::CAA => {
+                let flags = r.u8()?;
+                let len = r.u8()?;
+                let tag = String::from_utf8_lossy(r.bytes(len as usize)?).into_owned();
+                let value = String::from_utf8_lossy(r.rest()).into_owned();
+
+                Record::CAA { flags, tag, value }
+            }
+            other => return Err(eyre!("unsupported record type: {}", other)),
+        };
+
+        if !r.is_empty() {
+            return Err(eyre!("Trailing data in {} record", qtype));
+        }
+
+        Ok(record)
+    }
+}
+
+struct RDataReader<'a> {
+    qtype: QType,
+    message: &'a [u8],
+    pos: usize,
+    end: usize,
+}
+
+impl<'a> RDataReader<'a> {
+    fn is_empty(&self) -> bool {
+        self.pos >= self.end
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], Report> {
+        if self.pos + len > self.end {
+            return Err(eyre!("Truncated {} record", self.qtype));
+        }
+
+        let bytes = &self.message[self.pos..self.pos + len];
+        self.pos += len;
+
+        Ok(bytes)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], Report> {
+        Ok(self.bytes(N)?.try_into().unwrap())
+    }
+
+    fn u8(&mut self) -> Result<u8, Report> {
+        Ok(u8::from_be_bytes(self.array()?))
+    }
+
+    fn u16(&mut self) -> Result<u16, Report> {
+        Ok(u16::from_be_bytes(self.array()?))
+    }
+
+    fn u32(&mut self) -> Result<u32, Report> {
+        Ok(u32::from_be_bytes(self.array()?))
+    }
+
+    fn name(&mut self) -> Result<Name, Report> {
+        let (name, end) = NameView::parse(self.message, self.pos)?;
+
+        if end > self.end {
+            return Err(eyre!("Truncated {} record", self.qtype));
+        }
+
+        self.pos = end;
+        Ok(name.to_name())
+    }
+
+    fn rest(&mut self) -> &'a [u8] {
+        let rest = &self.message[self.pos..self.end];
+        self.pos = self.end;
+        rest
+    }
+}
+
+/// Presentation format of a list of character-strings, quoted and escaped.
+pub(crate) struct CharacterStrings<'a, S>(pub(crate) &'a [S]);
+
+impl<S: AsRef<[u8]>> fmt::Display for CharacterStrings<'_, S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, string) in self.0.iter().enumerate() {
+            if i > 0 {
+                write!(f, " ")?;
+            }
+
+            write!(f, "\"")?;
+
+            for &byte in string.as_ref() {
+                match byte {
+                    b'"' | b'\\' => write!(f, "\\{}", byte as char)?,
+                    0x20..=0x7E => write!(f, "{}", byte as char)?,
+                    _ => write!(f, "\\{byte:03}")?,
+                }
+            }
+
+            write!(f, "\"")?;
+        }
+
+        Ok(())
+    }
 }
 
-fn fmt_normal(r: &Record, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+/// A name within record data, fully qualified in zone files.
+struct RDataName<'a>(&'a Name, bool);
+
+impl fmt::Display for RDataName<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.1 {
+            write!(f, "{}", self.0.to_fqdn())
+        } else {
+            write!(f, "{}", self.0)
+        }
+    }
+}
+
+/// Presentation format of the record data alone, with fully qualified names,
+/// as found in zone files.
+pub struct RData<'a>(&'a Record);
+
+impl fmt::Display for RData<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt_rdata(self.0, true, f)
+    }
+}
+
+fn fmt_rdata(r: &Record, fqdn: bool, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let name = |name| RDataName(name, fqdn);
+
     match r {
         Record::A { address } => write!(
             f,
-            "A {}.{}.{}.{}",
+            "{}.{}.{}.{}",
             address[0], address[1], address[2], address[3]
         ),
-        Record::CNAME { name } => write!(f, "CNAME {}", name),
-        Record::TXT { text } => write!(f, "TXT {}", text),
+        Record::NS { name: ns } => write!(f, "{}", name(ns)),
+        Record::CNAME { name: cname } => write!(f, "{}", name(cname)),
+        Record::SOA {
+            mname,
+            rname,
+            serial,
+            refresh,
+            retry,
+            expire,
+            minimum,
+        } => write!(
+            f,
+            "{} {} {} {} {} {} {}",
+            name(mname),
+            name(rname),
+            serial,
+            refresh,
+            retry,
+            expire,
+            minimum
+        ),
+        Record::PTR { name: ptr } => write!(f, "{}", name(ptr)),
+        Record::MX {
+            preference,
+            exchange,
+        } => write!(f, "{} {}", preference, name(exchange)),
+        Record::TXT { strings } => write!(f, "{}", CharacterStrings(strings)),
+        Record::SRV {
+            priority,
+            weight,
+            port,
+            target,
+        } => write!(f, "{} {} {} {}", priority, weight, port, name(target)),
+        Record::SVCB {
+            priority,
+            target,
+            params,
+        }
+        | Record::HTTPS {
+            priority,
+            target,
+            params,
+        } => {
+            write!(f, "{} {}", priority, name(target))?;
+
+            if !params.is_empty() {
+                write!(f, " {}", params)?;
+            }
+
+            Ok(())
+        }
+        Record::CAA { flags, tag, value } => {
+            write!(f, "{} {} {}", flags, tag, CharacterStrings(&[value]))
+        }
     }
 }
 
+fn fmt_normal(r: &Record, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{} ", r.qtype())?;
+    fmt_rdata(r, false, f)
+}
+
 fn fmt_pretty(r: &Record, f: &mut fmt::Formatter<'_>) -> fmt::Result {
     match r {
         Record::A { address } => write!(
@@ -62,8 +464,85 @@ fn fmt_pretty(r: &Record, f: &mut fmt::Formatter<'_>) -> fmt::Result {
             )
             .yellow()
         ),
+        Record::NS { name } => write!(f, "{:<8} {}", "NS".green().bold(), name),
         Record::CNAME { name } => write!(f, "{:<8} {}", "CNAME".green().bold(), name),
-        Record::TXT { text } => write!(f, "{:<8} {}", "TXT".green().bold(), text.italic()),
+        Record::SOA {
+            mname,
+            rname,
+            serial,
+            refresh,
+            retry,
+            expire,
+            minimum,
+        } => write!(
+            f,
+            "{:<8} {} {} {}",
+            "SOA".green().bold(),
+            mname,
+            rname,
+            format!("{serial} {refresh} {retry} {expire} {minimum}").yellow()
+        ),
+        Record::PTR { name } => write!(f, "{:<8} {}", "PTR".green().bold(), name),
+        Record::MX {
+            preference,
+            exchange,
+        } => write!(
+            f,
+            "{:<8} {} {}",
+            "MX".green().bold(),
+            preference.yellow(),
+            exchange
+        ),
+        Record::TXT { strings } => write!(
+            f,
+            "{:<8} {}",
+            "TXT".green().bold(),
+            CharacterStrings(strings).italic()
+        ),
+        Record::SRV {
+            priority,
+            weight,
+            port,
+            target,
+        } => write!(
+            f,
+            "{:<8} {} {}",
+            "SRV".green().bold(),
+            format!("{priority} {weight} {port}").yellow(),
+            target
+        ),
+        Record::SVCB {
+            priority,
+            target,
+            params,
+        } => write!(
+            f,
+            "{:<8} {} {} {}",
+            "SVCB".green().bold(),
+            priority.yellow(),
+            target,
+            params.italic()
+        ),
+        Record::HTTPS {
+            priority,
+            target,
+            params,
+        } => write!(
+            f,
+            "{:<8} {} {} {}",
+            "HTTPS".green().bold(),
+            priority.yellow(),
+            target,
+            params.italic()
+        ),
+        Record::CAA { flags, tag, value } => write!(
+            f,
+            "{:<8} {} {} {}",
+            "CAA".green().bold(),
+            flags.yellow(),
+            tag,
+            CharacterStrings(&[value]).italic()
+        ),
     }
 }
 
@@ -77,9 +556,10 @@ impl fmt::Display for Record {
     }
 }
 
-#[derive(Clone, Debug, Default)]
+/// The records attached to a single name, grouped by type.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
 pub struct RecordMap {
-    records: HashMap<QType, Record>,
+    records: BTreeMap<QType, Vec<Record>>,
 }
 
 impl RecordMap {
@@ -88,23 +568,29 @@ impl RecordMap {
     }
 
     pub fn insert(&mut self, record: Record) {
-        self.records.insert(record.qtype(), record);
+        self.records.entry(record.qtype()).or_default().push(record);
     }
 
     pub fn get(&self, qtype: QType) -> Option<&Record> {
-        self.records.get(&qtype)
+        self.get_all(qtype).first()
+    }
+
+    pub fn get_all(&self, qtype: QType) -> &[Record] {
+        self.records.get(&qtype).map_or(&[], Vec::as_slice)
     }
 
     // pub fn get_mut(&mut self, qtype: QType) -> Option<&mut Record> {
     //     self.records.get_mut(&qtype)
     // }
 
-    pub fn remove(&mut self, qtype: QType) -> Option<Record> {
-        self.records.remove(&qtype)
+    pub fn remove(&mut self, qtype: QType) -> Vec<Record> {
+        self.records.remove(&qtype).unwrap_or_default()
     }
 
     pub fn iter(&self) -> impl Iterator<Item = (&QType, &Record)> {
-        self.records.iter()
+        self.records
+            .iter()
+            .flat_map(|(qtype, records)| records.iter().map(move |record| (qtype, record)))
     }
 
     // pub fn iter_mut(&mut self) -> impl Iterator<Item = (&QType, &mut Record)> {
@@ -115,3 +601,132 @@ impl RecordMap {
         self.records.is_empty()
     }
 }
+
+impl fmt::Display for RecordMap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, (_, record)) in self.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+
+            if f.alternate() {
+                write!(f, "{record:#}")?;
+            } else {
+                write!(f, "{record}")?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+
+    #[test]
+    fn https_roundtrip() {
+        let record = Record::HTTPS {
+            priority: 1,
+            target: Name::from_str("proxy.local.dev").unwrap(),
+            params: SvcParams::parse(["alpn=h3", "port=8443"]).unwrap(),
+        };
+
+        let data = record.to_bytes();
+        let decoded = Record::from_rdata(QType::HTTPS, &data, 0..data.len()).unwrap();
+
+        assert_eq!(decoded, record);
+    }
+
+    #[test]
+    fn embedded_names_roundtrip() {
+        let records = [
+            Record::MX {
+                preference: 10,
+                exchange: Name::from_str("mail.local.dev").unwrap(),
+            },
+            Record::SRV {
+                priority: 10,
+                weight: 5,
+                port: 5060,
+                target: Name::from_str("sip.local.dev").unwrap(),
+            },
+            Record::SOA {
+                mname: Name::from_str("ns1.local.dev").unwrap(),
+                rname: Name::from_str("hostmaster.local.dev").unwrap(),
+                serial: 2023041201,
+                refresh: 7200,
+                retry: 3600,
+                expire: 1209600,
+                minimum: 300,
+            },
+            Record::CAA {
+                flags: 0,
+                tag: "issue".to_string(),
+                value: "letsencrypt.org".to_string(),
+            },
+        ];
+
+        for record in records {
+            let data = record.to_bytes();
+            let decoded = Record::from_rdata(record.qtype(), &data, 0..data.len()).unwrap();
+
+            assert_eq!(decoded, record);
+        }
+    }
+
+    #[test]
+    fn long_txt() {
+        let record = Record::txt("a".repeat(300));
+
+        let Record::TXT { strings } = &record else {
+            unreachable!()
+        };
+
+        assert_eq!(strings.len(), 2);
+        assert_eq!(strings[0].len(), 255);
+        assert_eq!(strings[1].len(), 45);
+
+        let data = record.to_bytes();
+        assert_eq!(data.len(), 302);
+        assert_eq!(data[0], 255);
+        assert_eq!(data[256], 45);
+
+        let decoded = Record::from_rdata(QType::TXT, &data, 0..data.len()).unwrap();
+        assert_eq!(decoded, record);
+    }
+
+    #[test]
+    fn txt_presentation() {
+        let record = Record::TXT {
+            strings: vec![
+                Bytes::from_static(b"v=spf1 -all"),
+                Bytes::from_static(b"say \"hi\"\n"),
+            ],
+        };
+
+        assert_eq!(record.to_string(), r#"TXT "v=spf1 -all" "say \"hi\"\010""#);
+    }
+
+    #[test]
+    fn compressed_name() {
+        // news.ycombinator.com, followed by an MX record pointing back to it
+        let mut message = vec![
+            4, 110, 101, 119, 115, 11, 121, 99, 111, 109, 98, 105, 110, 97, 116, 111, 114, 3, 99,
+            111, 109, 0,
+        ];
+        message.extend([0, 10, 4, 109, 97, 105, 108, 192, 5]);
+
+        let record = Record::from_rdata(QType::MX, &message, 22..message.len()).unwrap();
+
+        assert_eq!(
+            record,
+            Record::MX {
+                preference: 10,
+                exchange: Name::from_str("mail.ycombinator.com").unwrap(),
+            }
+        );
+    }
+}
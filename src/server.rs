@@ -1,205 +1,1387 @@
-use std::{net::SocketAddr, path::Path, sync::Arc, time::Instant};
+use std::{
+    io::ErrorKind,
+    net::SocketAddr,
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use color_eyre::{eyre::eyre, owo_colors::OwoColorize, Report};
+use socket2::{Domain, Socket, Type};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream, UdpSocket},
+    sync::{mpsc, watch},
+    task::{JoinHandle, JoinSet},
+    time::timeout,
+};
+use tracing::{debug, error, info, trace, warn};
 
-use color_eyre::{owo_colors::OwoColorize, Report};
-use deku::{DekuContainerRead, DekuContainerWrite};
-use tokio::net::UdpSocket;
-use tracing::{debug, error, info, trace};
+#[cfg(unix)]
+use tokio::signal::unix::{signal, SignalKind};
 
-const MAX_MESSAGE_SIZE: usize = 512;
+/// The largest UDP message without EDNS (RFC 1035).
+pub(crate) const MAX_MESSAGE_SIZE: usize = 512;
+const TCP_IDLE_TIMEOUT: Duration = Duration::from_secs(10);
 
 use crate::{
-    data::{Flags, Header, Message, Question, ResourceRecord},
-    db::Db,
+    client::Transport,
+    config::{Config, Plugin, Upstream},
+    data::{Name, Opcode, QType, RCode},
+    db::{self, Db},
+    dnstap::{Dnstap, Event as DnstapEvent, MessageType},
+    fixtures::Replayer,
+    handler::{error_response, Chain, Handler, Request, Response},
+    metrics::{self, Metrics},
+    plugins,
+    querylog::{Entry, QueryLog, Source},
+    record::Record,
+    store::Store,
+    view::{HeaderView, MessageView},
 };
 
-#[derive(Clone, Debug)]
-struct Forwarder {
-    socket: Arc<UdpSocket>,
+/// Everything needed to answer a request, shared by all listeners.
+#[derive(Debug)]
+struct Context {
+    chain: Chain,
+    metrics: Arc<Metrics>,
+    query_log: Option<QueryLog>,
+    dnstap: Option<Dnstap>,
 }
 
-impl Forwarder {
-    pub async fn connect(addr: SocketAddr) -> Result<Self, Report> {
-        let socket = Arc::new(UdpSocket::bind("0.0.0.0:0").await?);
-        socket.connect(addr).await?;
+impl Context {
+    /// Set up the plugins of `config`, after the given `handlers`.
+    async fn new(
+        config: &Config,
+        store: Arc<Store>,
+        metrics: Arc<Metrics>,
+        mut handlers: Vec<Box<dyn Handler>>,
+    ) -> Result<Self, Report> {
+        let dnstap = config.dnstap.as_ref().map(Dnstap::open).transpose()?;
 
-        info!(
-            "Connected to upstream at {}",
-            addr.to_string().cyan().underline(),
-        );
+        for plugin in config.chain() {
+            let handler: Box<dyn Handler> = match plugin {
+                Plugin::Faults => Box::new(plugins::Faults::new(store.clone())),
+                Plugin::Local => {
+                    let zones = config
+                        .zones
+                        .iter()
+                        .map(|zone| Name::from_str(zone))
+                        .collect::<Result<_, _>>()?;
+
+                    Box::new(plugins::Local::new(store.clone(), zones, metrics.clone()))
+                }
+                Plugin::Cache => {
+                    Box::new(plugins::Cache::new(config.cache.clone(), metrics.clone()))
+                }
+                Plugin::Replay => {
+                    let Some(path) = &config.replay else {
+                        return Err(eyre!("The replay plugin requires a fixtures file"));
+                    };
+
+                    let replayer = Replayer::load(path)?;
+                    info!(
+                        "Replaying {} recorded response(s) from {}",
+                        replayer.len(),
+                        path.display()
+                    );
 
-        Ok(Self { socket })
+                    Box::new(plugins::Replay::new(replayer, metrics.clone()))
+                }
+                Plugin::Forward => Box::new(
+                    plugins::Forward::connect(config, metrics.clone(), dnstap.clone()).await?,
+                ),
+            };
+
+            handlers.push(handler);
+        }
+
+        Ok(Self {
+            chain: Chain::new(handlers),
+            metrics,
+            query_log: config.query_log.as_ref().map(QueryLog::open).transpose()?,
+            dnstap,
+        })
     }
+}
 
-    pub async fn forward(&self, data: &[u8]) -> Result<Vec<u8>, Report> {
-        self.socket.send(data).await?;
+/// Serve the db files of `config` until interrupted, or until a listener fails.
+pub async fn run(config: &Config) -> Result<(), Report> {
+    let mut handle = Server::with_config(config.clone())?.start().await?;
 
-        let mut buf = [0; MAX_MESSAGE_SIZE];
-        let count = self.socket.recv(&mut buf).await?;
+    tokio::select! {
+        result = &mut handle.task => return result?,
+        _ = tokio::signal::ctrl_c() => info!("Interrupted, shutting down"),
+        result = terminated() => {
+            result?;
+            info!("Terminated, shutting down");
+        }
+    }
+
+    handle.shutdown().await
+}
+
+/// Complete once we receive SIGTERM.
+#[cfg(unix)]
+async fn terminated() -> Result<(), Report> {
+    signal(SignalKind::terminate())?.recv().await;
+    Ok(())
+}
+
+/// Never completes, as there is no SIGTERM to receive.
+#[cfg(not(unix))]
+async fn terminated() -> Result<(), Report> {
+    std::future::pending().await
+}
+
+/// A DNS server running in-process, eg. for integration tests:
+///
+/// ```no_run
+/// # async fn example() -> Result<(), color_eyre::Report> {
+/// use denis::server::Server;
+///
+/// let server = Server::from_zone("api.local.dev A 127.0.0.1")?.start().await?;
+/// let addr = server.local_addr().unwrap();
+/// // ... query `addr` ...
+/// server.shutdown().await
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct Server {
+    config: Config,
+    db: Db,
+    handlers: Vec<Box<dyn Handler>>,
+}
+
+impl Server {
+    /// Serve `db` on an ephemeral port of 127.0.0.1, without any upstream.
+    pub fn new(db: Db) -> Self {
+        let config = Config {
+            listen: vec![SocketAddr::from(([127, 0, 0, 1], 0))],
+            ..Config::default()
+        };
+
+        Self {
+            config,
+            db,
+            handlers: vec![],
+        }
+    }
+
+    /// Serve the records of a db file given as a string.
+    pub fn from_zone(zone: &str) -> Result<Self, Report> {
+        Ok(Self::new(db::from_reader(zone.as_bytes())?))
+    }
+
+    /// Serve as configured, loading the db files of `config`.
+    pub fn with_config(config: Config) -> Result<Self, Report> {
+        let config = config.validate()?;
+        let mut db = db::load_all(&config.db)?;
+
+        if config.compact {
+            db.compact();
+        }
+
+        Ok(Self {
+            config,
+            db,
+            handlers: vec![],
+        })
+    }
+
+    /// Listen on `addr` instead, with port 0 for an ephemeral one.
+    pub fn listen(mut self, addr: SocketAddr) -> Self {
+        self.config.listen = vec![addr];
+        self
+    }
+
+    /// Forward the names missing from the database to `upstream`.
+    pub fn upstream(mut self, upstream: Upstream) -> Self {
+        self.config.upstreams.push(upstream);
+        self
+    }
+
+    /// Be authoritative for `zone`, answering NXDOMAIN for names missing from it.
+    pub fn zone(mut self, zone: impl Into<String>) -> Self {
+        self.config.zones.push(zone.into());
+        self
+    }
+
+    /// Run `handler` on every query, after the handlers added before it
+    /// and before the plugins of the config.
+    pub fn handler(mut self, handler: impl Handler + 'static) -> Self {
+        self.handlers.push(Box::new(handler));
+        self
+    }
 
-        Ok(buf[..count].to_vec())
+    /// Bind the listeners and start answering queries in the background.
+    pub async fn start(self) -> Result<ServerHandle, Report> {
+        let config = self.config;
+        let metrics = Arc::new(Metrics::new());
+        let store = Arc::new(Store::new(self.db));
+
+        let (shutdown, signal) = watch::channel(false);
+        let shutdown = Arc::new(shutdown);
+        let (drain, mut drained) = mpsc::channel::<()>(1);
+        let stop = Shutdown {
+            signal,
+            _drain: drain,
+        };
+
+        let context = Context::new(&config, store.clone(), metrics.clone(), self.handlers).await?;
+        let context = Arc::new(context);
+        let mut tasks = JoinSet::new();
+
+        #[cfg(unix)]
+        if !config.db.is_empty() {
+            let reload = reload_on_hangup(
+                config.db.clone(),
+                config.compact,
+                store.clone(),
+                metrics.clone(),
+                stop.clone(),
+            );
+
+            tasks.spawn(async move {
+                reload.await;
+                Ok(())
+            });
+        }
+
+        if let Some(addr) = config.metrics {
+            let mut stop = stop.clone();
+
+            tasks.spawn(async move {
+                tokio::select! {
+                    result = metrics::serve(addr, metrics) => result,
+                    _ = stop.requested() => Ok(()),
+                }
+            });
+        }
+
+        let listen_addrs = &config.listen;
+        let mut local_addrs = Vec::with_capacity(listen_addrs.len());
+
+        for &addr in listen_addrs {
+            let only_v6 = only_v6(addr, listen_addrs);
+
+            // Use the same port for TCP, should an ephemeral one be picked for UDP.
+            let udp = bind_udp(addr, only_v6)?;
+            let local = udp.local_addr()?;
+            let tcp = bind_tcp(local, only_v6)?;
+
+            info!(
+                "Listening on {} (UDP and TCP)",
+                local.to_string().cyan().underline(),
+            );
+
+            local_addrs.push(local);
+            tasks.spawn(serve_udp(udp, context.clone(), stop.clone()));
+            tasks.spawn(serve_tcp(tcp, context.clone(), stop.clone()));
+        }
+
+        drop((context, stop));
+
+        // Only a weak reference, so that dropping the handle still shuts down.
+        let stopping = Arc::downgrade(&shutdown);
+
+        let task = tokio::spawn(async move {
+            let mut result = Ok(());
+
+            while let Some(joined) = tasks.join_next().await {
+                if let Err(err) = joined.map_err(Report::from).and_then(|result| result) {
+                    result = Err(err);
+                    break;
+                }
+            }
+
+            // Stop whatever is left should a listener fail, along with the
+            // open connections, then let the requests being answered complete.
+            drop(tasks);
+
+            if let Some(shutdown) = stopping.upgrade() {
+                let _ = shutdown.send(true);
+            }
+
+            let _ = drained.recv().await;
+
+            result
+        });
+
+        Ok(ServerHandle {
+            addrs: local_addrs,
+            store,
+            shutdown,
+            task,
+        })
     }
 }
 
-pub async fn run(
-    db: &Path,
-    listen_addr: (&str, u16),
-    upstream_addr: SocketAddr,
-) -> Result<(), Report> {
-    let db = Arc::new(crate::db::load(db)?);
-    let socket = Arc::new(UdpSocket::bind(listen_addr).await?);
-    let forwarder = Forwarder::connect(upstream_addr).await?;
+/// A running [`Server`], which shuts down gracefully when dropped.
+#[derive(Debug)]
+pub struct ServerHandle {
+    addrs: Vec<SocketAddr>,
+    store: Arc<Store>,
+    shutdown: Arc<watch::Sender<bool>>,
+    task: JoinHandle<Result<(), Report>>,
+}
+
+impl ServerHandle {
+    /// Address of the first listener, eg. the only one of [`Server::new`].
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.addrs.first().copied()
+    }
+
+    /// Addresses of every listener, over both UDP and TCP.
+    pub fn local_addrs(&self) -> &[SocketAddr] {
+        &self.addrs
+    }
+
+    /// The database served, which can be updated while running.
+    pub fn store(&self) -> &Arc<Store> {
+        &self.store
+    }
+
+    /// Add `record`, which first moves the records of a compacted db back to
+    /// regular trie nodes, see [`Config::compact`].
+    pub fn insert(&self, name: &Name, record: Record) {
+        self.store.update(|db| db.insert(name, record));
+    }
+
+    pub fn remove(&self, name: &Name, qtype: QType) -> Vec<Record> {
+        self.store.update(|db| db.remove(name, qtype))
+    }
+
+    /// Stop listening, and wait for the queries being answered to complete.
+    pub async fn shutdown(self) -> Result<(), Report> {
+        let _ = self.shutdown.send(true);
+        self.task.await?
+    }
+}
+
+/// Signals a shutdown to the tasks holding it, which the server waits for
+/// until every clone is dropped.
+#[derive(Clone, Debug)]
+struct Shutdown {
+    signal: watch::Receiver<bool>,
+    _drain: mpsc::Sender<()>,
+}
+
+impl Shutdown {
+    /// Complete once a shutdown is requested, or the handle dropped.
+    async fn requested(&mut self) {
+        while !*self.signal.borrow() {
+            if self.signal.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+}
 
-    info!(
-        "Listening on {}",
-        socket.local_addr()?.to_string().cyan().underline(),
-    );
+/// An IPv6 wildcard socket also accepts IPv4 traffic, unless we are asked to
+/// listen on the IPv4 wildcard on the same port too.
+fn only_v6(addr: SocketAddr, listen_addrs: &[SocketAddr]) -> bool {
+    addr.is_ipv6()
+        && addr.ip().is_unspecified()
+        && listen_addrs.iter().any(|other| {
+            other.is_ipv4() && other.ip().is_unspecified() && other.port() == addr.port()
+        })
+}
+
+fn socket(addr: SocketAddr, ty: Type, only_v6: bool) -> Result<Socket, Report> {
+    let socket = Socket::new(Domain::for_address(addr), ty, None)?;
+
+    if addr.is_ipv6() {
+        socket.set_only_v6(only_v6 || !addr.ip().is_unspecified())?;
+    }
+
+    // Restart right away, despite the connections of the previous run lingering
+    // in TIME_WAIT. For UDP, this would only let another process steal the port.
+    if ty == Type::STREAM {
+        socket.set_reuse_address(true)?;
+    }
 
-    let mut buf = [0; MAX_MESSAGE_SIZE];
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+
+    Ok(socket)
+}
+
+fn bind_udp(addr: SocketAddr, only_v6: bool) -> Result<UdpSocket, Report> {
+    let socket = socket(addr, Type::DGRAM, only_v6)?;
+    Ok(UdpSocket::from_std(socket.into())?)
+}
+
+fn bind_tcp(addr: SocketAddr, only_v6: bool) -> Result<TcpListener, Report> {
+    let socket = socket(addr, Type::STREAM, only_v6)?;
+    socket.listen(1024)?;
+    Ok(TcpListener::from_std(socket.into())?)
+}
+
+async fn serve_udp(
+    socket: UdpSocket,
+    context: Arc<Context>,
+    mut shutdown: Shutdown,
+) -> Result<(), Report> {
+    let socket = Arc::new(socket);
+    let local = socket.local_addr()?;
+
+    // Queries are small, but nothing keeps them under 512 bytes with EDNS.
+    let mut buf = vec![0; u16::MAX as usize];
     loop {
-        let (count, addr) = socket.recv_from(&mut buf).await?;
-        let data = &buf[..count];
+        let (count, addr) = tokio::select! {
+            received = socket.recv_from(&mut buf) => received?,
+            _ = shutdown.requested() => return Ok(()),
+        };
+
+        let data = buf[..count].to_vec();
 
-        debug!("Received {count} bytes from {addr}");
+        debug!("Received {count} bytes from {addr} over UDP");
         trace!("Data: {data:?}");
 
-        tokio::spawn(handle_request(
-            db.clone(),
-            forwarder.clone(),
-            socket.clone(),
-            data.to_vec(),
-            addr,
-        ));
+        let (context, socket, guard) = (context.clone(), socket.clone(), shutdown.clone());
+
+        tokio::spawn(async move {
+            let _guard = guard;
+
+            let Some(response) = handle_request(
+                &context,
+                &data,
+                Peer {
+                    addr,
+                    local,
+                    transport: Transport::Udp,
+                },
+            )
+            .await
+            else {
+                return;
+            };
+
+            debug!("Sending {} bytes response to {addr}", response.len());
+
+            if let Err(err) = socket.send_to(&response, addr).await {
+                error!("Failed to send response: {err}");
+            }
+        });
     }
 }
 
-async fn forward(forwarder: &Forwarder, data: &[u8]) -> Result<Message, Report> {
-    let data = forwarder.forward(data).await?;
-    trace!("Data received from upstream: {data:?}");
+async fn serve_tcp(
+    listener: TcpListener,
+    context: Arc<Context>,
+    mut shutdown: Shutdown,
+) -> Result<(), Report> {
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = shutdown.requested() => return Ok(()),
+        };
+
+        let (stream, addr) = match accepted {
+            Ok(conn) => conn,
+            Err(err) => {
+                warn!("Failed to accept TCP connection: {err}");
+                continue;
+            }
+        };
+
+        let (context, mut shutdown) = (context.clone(), shutdown.clone());
 
-    let (_, msg) = Message::from_bytes((&data, 0))?;
-    Ok(msg)
+        tokio::spawn(async move {
+            if let Err(err) = handle_tcp(stream, addr, &context, &mut shutdown).await {
+                debug!("TCP connection from {addr} closed: {err}");
+            }
+        });
+    }
 }
 
-async fn handle_request(
-    db: Arc<Db>,
-    forwarder: Forwarder,
-    socket: Arc<UdpSocket>,
-    data: Vec<u8>,
+/// Answer the length-prefixed messages sent on a TCP connection (RFC 7766),
+/// until the client closes it, stays idle for too long, or we shut down.
+async fn handle_tcp(
+    mut stream: TcpStream,
     addr: SocketAddr,
+    context: &Context,
+    shutdown: &mut Shutdown,
+) -> Result<(), Report> {
+    let local = stream.local_addr()?;
+
+    loop {
+        let read = tokio::select! {
+            read = timeout(TCP_IDLE_TIMEOUT, stream.read_u16()) => read,
+            _ = shutdown.requested() => return Ok(()),
+        };
+
+        let len = match read {
+            Ok(Ok(len)) => len,
+            Ok(Err(err)) if err.kind() == ErrorKind::UnexpectedEof => return Ok(()),
+            Ok(Err(err)) => return Err(err.into()),
+            Err(_) => return Ok(()),
+        };
+
+        let mut data = vec![0; len as usize];
+        stream.read_exact(&mut data).await?;
+
+        debug!("Received {len} bytes from {addr} over TCP");
+        trace!("Data: {data:?}");
+
+        let Some(response) = handle_request(
+            context,
+            &data,
+            Peer {
+                addr,
+                local,
+                transport: Transport::Tcp,
+            },
+        )
+        .await
+        else {
+            continue;
+        };
+
+        debug!("Sending {} bytes response to {addr}", response.len());
+
+        stream
+            .write_all(&(response.len() as u16).to_be_bytes())
+            .await?;
+        stream.write_all(&response).await?;
+    }
+}
+
+/// Reload the database files into the store whenever we receive SIGHUP.
+#[cfg(unix)]
+async fn reload_on_hangup(
+    paths: Vec<std::path::PathBuf>,
+    compact: bool,
+    store: Arc<Store>,
+    metrics: Arc<Metrics>,
+    mut shutdown: Shutdown,
 ) {
-    let message = match Message::from_bytes((&data, 0)) {
-        Ok((_, message)) => message,
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
         Err(err) => {
-            error!("Failed to parse message: {err}");
+            warn!("Cannot listen for SIGHUP, reloading is disabled: {err}");
             return;
         }
     };
 
-    debug!("Handling message: {message:#?}");
+    loop {
+        tokio::select! {
+            received = hangup.recv() => if received.is_none() { return },
+            _ = shutdown.requested() => return,
+        }
 
-    let response = match handle_message(&db, &message).await {
-        Ok(Some(response)) => response,
-        Ok(None) => {
-            debug!("Forwarding request to upstream");
+        let result = db::load_all(&paths);
+        metrics.reload(result.is_ok());
 
-            match forward(&forwarder, &data).await {
-                Ok(response) => response,
-                Err(err) => {
-                    error!("Failed to forward request: {err}");
-                    return;
+        match result {
+            Ok(mut db) => {
+                if compact {
+                    db.compact();
                 }
+
+                info!("Reloaded {} db file(s)", paths.len());
+                store.replace(db);
             }
+            Err(err) => error!("Failed to reload: {err:#}"),
         }
+    }
+}
+
+/// Where a request comes from, and the socket it arrived on.
+#[derive(Copy, Clone, Debug)]
+struct Peer {
+    addr: SocketAddr,
+    local: SocketAddr,
+    transport: Transport,
+}
+
+/// Answer a request, or return `None` if it does not even look like a query.
+async fn handle_request(context: &Context, data: &[u8], peer: Peer) -> Option<Vec<u8>> {
+    let start = SystemTime::now();
+    let _in_flight = context.metrics.in_flight();
+
+    let tap = |kind, message| {
+        if let Some(dnstap) = &context.dnstap {
+            dnstap.log(&DnstapEvent {
+                kind,
+                transport: peer.transport,
+                query_address: peer.addr,
+                response_address: peer.local,
+                query_time: start,
+                response_time: (kind == MessageType::ClientResponse).then(SystemTime::now),
+                message,
+            });
+        }
+    };
+
+    tap(MessageType::ClientQuery, data);
+
+    let (response, source) = answer_request(context, data, peer).await?;
+    context.metrics.response(&response);
+    tap(MessageType::ClientResponse, &response);
+
+    if let (Some(query_log), Ok(query)) = (&context.query_log, MessageView::parse(data)) {
+        let entry = Entry::new(start, peer.addr, peer.transport, &query, &response, source);
+        query_log.log(entry);
+    }
+
+    Some(response)
+}
+
+async fn answer_request(context: &Context, data: &[u8], peer: Peer) -> Option<(Vec<u8>, Source)> {
+    let local = |rcode| Some((error_response(data, rcode)?, Source::Local));
+
+    let header = match HeaderView::parse(data) {
+        Ok(header) => header,
         Err(err) => {
-            error!("Failed to handle message: {err}");
-            return;
+            error!("Failed to parse message: {err}");
+            context.metrics.malformed();
+            return None;
         }
     };
 
-    // debug!("Response: {response:#?}");
+    // Never reply to a response, lest two servers keep answering each other.
+    if header.qr() {
+        warn!("Ignoring response with id {}", header.id);
+        return None;
+    }
 
-    let response_data = match response.to_bytes() {
-        Ok(data) => data,
+    let message = match MessageView::parse(data) {
+        Ok(message) => message,
         Err(err) => {
-            error!("Failed to serialize response: {err}");
-            return;
+            error!("Failed to parse message: {err}");
+            context.metrics.malformed();
+            return local(RCode::FormatError);
         }
     };
 
-    debug!("Sending {} bytes response to {addr}", response_data.len(),);
+    debug!("Handling message: {message:#?}");
 
-    if let Err(err) = socket.send_to(&response_data, addr).await {
-        error!("Failed to send response: {err}");
+    if header.opcode() != Opcode::Query as u8 {
+        debug!("Unsupported opcode {}", header.opcode());
+        return local(RCode::NotImplemented);
     }
-}
 
-async fn handle_message(db: &Db, message: &Message) -> Result<Option<Message>, Report> {
-    let answers = message
-        .questions
-        .iter()
-        .map(|q| answer_question(db, q))
-        .collect::<Result<Option<Vec<_>>, _>>()?;
+    // Like most servers, only accept a single question per query, as there
+    // is no way to give each one its own rcode or authority.
+    if header.qdcount != 1 {
+        debug!("Query with {} questions", header.qdcount);
+        return local(RCode::FormatError);
+    }
+
+    let question = message.questions().next()?;
+    context.metrics.query(question.qtype);
 
-    let Some(answers) = answers else {
-        return Ok(None);
+    let request = Request {
+        data,
+        message,
+        question,
+        name: question.qname.to_name(),
+        client: peer.addr,
+        transport: peer.transport,
     };
 
-    let header = Header {
-        id: message.header.id,
-        flags: Flags::answer(message.header.flags.opcode),
-        ancount: answers.len() as u16,
-        qdcount: 0,
-        nscount: 0,
-        arcount: 0,
+    match context.chain.run(&request).await {
+        Some(Response::Answer { message, source }) => Some((message, source)),
+        Some(Response::Drop) => None,
+        // Nobody handles this name.
+        None => Some((error_response(data, RCode::Refused)?, Source::Blocked)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, Ipv6Addr, SocketAddrV4};
+
+    use crate::{
+        config::ForwardRule,
+        handler::{HandlerFuture, Next},
+        plugins::Forwarder,
     };
 
-    let response = Message {
-        header,
-        answers,
-        questions: vec![],
-        authorities: vec![],
-        additionals: vec![],
+    // news.ycombinator.com A, with RD set.
+    const QUERY: &[u8] = &[
+        100, 68, 1, 0, 0, 1, 0, 0, 0, 0, 0, 0, 4, 110, 101, 119, 115, 11, 121, 99, 111, 109, 98,
+        105, 110, 97, 116, 111, 114, 3, 99, 111, 109, 0, 0, 1, 0, 1,
+    ];
+
+    const CLIENT: Peer = Peer {
+        addr: SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 5353)),
+        local: SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 53)),
+        transport: Transport::Udp,
     };
 
-    Ok(Some(response))
-}
+    /// A context with the default plugins, over an empty database.
+    fn context(routes: Vec<(Name, Forwarder)>) -> (Context, Arc<Store>) {
+        let store = Arc::new(Store::new(Db::new()));
+        let metrics = Arc::new(Metrics::new());
+
+        let context = Context {
+            chain: Chain::new(vec![
+                Box::new(plugins::Faults::new(store.clone())),
+                Box::new(plugins::Local::new(store.clone(), vec![], metrics.clone())),
+                Box::new(plugins::Forward::new(routes, None, metrics.clone())),
+            ]),
+            metrics,
+            query_log: None,
+            dnstap: None,
+        };
+
+        (context, store)
+    }
+
+    fn rcode(response: &[u8]) -> u8 {
+        HeaderView::parse(response).unwrap().rcode()
+    }
+
+    #[tokio::test]
+    async fn malformed() {
+        let (context, _) = context(vec![]);
+
+        // Not even a header, so there is no id to reply to.
+        assert_eq!(handle_request(&context, &QUERY[..8], CLIENT).await, None);
 
-fn answer_question(db: &Db, question: &Question) -> Result<Option<ResourceRecord>, Report> {
-    let now = Instant::now();
+        // Truncated question.
+        let response = handle_request(&context, &QUERY[..20], CLIENT)
+            .await
+            .unwrap();
+        assert_eq!(response, [100, 68, 129, 1, 0, 0, 0, 0, 0, 0, 0, 0]);
 
-    info!(
-        "<== {:<50}    {:?}",
-        question.qname.blue().bold().to_string(),
-        question.qtype.green().bold(),
-    );
+        // No question at all.
+        let mut query = QUERY[..12].to_vec();
+        query[5] = 0;
 
-    let record = db.lookup(&question.qname, question.qtype);
+        let response = handle_request(&context, &query, CLIENT).await.unwrap();
+        assert_eq!(rcode(&response), RCode::FormatError as u8);
+
+        // Two questions.
+        let mut query = QUERY.to_vec();
+        query[5] = 2;
+        query.extend_from_slice(&QUERY[12..]);
+
+        let response = handle_request(&context, &query, CLIENT).await.unwrap();
+        assert_eq!(rcode(&response), RCode::FormatError as u8);
+        assert_eq!(
+            MessageView::parse(&response).unwrap().questions().count(),
+            2
+        );
 
-    let Some(record) = record else {
-            return Ok(None);
+        // Responses are never answered.
+        let mut response = QUERY.to_vec();
+        response[2] |= 0x80;
+        assert_eq!(handle_request(&context, &response, CLIENT).await, None);
+
+        assert!(context
+            .metrics
+            .render()
+            .contains("denis_malformed_total 2\n"));
+    }
+
+    #[tokio::test]
+    async fn unsupported_opcode() {
+        let (context, _) = context(vec![]);
+
+        // NOTIFY
+        let mut query = QUERY.to_vec();
+        query[2] |= 4 << 3;
+
+        let response = handle_request(&context, &query, CLIENT).await.unwrap();
+        let message = MessageView::parse(&response).unwrap();
+
+        assert_eq!(message.header().id, 25668);
+        assert!(message.header().qr());
+        assert!(message.header().rd());
+        assert_eq!(message.header().opcode(), 4);
+        assert_eq!(message.header().rcode(), RCode::NotImplemented as u8);
+        assert_eq!(message.question_bytes(), &QUERY[12..]);
+    }
+
+    #[tokio::test]
+    async fn faults() {
+        let (context, store) = context(vec![]);
+
+        let inject = |rules: &[&str]| {
+            let mut db = Db::new();
+
+            for rule in rules {
+                db.add_fault(rule.parse().unwrap());
+            }
+
+            store.replace(db);
         };
 
-    let data = record.to_bytes();
+        inject(&["$FAULT *.ycombinator.com servfail"]);
+        let response = handle_request(&context, QUERY, CLIENT).await.unwrap();
+        assert_eq!(rcode(&response), RCode::ServerFailure as u8);
 
-    let answer = ResourceRecord {
-        name: question.qname.clone(),
-        qtype: record.qtype(),
-        qclass: record.qclass(),
-        ttl: 1,
-        rdlength: data.len() as u16,
-        data,
-        options_code: None,
-        options_length: None,
-    };
+        inject(&["$FAULT news.ycombinator.com truncate"]);
+        let response = handle_request(&context, QUERY, CLIENT).await.unwrap();
+        assert!(HeaderView::parse(&response).unwrap().tc());
+        assert_eq!(rcode(&response), RCode::NoError as u8);
+
+        // Rules which do not fire are skipped, and delays add up to the next one.
+        inject(&[
+            "$FAULT news.ycombinator.com servfail 0%",
+            "$FAULT NEWS.ycombinator.com delay 1ms",
+            "$FAULT news.ycombinator.com drop",
+        ]);
+        assert_eq!(handle_request(&context, QUERY, CLIENT).await, None);
+    }
+
+    #[tokio::test]
+    async fn truncated() {
+        let (context, store) = context(vec![]);
+
+        let mut db = Db::new();
+        let name = Name::from_str("news.ycombinator.com").unwrap();
+
+        for i in 0..40 {
+            db.insert(
+                &name,
+                Record::A {
+                    address: [127, 0, 0, i],
+                },
+            );
+        }
+
+        store.replace(db);
+
+        // Too large for a client over UDP without EDNS.
+        let response = handle_request(&context, QUERY, CLIENT).await.unwrap();
+        let message = MessageView::parse(&response).unwrap();
+
+        assert!(message.header().tc());
+        assert_eq!(message.answers().count(), 0);
+        assert_eq!(message.question_bytes(), &QUERY[12..]);
+
+        // Unless it came over TCP.
+        let tcp = Peer {
+            transport: Transport::Tcp,
+            ..CLIENT
+        };
+
+        let response = handle_request(&context, QUERY, tcp).await.unwrap();
+        let message = MessageView::parse(&response).unwrap();
+
+        assert!(!message.header().tc());
+        assert_eq!(message.answers().count(), 40);
+
+        // Or advertises a large enough payload size.
+        let mut query = QUERY.to_vec();
+        query[11] = 1;
+        query.extend_from_slice(&[0, 0, 41, 16, 0, 0, 0, 0, 0, 0, 0]);
+
+        let response = handle_request(&context, &query, CLIENT).await.unwrap();
+        let message = MessageView::parse(&response).unwrap();
+
+        assert!(!message.header().tc());
+        assert_eq!(message.answers().count(), 40);
+        assert_eq!(message.edns().unwrap().udp_payload_size, 1232);
+    }
+
+    #[tokio::test]
+    async fn cached_truncated() {
+        /// Answers with a fixed response, as if from upstream.
+        #[derive(Debug)]
+        struct Upstream(Vec<u8>);
+
+        impl Handler for Upstream {
+            fn handle<'a>(&'a self, _: &'a Request<'a>, _: Next<'a>) -> HandlerFuture<'a> {
+                Box::pin(async move {
+                    Some(Response::Answer {
+                        message: self.0.clone(),
+                        source: Source::Upstream,
+                    })
+                })
+            }
+        }
+
+        // 32 A records, for 550 bytes.
+        let mut response = QUERY.to_vec();
+        response[2..4].copy_from_slice(&[0x81, 0x80]);
+        response[7] = 32;
+
+        for i in 0..32 {
+            response.extend_from_slice(&[192, 12, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 127, 0, 0, i]);
+        }
+
+        let metrics = Arc::new(Metrics::new());
+        let context = Context {
+            chain: Chain::new(vec![
+                Box::new(plugins::Cache::new(Default::default(), metrics.clone())),
+                Box::new(Upstream(response.clone())),
+            ]),
+            metrics,
+            query_log: None,
+            dnstap: None,
+        };
+
+        let tcp = Peer {
+            transport: Transport::Tcp,
+            ..CLIENT
+        };
+
+        let (message, source) = answer_request(&context, QUERY, tcp).await.unwrap();
+        assert_eq!((message, source), (response.clone(), Source::Upstream));
+
+        // Too large for a client over UDP without EDNS.
+        let (message, source) = answer_request(&context, QUERY, CLIENT).await.unwrap();
+        assert_eq!(source, Source::Cache);
+        assert!(HeaderView::parse(&message).unwrap().tc());
+        assert_eq!(&message[..2], &QUERY[..2]);
+        assert_eq!(message[6..12], [0; 6]);
+        assert_eq!(message[12..], QUERY[12..]);
+
+        // But not for one advertising a larger payload size.
+        let mut query = QUERY.to_vec();
+        query[11] = 1;
+        query.extend_from_slice(&[0, 0, 41, 16, 0, 0, 0, 0, 0, 0, 0]);
+
+        let (message, source) = answer_request(&context, &query, CLIENT).await.unwrap();
+        assert_eq!((message, source), (response, Source::Cache));
+    }
+
+    #[tokio::test]
+    async fn embedded() {
+        let server = Server::from_zone("$FAULT *.local.dev servfail")
+            .unwrap()
+            .start()
+            .await
+            .unwrap();
+
+        let addr = server.local_addr().unwrap();
+        assert_eq!(addr.ip(), Ipv4Addr::LOCALHOST);
+        assert_ne!(addr.port(), 0);
+
+        let name = Name::from_str("api.local.dev").unwrap();
+        let record = Record::A {
+            address: [127, 0, 0, 1],
+        };
+
+        server.insert(&name, record.clone());
+        assert_eq!(server.store().load().lookup(&name, QType::A), Some(&record));
+
+        // api.local.dev A
+        let query = [
+            18, 52, 1, 0, 0, 1, 0, 0, 0, 0, 0, 0, 3, 97, 112, 105, 5, 108, 111, 99, 97, 108, 3,
+            100, 101, 118, 0, 0, 1, 0, 1,
+        ];
+
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        socket.send_to(&query, addr).await.unwrap();
+
+        let mut buf = [0; MAX_MESSAGE_SIZE];
+        let count = socket.recv(&mut buf).await.unwrap();
+        assert_eq!(rcode(&buf[..count]), RCode::ServerFailure as u8);
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_u16(query.len() as u16).await.unwrap();
+        stream.write_all(&query).await.unwrap();
+
+        let mut response = vec![0; stream.read_u16().await.unwrap() as usize];
+        stream.read_exact(&mut response).await.unwrap();
+        assert_eq!(rcode(&response), RCode::ServerFailure as u8);
+
+        // Idle connections do not hold up the shutdown.
+        server.shutdown().await.unwrap();
+
+        assert_eq!(
+            stream.read_u16().await.unwrap_err().kind(),
+            ErrorKind::UnexpectedEof
+        );
+        assert!(TcpStream::connect(addr).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn listener_failure() {
+        // The metrics port is taken, so that its listener fails right away.
+        let taken = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+
+        let path = std::env::temp_dir().join(format!("denis-failure-{}.db", std::process::id()));
+        std::fs::write(&path, "api.local.dev A 127.0.0.1\n").unwrap();
+
+        let config = Config {
+            listen: vec![SocketAddr::from(([127, 0, 0, 1], 0))],
+            db: vec![path.clone()],
+            metrics: Some(taken.local_addr().unwrap()),
+            ..Config::default()
+        };
+
+        let server = Server::with_config(config).unwrap().start().await;
+        std::fs::remove_file(&path).unwrap();
+
+        // Despite the db reloader, which waits for SIGHUP.
+        let result = timeout(Duration::from_secs(5), server.unwrap().task).await;
+        assert!(result.expect("the server stops").unwrap().is_err());
+    }
+
+    #[tokio::test]
+    async fn compact() {
+        let path = std::env::temp_dir().join(format!("denis-compact-{}.db", std::process::id()));
+        std::fs::write(&path, "api.local.dev A 127.0.0.1\n").unwrap();
+
+        let config = Config {
+            listen: vec![SocketAddr::from(([127, 0, 0, 1], 0))],
+            db: vec![path.clone()],
+            compact: true,
+            ..Config::default()
+        };
+
+        let server = Server::with_config(config).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(server.db.is_compact());
+
+        let server = server.start().await.unwrap();
+        let a = |n| Record::A {
+            address: [127, 0, 0, n],
+        };
+
+        let api = Name::from_str("api.local.dev").unwrap();
+        assert_eq!(server.store().load().lookup(&api, QType::A), Some(&a(1)));
+
+        let www = Name::from_str("www.local.dev").unwrap();
+        server.insert(&www, a(2));
+
+        let db = server.store().load();
+        assert!(!db.is_compact());
+        assert_eq!(db.lookup(&api, QType::A), Some(&a(1)));
+        assert_eq!(db.lookup(&www, QType::A), Some(&a(2)));
 
-    let elapsed = now.elapsed().as_millis();
+        server.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn invalid_config() {
+        assert!(Server::with_config(Config::default()).is_err());
+
+        let config = Config {
+            db: vec!["/dev/null".into()],
+            rules: vec![ForwardRule {
+                zone: "corp".to_string(),
+                upstream: "nowhere".to_string(),
+            }],
+            ..Config::default()
+        };
+
+        // Even without validating the config first.
+        let mut server = Server::new(Db::new());
+        server.config.rules = config.rules.clone();
+        assert!(server.start().await.is_err());
+
+        assert!(Server::with_config(config).is_err());
+    }
+
+    #[tokio::test]
+    async fn upstream_failure() {
+        // An upstream which never answers.
+        let upstream = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+        let metrics = Metrics::new();
+        let forwarder = Forwarder::connect(
+            &Upstream {
+                timeout_ms: 10,
+                ..Upstream::new("silent", upstream.local_addr().unwrap())
+            },
+            &metrics,
+            None,
+        )
+        .await
+        .unwrap();
+
+        let (context, _) = context(vec![(Name::root(), forwarder)]);
+
+        let response = handle_request(&context, QUERY, CLIENT).await.unwrap();
+        assert_eq!(rcode(&response), RCode::ServerFailure as u8);
+        assert_eq!(&response[..2], &QUERY[..2]);
+
+        assert!(metrics
+            .render()
+            .contains("denis_upstream_errors_total{upstream=\"silent\"} 1\n"));
+    }
+
+    #[tokio::test]
+    async fn upstream_mixups() {
+        let upstream = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+        let metrics = Metrics::new();
+        let forwarder = Forwarder::connect(
+            &Upstream {
+                timeout_ms: 200,
+                ..Upstream::new("mixed", upstream.local_addr().unwrap())
+            },
+            &metrics,
+            None,
+        )
+        .await
+        .unwrap();
+
+        let reply = |query: &[u8]| {
+            let mut response = query.to_vec();
+            response[2] |= 0x80;
+            response
+        };
+
+        // vews.ycombinator.com A, with another id.
+        let mut other = QUERY.to_vec();
+        other[..2].copy_from_slice(&[0x12, 0x34]);
+        other[13] = b'v';
+
+        let mut buf = [0; MAX_MESSAGE_SIZE];
+
+        // Each of two concurrent queries first gets the response to the
+        // other one, and one with the wrong id, before its own.
+        let serve = async {
+            let mut queries = Vec::new();
+
+            for _ in 0..2 {
+                let (count, from) = upstream.recv_from(&mut buf).await.unwrap();
+                queries.push((buf[..count].to_vec(), from));
+            }
+
+            for (i, (query, from)) in queries.iter().enumerate() {
+                let mut wrong_id = reply(query);
+                wrong_id[1] ^= 1;
+
+                upstream
+                    .send_to(&reply(&queries[1 - i].0), from)
+                    .await
+                    .unwrap();
+                upstream.send_to(&wrong_id, from).await.unwrap();
+                upstream.send_to(&reply(query), from).await.unwrap();
+            }
+        };
+
+        let (first, second, ()) = tokio::join!(
+            forwarder.forward(QUERY, Transport::Udp),
+            forwarder.forward(&other, Transport::Udp),
+            serve
+        );
+        assert_eq!(first.unwrap(), reply(QUERY));
+        assert_eq!(second.unwrap(), reply(&other));
+
+        // An unanswered query, whose response comes in late.
+        assert!(forwarder.forward(&other, Transport::Udp).await.is_err());
+        let (_, late) = upstream.recv_from(&mut buf).await.unwrap();
+
+        let serve = async {
+            let (count, from) = upstream.recv_from(&mut buf).await.unwrap();
+            assert_ne!(from, late);
+
+            upstream.send_to(&reply(&other), late).await.unwrap();
+            upstream.send_to(&reply(&other), from).await.unwrap();
+            upstream.send_to(&reply(&buf[..count]), from).await.unwrap();
+        };
+
+        let (response, ()) = tokio::join!(forwarder.forward(QUERY, Transport::Udp), serve);
+        assert_eq!(response.unwrap(), reply(QUERY));
+
+        assert!(metrics
+            .render()
+            .contains("denis_upstream_errors_total{upstream=\"mixed\"} 1\n"));
+    }
+
+    #[tokio::test]
+    async fn upstream_truncated() {
+        // An upstream truncating its responses over UDP, unless the query has
+        // EDNS, and answering vews.ycombinator.com with a large response.
+        let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = udp.local_addr().unwrap();
+        let tcp = TcpListener::bind(addr).await.unwrap();
+
+        fn reply(query: &[u8]) -> Vec<u8> {
+            let mut response = query.to_vec();
+            response[2] |= 0x80;
+
+            if query[13] == b'v' {
+                response.resize(1000, 0);
+            }
+
+            response
+        }
+
+        tokio::spawn(async move {
+            let mut buf = [0; MAX_MESSAGE_SIZE];
+
+            loop {
+                let (count, from) = udp.recv_from(&mut buf).await.unwrap();
+                let query = &buf[..count];
+
+                let response = if query[11] == 0 {
+                    let mut truncated = query.to_vec();
+                    truncated[2] |= 0x82;
+                    truncated
+                } else {
+                    reply(query)
+                };
 
-    info!(
-        "==> {:<50}    {:#}          {}",
-        question.qname.blue().bold().to_string(),
-        record,
-        format!("{elapsed}ms").dimmed()
-    );
+                udp.send_to(&response, from).await.unwrap();
+            }
+        });
+
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = tcp.accept().await.unwrap();
+
+                let mut query = vec![0; stream.read_u16().await.unwrap() as usize];
+                stream.read_exact(&mut query).await.unwrap();
+
+                let response = reply(&query);
+                stream.write_u16(response.len() as u16).await.unwrap();
+                stream.write_all(&response).await.unwrap();
+            }
+        });
+
+        let metrics = Metrics::new();
+        let forwarder = Forwarder::connect(&Upstream::new("truncating", addr), &metrics, None)
+            .await
+            .unwrap();
+
+        // vews.ycombinator.com A, with another id.
+        let mut large = QUERY.to_vec();
+        large[..2].copy_from_slice(&[0x12, 0x34]);
+        large[13] = b'v';
+
+        // Retried over TCP, as the full response fits in 512 bytes.
+        let response = forwarder.forward(QUERY, Transport::Udp).await.unwrap();
+        assert_eq!(response, reply(QUERY));
+
+        // Too large for a client without EDNS, which gets it truncated.
+        let response = forwarder.forward(&large, Transport::Udp).await.unwrap();
+        assert!(HeaderView::parse(&response).unwrap().tc());
+        assert_eq!(response.len(), large.len());
+
+        // Unless it came over TCP.
+        let response = forwarder.forward(&large, Transport::Tcp).await.unwrap();
+        assert_eq!(response, reply(&large));
+
+        // Or advertises a large enough payload size, with an OPT record.
+        large[11] = 1;
+        large.extend_from_slice(&[0, 0, 41, 16, 0, 0, 0, 0, 0, 0, 0]);
+
+        let response = forwarder.forward(&large, Transport::Udp).await.unwrap();
+        assert_eq!(response, reply(&large));
+    }
+
+    async fn query_udp(addr: SocketAddr, query: &[u8]) -> Vec<u8> {
+        let local = if addr.is_ipv4() {
+            "0.0.0.0:0"
+        } else {
+            "[::]:0"
+        };
+        let socket = UdpSocket::bind(local).await.unwrap();
+        socket.send_to(query, addr).await.unwrap();
+
+        let mut buf = [0; MAX_MESSAGE_SIZE];
+        let count = socket.recv(&mut buf).await.unwrap();
+        buf[..count].to_vec()
+    }
+
+    async fn query_tcp(addr: SocketAddr, query: &[u8]) -> Vec<u8> {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_u16(query.len() as u16).await.unwrap();
+        stream.write_all(query).await.unwrap();
+        read_tcp(&mut stream).await
+    }
+
+    async fn read_tcp(stream: &mut TcpStream) -> Vec<u8> {
+        let mut response = vec![0; stream.read_u16().await.unwrap() as usize];
+        stream.read_exact(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn tcp_framing() {
+        let server = Server::from_zone("$FAULT *.ycombinator.com servfail")
+            .unwrap()
+            .start()
+            .await
+            .unwrap();
 
-    Ok(Some(answer))
+        let mut other = QUERY.to_vec();
+        other[..2].copy_from_slice(&[0x12, 0x34]);
+
+        let mut data = Vec::new();
+        for query in [QUERY, &other] {
+            data.extend_from_slice(&(query.len() as u16).to_be_bytes());
+            data.extend_from_slice(query);
+        }
+
+        let mut stream = TcpStream::connect(server.local_addr().unwrap())
+            .await
+            .unwrap();
+
+        // Several queries in a single segment, answered in order.
+        stream.write_all(&data).await.unwrap();
+        assert_eq!(read_tcp(&mut stream).await[..2], QUERY[..2]);
+        assert_eq!(read_tcp(&mut stream).await[..2], other[..2]);
+
+        // A query split across segments, even within its length.
+        for chunk in [&data[..1], &data[1..10], &data[10..]] {
+            stream.write_all(chunk).await.unwrap();
+            stream.flush().await.unwrap();
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        assert_eq!(read_tcp(&mut stream).await[..2], QUERY[..2]);
+        assert_eq!(read_tcp(&mut stream).await[..2], other[..2]);
+
+        server.shutdown().await.unwrap();
+    }
+
+    #[test]
+    fn dual_stack() {
+        let addr = |s: &str| SocketAddr::from_str(s).unwrap();
+        let listen = [addr("[::]:53"), addr("0.0.0.0:53"), addr("[::]:5353")];
+
+        assert!(only_v6(listen[0], &listen));
+        assert!(!only_v6(listen[1], &listen));
+        assert!(!only_v6(listen[2], &listen));
+        assert!(!only_v6(addr("[::1]:53"), &listen));
+    }
+
+    #[tokio::test]
+    async fn listeners() {
+        // A port free over both IPv4 and IPv6.
+        let port = UdpSocket::bind("[::]:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+
+        let mut server = Server::from_zone("$FAULT *.ycombinator.com servfail").unwrap();
+        server.config.listen = vec![
+            SocketAddr::from(([0, 0, 0, 0], port)),
+            SocketAddr::from(([0; 16], port)),
+            SocketAddr::from(([0; 16], 0)),
+        ];
+
+        let server = server.start().await.unwrap();
+        let addrs = server.local_addrs().to_vec();
+
+        assert_eq!(addrs.len(), 3);
+        assert_eq!((addrs[0].port(), addrs[1].port()), (port, port));
+        assert_ne!(addrs[2].port(), port);
+
+        // The last one is dual-stack, unlike the IPv6 one on the same port
+        // as the IPv4 one.
+        let targets = [
+            SocketAddr::from(([127, 0, 0, 1], port)),
+            SocketAddr::from((Ipv6Addr::LOCALHOST, port)),
+            SocketAddr::from(([127, 0, 0, 1], addrs[2].port())),
+            SocketAddr::from((Ipv6Addr::LOCALHOST, addrs[2].port())),
+        ];
+
+        for target in targets {
+            let response = query_udp(target, QUERY).await;
+            assert_eq!(rcode(&response), RCode::ServerFailure as u8);
+
+            let response = query_tcp(target, QUERY).await;
+            assert_eq!(rcode(&response), RCode::ServerFailure as u8);
+        }
+
+        server.shutdown().await.unwrap();
+    }
 }
@@ -0,0 +1,128 @@
+use std::{collections::VecDeque, sync::Arc, sync::Mutex};
+
+use arc_swap::ArcSwap;
+
+use crate::db::Db;
+
+/// Number of previous versions kept around for rollback by default.
+const DEFAULT_HISTORY: usize = 16;
+
+/// Shared, versioned handle to the [`Db`].
+///
+/// Readers get a consistent snapshot without taking any lock, while writers
+/// build the next version from a cheap clone of the current one and swap it in.
+#[derive(Debug)]
+pub struct Store {
+    current: ArcSwap<Db>,
+    history: Mutex<VecDeque<Arc<Db>>>,
+    max_history: usize,
+}
+
+impl Store {
+    pub fn new(db: Db) -> Self {
+        Self::with_history(db, DEFAULT_HISTORY)
+    }
+
+    pub fn with_history(db: Db, max_history: usize) -> Self {
+        Self {
+            current: ArcSwap::from_pointee(db),
+            history: Mutex::new(VecDeque::new()),
+            max_history,
+        }
+    }
+
+    /// Current version of the database.
+    pub fn load(&self) -> Arc<Db> {
+        self.current.load_full()
+    }
+
+    /// Apply `f` to a copy of the current version and publish the result.
+    pub fn update<R>(&self, f: impl FnOnce(&mut Db) -> R) -> R {
+        let mut history = self.history.lock().unwrap();
+
+        let old = self.current.load_full();
+        let mut db = Db::clone(&old);
+        let result = f(&mut db);
+
+        self.current.store(Arc::new(db));
+        self.push(&mut history, old);
+
+        result
+    }
+
+    /// Replace the current version, eg. after reloading the database file.
+    pub fn replace(&self, db: Db) {
+        let mut history = self.history.lock().unwrap();
+
+        let old = self.current.swap(Arc::new(db));
+        self.push(&mut history, old);
+    }
+
+    /// Restore the previous version, returning `false` if there is none.
+    pub fn rollback(&self) -> bool {
+        let mut history = self.history.lock().unwrap();
+
+        match history.pop_back() {
+            Some(db) => {
+                self.current.store(db);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Number of previous versions available for rollback.
+    pub fn history_len(&self) -> usize {
+        self.history.lock().unwrap().len()
+    }
+
+    fn push(&self, history: &mut VecDeque<Arc<Db>>, db: Arc<Db>) {
+        if self.max_history == 0 {
+            return;
+        }
+
+        if history.len() == self.max_history {
+            history.pop_front();
+        }
+
+        history.push_back(db);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        data::{Name, QType},
+        record::Record,
+    };
+
+    #[test]
+    fn update_and_rollback() {
+        let name = Name::new("example.com".to_string());
+        let a = |n| Record::A {
+            address: [n, n, n, n],
+        };
+
+        let mut db = Db::new();
+        db.insert(&name, a(1));
+
+        let store = Store::with_history(db, 1);
+        let snapshot = store.load();
+
+        store.update(|db| {
+            db.remove(&name, QType::A);
+            db.insert(&name, a(2));
+        });
+
+        assert_eq!(snapshot.lookup(&name, QType::A), Some(&a(1)));
+        assert_eq!(store.load().lookup(&name, QType::A), Some(&a(2)));
+
+        store.update(|db| db.insert(&name, a(3)));
+        assert_eq!(store.history_len(), 1);
+
+        assert!(store.rollback());
+        assert_eq!(store.load().lookup_all(&name, QType::A), vec![&a(2)]);
+        assert!(!store.rollback());
+    }
+}
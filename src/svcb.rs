@@ -0,0 +1,586 @@
+use core::fmt;
+use std::{
+    collections::BTreeMap,
+    net::{Ipv4Addr, Ipv6Addr},
+    str::FromStr,
+};
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use color_eyre::{eyre::eyre, Report};
+
+use crate::record::CharacterStrings;
+
+/// A service parameter key, as defined in RFC 9460, section 14.3.2.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SvcParamKey(pub u16);
+
+impl SvcParamKey {
+    pub const MANDATORY: Self = Self(0);
+    pub const ALPN: Self = Self(1);
+    pub const NO_DEFAULT_ALPN: Self = Self(2);
+    pub const PORT: Self = Self(3);
+    pub const IPV4HINT: Self = Self(4);
+    pub const ECH: Self = Self(5);
+    pub const IPV6HINT: Self = Self(6);
+    pub const INVALID: Self = Self(65535);
+}
+
+impl fmt::Display for SvcParamKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Self::MANDATORY => write!(f, "mandatory"),
+            Self::ALPN => write!(f, "alpn"),
+            Self::NO_DEFAULT_ALPN => write!(f, "no-default-alpn"),
+            Self::PORT => write!(f, "port"),
+            Self::IPV4HINT => write!(f, "ipv4hint"),
+            Self::ECH => write!(f, "ech"),
+            Self::IPV6HINT => write!(f, "ipv6hint"),
+            Self(key) => write!(f, "key{key}"),
+        }
+    }
+}
+
+impl FromStr for SvcParamKey {
+    type Err = Report;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let key = match s {
+            "mandatory" => Self::MANDATORY,
+            "alpn" => Self::ALPN,
+            "no-default-alpn" => Self::NO_DEFAULT_ALPN,
+            "port" => Self::PORT,
+            "ipv4hint" => Self::IPV4HINT,
+            "ech" => Self::ECH,
+            "ipv6hint" => Self::IPV6HINT,
+            s => {
+                let key = s
+                    .strip_prefix("key")
+                    .and_then(|n| n.parse().ok())
+                    .ok_or_else(|| eyre!("Invalid SvcParamKey: {s}"))?;
+
+                Self(key)
+            }
+        };
+
+        if key == Self::INVALID {
+            return Err(eyre!("Invalid SvcParamKey: {s}"));
+        }
+
+        Ok(key)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SvcParamValue {
+    Mandatory(Vec<SvcParamKey>),
+    Alpn(Vec<String>),
+    NoDefaultAlpn,
+    Port(u16),
+    Ipv4Hint(Vec<Ipv4Addr>),
+    Ech(Vec<u8>),
+    Ipv6Hint(Vec<Ipv6Addr>),
+    Unknown(Vec<u8>),
+}
+
+impl SvcParamValue {
+    fn parse(key: SvcParamKey, value: Option<&str>) -> Result<Self, Report> {
+        let value = value.map(|v| {
+            v.strip_prefix('"')
+                .and_then(|v| v.strip_suffix('"'))
+                .unwrap_or(v)
+        });
+        let unknown = key.0 > SvcParamKey::IPV6HINT.0;
+
+        if key == SvcParamKey::NO_DEFAULT_ALPN {
+            return match value {
+                None | Some("") => Ok(Self::NoDefaultAlpn),
+                Some(_) => Err(eyre!("SvcParam {key} does not take a value")),
+            };
+        }
+
+        let value = match value {
+            Some(value) if !value.is_empty() || unknown => value,
+            None if unknown => "",
+            _ => return Err(eyre!("SvcParam {key} requires a value")),
+        };
+
+        let parsed = match key {
+            SvcParamKey::MANDATORY => {
+                let mut keys = value
+                    .split(',')
+                    .map(SvcParamKey::from_str)
+                    .collect::<Result<Vec<_>, _>>()?;
+
+                // They must be in strictly increasing order on the wire.
+                keys.sort();
+                Self::Mandatory(keys)
+            }
+            SvcParamKey::ALPN => Self::Alpn(split_alpn(value)),
+            SvcParamKey::PORT => Self::Port(value.parse()?),
+            SvcParamKey::IPV4HINT => Self::Ipv4Hint(
+                value
+                    .split(',')
+                    .map(Ipv4Addr::from_str)
+                    .collect::<Result<_, _>>()?,
+            ),
+            SvcParamKey::ECH => Self::Ech(BASE64.decode(value)?),
+            SvcParamKey::IPV6HINT => Self::Ipv6Hint(
+                value
+                    .split(',')
+                    .map(Ipv6Addr::from_str)
+                    .collect::<Result<_, _>>()?,
+            ),
+            _ => Self::Unknown(unescape(value)?),
+        };
+
+        Ok(parsed)
+    }
+
+    fn decode(key: SvcParamKey, data: &[u8]) -> Result<Self, Report> {
+        let value = match key {
+            SvcParamKey::MANDATORY => {
+                let keys = chunks::<2>(data, key)?
+                    .map(|c| SvcParamKey(u16::from_be_bytes(c)))
+                    .collect::<Vec<_>>();
+
+                if keys.windows(2).any(|pair| pair[0] >= pair[1]) {
+                    return Err(eyre!("Malformed SvcParam {key}"));
+                }
+
+                Self::Mandatory(keys)
+            }
+            SvcParamKey::ALPN => {
+                let mut ids = Vec::new();
+                let mut rest = data;
+
+                while let Some((&len, tail)) = rest.split_first() {
+                    let len = len as usize;
+
+                    if len == 0 || tail.len() < len {
+                        return Err(eyre!("Malformed SvcParam {key}"));
+                    }
+
+                    ids.push(String::from_utf8_lossy(&tail[..len]).into_owned());
+                    rest = &tail[len..];
+                }
+
+                Self::Alpn(ids)
+            }
+            SvcParamKey::NO_DEFAULT_ALPN if data.is_empty() => Self::NoDefaultAlpn,
+            SvcParamKey::NO_DEFAULT_ALPN => {
+                return Err(eyre!("Malformed SvcParam {key}"));
+            }
+            SvcParamKey::PORT => {
+                let [hi, lo] = data else {
+                    return Err(eyre!("Malformed SvcParam {key}"));
+                };
+
+                Self::Port(u16::from_be_bytes([*hi, *lo]))
+            }
+            SvcParamKey::IPV4HINT => {
+                Self::Ipv4Hint(chunks::<4>(data, key)?.map(Ipv4Addr::from).collect())
+            }
+            SvcParamKey::ECH => Self::Ech(data.to_vec()),
+            SvcParamKey::IPV6HINT => {
+                Self::Ipv6Hint(chunks::<16>(data, key)?.map(Ipv6Addr::from).collect())
+            }
+            _ => Self::Unknown(data.to_vec()),
+        };
+
+        Ok(value)
+    }
+
+    fn encode(&self, output: &mut Vec<u8>) {
+        match self {
+            Self::Mandatory(keys) => {
+                for key in keys {
+                    output.extend(key.0.to_be_bytes());
+                }
+            }
+            Self::Alpn(ids) => {
+                for id in ids {
+                    output.push(id.len() as u8);
+                    output.extend(id.as_bytes());
+                }
+            }
+            Self::NoDefaultAlpn => {}
+            Self::Port(port) => output.extend(port.to_be_bytes()),
+            Self::Ipv4Hint(addrs) => {
+                for addr in addrs {
+                    output.extend(addr.octets());
+                }
+            }
+            Self::Ech(config) => output.extend(config),
+            Self::Ipv6Hint(addrs) => {
+                for addr in addrs {
+                    output.extend(addr.octets());
+                }
+            }
+            Self::Unknown(data) => output.extend(data),
+        }
+    }
+}
+
+impl fmt::Display for SvcParamValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fn list<T: fmt::Display>(f: &mut fmt::Formatter<'_>, items: &[T]) -> fmt::Result {
+            for (i, item) in items.iter().enumerate() {
+                if i > 0 {
+                    write!(f, ",")?;
+                }
+
+                write!(f, "{item}")?;
+            }
+
+            Ok(())
+        }
+
+        match self {
+            Self::Mandatory(keys) => list(f, keys),
+            Self::Alpn(ids) => {
+                let ids = ids
+                    .iter()
+                    .map(|id| id.replace(',', "\\,"))
+                    .collect::<Vec<_>>();
+                list(f, &ids)
+            }
+            Self::NoDefaultAlpn => Ok(()),
+            Self::Port(port) => write!(f, "{port}"),
+            Self::Ipv4Hint(addrs) => list(f, addrs),
+            Self::Ech(config) => write!(f, "{}", BASE64.encode(config)),
+            Self::Ipv6Hint(addrs) => list(f, addrs),
+            Self::Unknown(data) => write!(f, "{}", CharacterStrings(&[data])),
+        }
+    }
+}
+
+/// The SvcParams of an SVCB or HTTPS record, kept sorted by key.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SvcParams {
+    params: BTreeMap<SvcParamKey, SvcParamValue>,
+}
+
+impl SvcParams {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&mut self, key: SvcParamKey, value: SvcParamValue) -> Option<SvcParamValue> {
+        self.params.insert(key, value)
+    }
+
+    pub fn get(&self, key: SvcParamKey) -> Option<&SvcParamValue> {
+        self.params.get(&key)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&SvcParamKey, &SvcParamValue)> {
+        self.params.iter()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.params.is_empty()
+    }
+
+    /// Parse the presentation format, eg. `alpn=h2,h3 port=8443`.
+    pub fn parse<'a>(tokens: impl IntoIterator<Item = &'a str>) -> Result<Self, Report> {
+        let mut params = Self::new();
+
+        for token in tokens {
+            let (key, value) = match token.split_once('=') {
+                Some((key, value)) => (key, Some(value)),
+                None => (token, None),
+            };
+
+            let key = SvcParamKey::from_str(key)?;
+            let value = SvcParamValue::parse(key, value)?;
+
+            if params.insert(key, value).is_some() {
+                return Err(eyre!("Duplicate SvcParam: {key}"));
+            }
+        }
+
+        params.validate()?;
+        Ok(params)
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Self, Report> {
+        let mut params = Self::new();
+        let mut rest = data;
+        let mut last = None;
+
+        while !rest.is_empty() {
+            let [k0, k1, l0, l1, tail @ ..] = rest else {
+                return Err(eyre!("Truncated SvcParam"));
+            };
+
+            let key = SvcParamKey(u16::from_be_bytes([*k0, *k1]));
+            let len = u16::from_be_bytes([*l0, *l1]) as usize;
+
+            if matches!(last, Some(last) if key <= last) {
+                return Err(eyre!("SvcParam keys are not in strictly increasing order"));
+            }
+
+            if tail.len() < len {
+                return Err(eyre!("Truncated SvcParam {key}"));
+            }
+
+            params.insert(key, SvcParamValue::decode(key, &tail[..len])?);
+
+            last = Some(key);
+            rest = &tail[len..];
+        }
+
+        params.validate()?;
+        Ok(params)
+    }
+
+    pub fn encode(&self, output: &mut Vec<u8>) {
+        let mut value = Vec::new();
+
+        for (key, param) in &self.params {
+            value.clear();
+            param.encode(&mut value);
+
+            output.extend(key.0.to_be_bytes());
+            output.extend((value.len() as u16).to_be_bytes());
+            output.extend(&value);
+        }
+    }
+
+    /// Check that the parameters are self-consistent, as per RFC 9460, section 8.
+    pub fn validate(&self) -> Result<(), Report> {
+        if let Some(SvcParamValue::Mandatory(keys)) = self.get(SvcParamKey::MANDATORY) {
+            if keys.is_empty() {
+                return Err(eyre!("SvcParam mandatory must list at least one key"));
+            }
+
+            for (i, key) in keys.iter().enumerate() {
+                if *key == SvcParamKey::MANDATORY {
+                    return Err(eyre!("SvcParam mandatory must not list itself"));
+                }
+
+                if keys[..i].contains(key) {
+                    return Err(eyre!("SvcParam mandatory lists {key} more than once"));
+                }
+
+                if self.get(*key).is_none() {
+                    return Err(eyre!("SvcParam {key} is mandatory but missing"));
+                }
+            }
+        }
+
+        if self.get(SvcParamKey::NO_DEFAULT_ALPN).is_some() && self.get(SvcParamKey::ALPN).is_none()
+        {
+            return Err(eyre!("SvcParam no-default-alpn requires alpn"));
+        }
+
+        if let Some(SvcParamValue::Alpn(ids)) = self.get(SvcParamKey::ALPN) {
+            if ids.is_empty() || ids.iter().any(|id| id.is_empty() || id.len() > 255) {
+                return Err(eyre!(
+                    "SvcParam alpn must list protocol ids of 1 to 255 bytes"
+                ));
+            }
+        }
+
+        match self.get(SvcParamKey::IPV4HINT) {
+            Some(SvcParamValue::Ipv4Hint(addrs)) if addrs.is_empty() => {
+                return Err(eyre!("SvcParam ipv4hint must list at least one address"));
+            }
+            _ => {}
+        }
+
+        match self.get(SvcParamKey::IPV6HINT) {
+            Some(SvcParamValue::Ipv6Hint(addrs)) if addrs.is_empty() => {
+                return Err(eyre!("SvcParam ipv6hint must list at least one address"));
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Display for SvcParams {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, (key, value)) in self.params.iter().enumerate() {
+            if i > 0 {
+                write!(f, " ")?;
+            }
+
+            match value {
+                SvcParamValue::NoDefaultAlpn => write!(f, "{key}")?,
+                SvcParamValue::Unknown(data) if data.is_empty() => write!(f, "{key}")?,
+                value => write!(f, "{key}={value}")?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn split_alpn(value: &str) -> Vec<String> {
+    let mut ids = vec![String::new()];
+    let mut chars = value.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => ids.last_mut().unwrap().extend(chars.next()),
+            ',' => ids.push(String::new()),
+            c => ids.last_mut().unwrap().push(c),
+        }
+    }
+
+    ids
+}
+
+/// Undo the `\X` and `\DDD` escapes of a value.
+fn unescape(value: &str) -> Result<Vec<u8>, Report> {
+    let invalid = || eyre!("Invalid escape in SvcParam value: {value}");
+
+    let mut bytes = Vec::new();
+    let mut chars = value.chars();
+
+    while let Some(c) = chars.next() {
+        let c = match c {
+            '\\' => chars.next().ok_or_else(invalid)?,
+            c => {
+                bytes.extend(c.encode_utf8(&mut [0; 4]).as_bytes());
+                continue;
+            }
+        };
+
+        match c.to_digit(10) {
+            Some(d0) => {
+                let (Some(d1), Some(d2)) = (
+                    chars.next().and_then(|c| c.to_digit(10)),
+                    chars.next().and_then(|c| c.to_digit(10)),
+                ) else {
+                    return Err(invalid());
+                };
+
+                bytes.push(u8::try_from(d0 * 100 + d1 * 10 + d2).map_err(|_| invalid())?);
+            }
+            None => bytes.extend(c.encode_utf8(&mut [0; 4]).as_bytes()),
+        }
+    }
+
+    Ok(bytes)
+}
+
+fn chunks<const N: usize>(
+    data: &[u8],
+    key: SvcParamKey,
+) -> Result<impl Iterator<Item = [u8; N]> + '_, Report> {
+    if data.is_empty() || data.len() % N != 0 {
+        return Err(eyre!("Malformed SvcParam {key}"));
+    }
+
+    Ok(data
+        .chunks_exact(N)
+        .map(|chunk| <[u8; N]>::try_from(chunk).unwrap()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_presentation() {
+        let params = SvcParams::parse(["alpn=h2,h3", "port=8443", "ipv4hint=127.0.0.1"]).unwrap();
+
+        assert_eq!(
+            params.get(SvcParamKey::ALPN),
+            Some(&SvcParamValue::Alpn(vec![
+                "h2".to_string(),
+                "h3".to_string()
+            ]))
+        );
+        assert_eq!(
+            params.get(SvcParamKey::PORT),
+            Some(&SvcParamValue::Port(8443))
+        );
+        assert_eq!(
+            params.to_string(),
+            "alpn=h2,h3 port=8443 ipv4hint=127.0.0.1"
+        );
+    }
+
+    #[test]
+    fn wire_roundtrip() {
+        let params = SvcParams::parse([
+            "mandatory=alpn,ipv4hint",
+            "alpn=h3",
+            "no-default-alpn",
+            "ipv4hint=127.0.0.1,127.0.0.2",
+            "ech=AEX+DQBBpQAgACA=",
+            "ipv6hint=::1",
+            "key667=hello",
+        ])
+        .unwrap();
+
+        let mut data = Vec::new();
+        params.encode(&mut data);
+
+        assert_eq!(&data[..8], &[0, 0, 0, 4, 0, 1, 0, 4]);
+        assert_eq!(SvcParams::decode(&data).unwrap(), params);
+    }
+
+    #[test]
+    fn unknown_values() {
+        let params = SvcParams::parse(["key667", "key668=\"\""]).unwrap();
+        assert_eq!(
+            params.get(SvcParamKey(667)),
+            Some(&SvcParamValue::Unknown(vec![]))
+        );
+        assert_eq!(params.to_string(), "key667 key668");
+
+        // Only the enclosing quotes are stripped.
+        let params = SvcParams::parse([r#"key667="a \"b\" \\ \255""#]).unwrap();
+        assert_eq!(
+            params.get(SvcParamKey(667)),
+            Some(&SvcParamValue::Unknown(b"a \"b\" \\ \xff".to_vec()))
+        );
+
+        let printed = params.to_string();
+        assert_eq!(printed, r#"key667="a \"b\" \\ \255""#);
+        assert_eq!(SvcParams::parse([printed.as_str()]).unwrap(), params);
+    }
+
+    #[test]
+    fn mandatory_validation() {
+        assert!(SvcParams::parse(["mandatory=port", "alpn=h2"]).is_err());
+        assert!(SvcParams::parse(["mandatory=mandatory"]).is_err());
+        assert!(SvcParams::parse(["mandatory=alpn,alpn", "alpn=h2"]).is_err());
+        assert!(SvcParams::parse(["mandatory=alpn", "alpn=h2"]).is_ok());
+
+        let params = SvcParams::parse(["mandatory=port,alpn", "alpn=h2", "port=443"]).unwrap();
+        assert_eq!(
+            params.get(SvcParamKey::MANDATORY),
+            Some(&SvcParamValue::Mandatory(vec![
+                SvcParamKey::ALPN,
+                SvcParamKey::PORT
+            ]))
+        );
+
+        let mut data = Vec::new();
+        params.encode(&mut data);
+        assert_eq!(&data[..8], &[0, 0, 0, 4, 0, 1, 0, 3]);
+
+        // Keys out of order, or repeated, in mandatory
+        assert!(SvcParams::decode(&[0, 0, 0, 4, 0, 3, 0, 1]).is_err());
+        assert!(SvcParams::decode(&[0, 0, 0, 4, 0, 1, 0, 1]).is_err());
+    }
+
+    #[test]
+    fn invalid_params() {
+        assert!(SvcParams::parse(["no-default-alpn"]).is_err());
+        assert!(SvcParams::parse(["port=8443", "port=443"]).is_err());
+        assert!(SvcParams::parse(["port"]).is_err());
+        assert!(SvcParams::parse(["key65535=foo"]).is_err());
+        assert!(SvcParams::parse(["key667=\\25"]).is_err());
+        assert!(SvcParams::parse(["key667=\\256"]).is_err());
+
+        // Keys out of order
+        assert!(SvcParams::decode(&[0, 3, 0, 2, 1, 187, 0, 1, 0, 3, 2, 104, 50]).is_err());
+    }
+}
@@ -1,5 +1,10 @@
 use core::fmt;
-use std::collections::BTreeMap;
+use std::{
+    collections::{btree_map, BTreeMap},
+    sync::Arc,
+};
+
+use crate::arena::{self, ArenaTrie};
 
 #[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub enum Key<K> {
@@ -16,9 +21,29 @@ impl<K: fmt::Display> fmt::Display for Key<K> {
     }
 }
 
+/// Outcome of [`Trie::find`], following the wildcard rules of RFC 4592.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Match<'a, 'k, K, V> {
+    /// The key exists and holds a value.
+    Exact(&'a V),
+    /// The key exists but only as an ancestor of other keys.
+    EmptyNonTerminal,
+    /// The key does not exist and was matched by the wildcard child of `source`,
+    /// its closest encloser.
+    Wildcard { source: &'k [Key<K>], value: &'a V },
+    /// The key does not exist. `encloser` is its deepest existing ancestor,
+    /// holding `value` if any, and `remaining` the keys that could not be matched.
+    Miss {
+        encloser: &'k [Key<K>],
+        value: Option<&'a V>,
+        remaining: &'k [Key<K>],
+    },
+}
+
+/// A trie node. Children are shared between versions of a trie, and copied on write.
 #[derive(Clone, Debug)]
 pub struct Node<K, V> {
-    children: BTreeMap<Key<K>, Node<K, V>>,
+    children: BTreeMap<Key<K>, Arc<Node<K, V>>>,
     value: Option<V>,
 }
 
@@ -48,7 +73,9 @@ fn pretty<K: fmt::Display, V: fmt::Display>(
     }
 
     if let Some(value) = &node.value {
-        write!(f, "\n{:indent$}{spacer} {value}", "")?;
+        for line in value.to_string().lines() {
+            write!(f, "\n{:indent$}{spacer} {line}", "")?;
+        }
     }
 
     Ok(())
@@ -61,17 +88,26 @@ impl<K: fmt::Display, V: fmt::Display> fmt::Display for Node<K, V> {
 }
 
 impl<K, V> Node<K, V> {
-    pub fn insert(&mut self, keys: impl IntoIterator<Item = Key<K>>, val: V)
+    pub fn insert(&mut self, keys: impl IntoIterator<Item = Key<K>>, val: V) -> Option<V>
     where
-        K: Ord,
+        K: Clone + Ord,
+        V: Clone,
+    {
+        self.get_or_create(keys).value.replace(val)
+    }
+
+    fn get_or_create(&mut self, keys: impl IntoIterator<Item = Key<K>>) -> &mut Node<K, V>
+    where
+        K: Clone + Ord,
+        V: Clone,
     {
         let mut iter = keys.into_iter();
 
         if let Some(head) = iter.next() {
-            let node = self.children.entry(head).or_insert_with(Node::default);
-            node.insert(iter, val);
+            let node = Arc::make_mut(self.children.entry(head).or_default());
+            node.get_or_create(iter)
         } else {
-            self.value = Some(val);
+            self
         }
     }
 
@@ -91,24 +127,178 @@ impl<K, V> Node<K, V> {
             self.value.as_ref()
         }
     }
+
+    fn find<'a, 'k>(&'a self, keys: &'k [Key<K>]) -> Match<'a, 'k, K, V>
+    where
+        K: Ord,
+    {
+        let mut node = self;
+
+        for (depth, key) in keys.iter().enumerate() {
+            match node.children.get(key) {
+                Some(child) => node = child,
+                None => {
+                    let (encloser, remaining) = keys.split_at(depth);
+
+                    let wildcard = node.children.get(&Key::Wildcard);
+
+                    return match wildcard.and_then(|node| node.value.as_ref()) {
+                        Some(value) => Match::Wildcard {
+                            source: encloser,
+                            value,
+                        },
+                        _ => Match::Miss {
+                            encloser,
+                            value: node.value.as_ref(),
+                            remaining,
+                        },
+                    };
+                }
+            }
+        }
+
+        match &node.value {
+            Some(value) => Match::Exact(value),
+            None => Match::EmptyNonTerminal,
+        }
+    }
+
+    /// Find the node at exactly the given path, without wildcard matching.
+    fn get(&self, keys: &[Key<K>]) -> Option<&Node<K, V>>
+    where
+        K: Ord,
+    {
+        match keys.split_first() {
+            Some((head, tail)) => self.children.get(head)?.get(tail),
+            None => Some(self),
+        }
+    }
+
+    fn get_mut(&mut self, keys: &[Key<K>]) -> Option<&mut Node<K, V>>
+    where
+        K: Clone + Ord,
+        V: Clone,
+    {
+        match keys.split_first() {
+            Some((head, tail)) => Arc::make_mut(self.children.get_mut(head)?).get_mut(tail),
+            None => Some(self),
+        }
+    }
+
+    /// Remove the value at exactly the given path, pruning the nodes left empty.
+    pub fn remove(&mut self, keys: &[Key<K>]) -> Option<V>
+    where
+        K: Clone + Ord,
+        V: Clone,
+    {
+        let Some((head, tail)) = keys.split_first() else {
+            return self.value.take();
+        };
+
+        let child = Arc::make_mut(self.children.get_mut(head)?);
+        let value = child.remove(tail);
+
+        if child.is_empty() {
+            self.children.remove(head);
+        }
+
+        value
+    }
+
+    fn is_empty(&self) -> bool {
+        self.value.is_none() && self.children.is_empty()
+    }
+
+    fn iter(&self, path: Vec<Key<K>>) -> NodeIter<'_, K, V> {
+        NodeIter {
+            path,
+            stack: vec![self.children.iter()],
+            pending: self.value.as_ref(),
+        }
+    }
+}
+
+type Children<'a, K, V> = btree_map::Iter<'a, Key<K>, Arc<Node<K, V>>>;
+
+/// Iterator over the values of a trie and their full key paths, in order.
+pub struct Iter<'a, K, V>(Inner<'a, K, V>);
+
+enum Inner<'a, K, V> {
+    Nodes(NodeIter<'a, K, V>),
+    Arena(arena::Iter<'a, K, V>),
+}
+
+impl<'a, K: Clone, V> Iterator for Iter<'a, K, V> {
+    type Item = (Vec<Key<K>>, &'a V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match &mut self.0 {
+            Inner::Nodes(iter) => iter.next(),
+            Inner::Arena(iter) => iter.next(),
+        }
+    }
+}
+
+struct NodeIter<'a, K, V> {
+    path: Vec<Key<K>>,
+    stack: Vec<Children<'a, K, V>>,
+    pending: Option<&'a V>,
+}
+
+impl<'a, K: Clone, V> Iterator for NodeIter<'a, K, V> {
+    type Item = (Vec<Key<K>>, &'a V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(value) = self.pending.take() {
+                return Some((self.path.clone(), value));
+            }
+
+            match self.stack.last_mut()?.next() {
+                Some((key, child)) => {
+                    self.path.push(key.clone());
+                    self.stack.push(child.children.iter());
+                    self.pending = child.value.as_ref();
+                }
+                None => {
+                    self.stack.pop();
+                    self.path.pop();
+                }
+            }
+        }
+    }
 }
 
+/// A persistent trie: cloning is O(1), and updating a clone only copies the
+/// nodes along the updated path, leaving the original untouched.
+///
+/// The values can also be moved to a compact, read-only [`ArenaTrie`] with
+/// [`Trie::compact`], for very large zones.
 #[derive(Clone, Debug)]
 pub struct Trie<K, V> {
-    root: Node<K, V>,
+    root: Arc<Node<K, V>>,
+    /// Holds the values instead of `root` once compacted.
+    arena: Option<Arc<ArenaTrie<K, V>>>,
+    len: usize,
 }
 
 impl<K, V> Default for Trie<K, V> {
     fn default() -> Self {
         Self {
-            root: Node::default(),
+            root: Arc::default(),
+            arena: None,
+            len: 0,
         }
     }
 }
 
-impl<K: fmt::Display, V: fmt::Display> fmt::Display for Trie<K, V> {
+impl<K, V> fmt::Display for Trie<K, V>
+where
+    K: Clone + Ord + fmt::Display,
+    V: Clone + fmt::Display,
+{
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        fmt::Display::fmt(&self.root, f)
+        fmt::Display::fmt(&self.nodes(), f)
     }
 }
 
@@ -117,18 +307,235 @@ impl<K, V> Trie<K, V> {
         Self::default()
     }
 
-    pub fn insert(&mut self, keys: impl IntoIterator<Item = Key<K>>, val: V)
+    /// Number of values in the trie.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn insert(&mut self, keys: impl IntoIterator<Item = Key<K>>, val: V) -> Option<V>
     where
         K: Clone + Ord,
+        V: Clone,
     {
-        self.root.insert(keys, val)
+        self.thaw();
+        let old = Arc::make_mut(&mut self.root).insert(keys, val);
+
+        if old.is_none() {
+            self.len += 1;
+        }
+
+        old
+    }
+
+    /// Get a mutable reference to the value at the given key, inserting the
+    /// default value first if there is none.
+    pub fn get_or_insert_default(&mut self, keys: impl IntoIterator<Item = Key<K>>) -> &mut V
+    where
+        K: Clone + Ord,
+        V: Clone + Default,
+    {
+        self.thaw();
+        let node = Arc::make_mut(&mut self.root).get_or_create(keys);
+
+        if node.value.is_none() {
+            self.len += 1;
+        }
+
+        node.value.get_or_insert_with(V::default)
     }
 
     pub fn lookup(&self, keys: &[Key<K>]) -> Option<&V>
     where
         K: Clone + Ord,
     {
-        self.root.lookup(keys)
+        match &self.arena {
+            Some(arena) => arena.lookup(keys),
+            None => self.root.lookup(keys),
+        }
+    }
+
+    /// Look up a key, reporting how far the match got.
+    ///
+    /// Unlike [`Trie::lookup`], a wildcard matches any number of remaining keys
+    /// below the closest encloser, and is never used when the key exists.
+    pub fn find<'a, 'k>(&'a self, keys: &'k [Key<K>]) -> Match<'a, 'k, K, V>
+    where
+        K: Ord,
+    {
+        match &self.arena {
+            Some(arena) => arena.find(keys),
+            None => self.root.find(keys),
+        }
+    }
+
+    /// Get the value at exactly the given key, without wildcard matching.
+    pub fn get(&self, keys: &[Key<K>]) -> Option<&V>
+    where
+        K: Ord,
+    {
+        match &self.arena {
+            Some(arena) => arena.get(keys),
+            None => self.root.get(keys)?.value.as_ref(),
+        }
+    }
+
+    pub fn get_mut(&mut self, keys: &[Key<K>]) -> Option<&mut V>
+    where
+        K: Clone + Ord,
+        V: Clone,
+    {
+        self.get(keys)?;
+        self.thaw();
+        Arc::make_mut(&mut self.root).get_mut(keys)?.value.as_mut()
+    }
+
+    pub fn remove(&mut self, keys: &[Key<K>]) -> Option<V>
+    where
+        K: Clone + Ord,
+        V: Clone,
+    {
+        self.get(keys)?;
+        self.thaw();
+
+        let old = Arc::make_mut(&mut self.root).remove(keys);
+
+        if old.is_some() {
+            self.len -= 1;
+        }
+
+        old
+    }
+
+    pub fn iter(&self) -> Iter<'_, K, V> {
+        match &self.arena {
+            Some(arena) => Iter(Inner::Arena(arena.iter())),
+            None => Iter(Inner::Nodes(self.root.iter(vec![]))),
+        }
+    }
+
+    /// Iterate over the values at or below the given prefix.
+    pub fn subtree(&self, prefix: &[Key<K>]) -> Iter<'_, K, V>
+    where
+        K: Clone + Ord,
+    {
+        if let Some(arena) = &self.arena {
+            return Iter(Inner::Arena(arena.subtree(prefix)));
+        }
+
+        Iter(Inner::Nodes(match self.root.get(prefix) {
+            Some(node) => node.iter(prefix.to_vec()),
+            None => NodeIter {
+                path: vec![],
+                stack: vec![],
+                pending: None,
+            },
+        }))
+    }
+
+    /// Move the values to a compact [`ArenaTrie`], which takes much less
+    /// memory for large zones. The next update moves them back to nodes.
+    pub fn compact(&mut self)
+    where
+        K: Clone + Ord,
+        V: Clone,
+    {
+        if self.arena.is_none() {
+            self.arena = Some(Arc::new(ArenaTrie::from(&*self)));
+            self.root = Arc::default();
+        }
+    }
+
+    pub fn is_compact(&self) -> bool {
+        self.arena.is_some()
+    }
+
+    /// The nodes of the trie, rebuilt from the arena if compacted.
+    fn nodes(&self) -> Arc<Node<K, V>>
+    where
+        K: Clone + Ord,
+        V: Clone,
+    {
+        let Some(arena) = &self.arena else {
+            return self.root.clone();
+        };
+
+        let mut root = Node::default();
+
+        for (keys, value) in arena.iter() {
+            root.insert(keys, value.clone());
+        }
+
+        Arc::new(root)
+    }
+
+    /// Move the values back from the arena to nodes, before an update.
+    fn thaw(&mut self)
+    where
+        K: Clone + Ord,
+        V: Clone,
+    {
+        if self.arena.is_some() {
+            self.root = self.nodes();
+            self.arena = None;
+        }
+    }
+}
+
+impl<K, V> Trie<K, V>
+where
+    K: Clone + Ord + fmt::Display,
+    V: Clone + fmt::Display,
+{
+    /// Render the trie as a Graphviz DOT graph.
+    pub fn to_dot(&self) -> String {
+        use std::fmt::Write;
+
+        fn escape(s: &str) -> String {
+            s.replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n")
+        }
+
+        fn walk<K: fmt::Display, V: fmt::Display>(
+            parent: &Node<K, V>,
+            id: usize,
+            next: &mut usize,
+            out: &mut String,
+        ) {
+            for (key, child) in &parent.children {
+                *next += 1;
+                let child_id = *next;
+
+                let (label, shape) = match &child.value {
+                    Some(value) => (format!("{key}\n{value}"), "box"),
+                    None => (key.to_string(), "ellipse"),
+                };
+
+                let label = escape(&label);
+                writeln!(out, "    n{child_id} [label=\"{label}\", shape={shape}];").unwrap();
+                writeln!(out, "    n{id} -> n{child_id};").unwrap();
+
+                walk(child, child_id, next, out);
+            }
+        }
+
+        let mut out = String::from("digraph trie {\n    n0 [label=\".\"];\n");
+        walk(&self.nodes(), 0, &mut 0, &mut out);
+        out.push_str("}\n");
+        out
+    }
+}
+
+impl<'a, K: Clone, V> IntoIterator for &'a Trie<K, V> {
+    type Item = (Vec<Key<K>>, &'a V);
+    type IntoIter = Iter<'a, K, V>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
     }
 }
 
@@ -177,4 +584,208 @@ mod tests {
         assert_eq!(trie.lookup(&[foo.clone()]), None);
         assert_eq!(trie.lookup(&key), Some(&1));
     }
+
+    #[test]
+    fn test_remove_prunes() {
+        let mut trie = Trie::new();
+
+        let foo = Key::Exact("foo");
+        let bar = Key::Exact("bar");
+        let baz = Key::Exact("baz");
+
+        trie.insert([foo.clone(), bar.clone(), baz.clone()], 1);
+        trie.insert([foo.clone()], 2);
+        assert_eq!(trie.len(), 2);
+
+        assert_eq!(trie.remove(&[foo.clone(), bar.clone()]), None);
+        assert_eq!(
+            trie.remove(&[foo.clone(), bar.clone(), baz.clone()]),
+            Some(1)
+        );
+        assert_eq!(trie.len(), 1);
+        assert!(trie.root.children[&foo].children.is_empty());
+
+        assert_eq!(trie.remove(&[foo.clone()]), Some(2));
+        assert!(trie.is_empty());
+        assert!(trie.root.children.is_empty());
+    }
+
+    #[test]
+    fn test_iter() {
+        let mut trie = Trie::new();
+
+        let foo = Key::Exact("foo");
+        let bar = Key::Exact("bar");
+        let baz = Key::Exact("baz");
+
+        trie.insert([foo.clone(), bar.clone()], 1);
+        trie.insert([foo.clone()], 2);
+        trie.insert([foo.clone(), Key::Wildcard], 3);
+        trie.insert([baz.clone()], 4);
+
+        let all = trie.iter().collect::<Vec<_>>();
+
+        assert_eq!(
+            all,
+            vec![
+                (vec![baz.clone()], &4),
+                (vec![foo.clone()], &2),
+                (vec![foo.clone(), Key::Wildcard], &3),
+                (vec![foo.clone(), bar.clone()], &1),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_subtree() {
+        let mut trie = Trie::new();
+
+        let foo = Key::Exact("foo");
+        let bar = Key::Exact("bar");
+        let baz = Key::Exact("baz");
+
+        trie.insert([foo.clone(), bar.clone()], 1);
+        trie.insert([foo.clone(), bar.clone(), baz.clone()], 2);
+        trie.insert([baz.clone()], 3);
+
+        let sub = trie
+            .subtree(&[foo.clone(), bar.clone()])
+            .map(|(_, v)| *v)
+            .collect::<Vec<_>>();
+
+        assert_eq!(sub, vec![1, 2]);
+        assert_eq!(trie.subtree(&[bar.clone()]).count(), 0);
+        assert_eq!(trie.subtree(&[]).count(), 3);
+    }
+
+    #[test]
+    fn test_find() {
+        let mut trie = Trie::new();
+
+        let dev = Key::Exact("dev");
+        let local = Key::Exact("local");
+        let api = Key::Exact("api");
+        let v1 = Key::Exact("v1");
+        let foo = Key::Exact("foo");
+        let bar = Key::Exact("bar");
+
+        trie.insert([dev.clone(), local.clone()], 1);
+        trie.insert([dev.clone(), local.clone(), api.clone(), v1.clone()], 2);
+        trie.insert([dev.clone(), local.clone(), Key::Wildcard], 3);
+
+        let key = [dev.clone(), local.clone()];
+        assert_eq!(trie.find(&key), Match::Exact(&1));
+
+        let key = [dev.clone(), local.clone(), api.clone()];
+        assert_eq!(trie.find(&key), Match::EmptyNonTerminal);
+
+        let key = [dev.clone(), local.clone(), foo.clone(), bar.clone()];
+        assert_eq!(
+            trie.find(&key),
+            Match::Wildcard {
+                source: &key[..2],
+                value: &3
+            }
+        );
+
+        let key = [dev.clone(), local.clone(), api.clone(), foo.clone()];
+        assert_eq!(
+            trie.find(&key),
+            Match::Miss {
+                encloser: &key[..3],
+                value: None,
+                remaining: &key[3..],
+            }
+        );
+
+        let key = [dev.clone(), foo.clone()];
+        assert_eq!(
+            trie.find(&key),
+            Match::Miss {
+                encloser: &key[..1],
+                value: None,
+                remaining: &key[1..],
+            }
+        );
+    }
+
+    #[test]
+    fn test_persistent() {
+        let mut trie = Trie::new();
+
+        let foo = Key::Exact("foo");
+        let bar = Key::Exact("bar");
+        let baz = Key::Exact("baz");
+
+        trie.insert([foo.clone(), bar.clone()], 1);
+        trie.insert([baz.clone()], 2);
+
+        let snapshot = trie.clone();
+
+        trie.insert([foo.clone(), bar.clone()], 3);
+        trie.remove(&[Key::Exact("baz")]);
+
+        assert_eq!(snapshot.get(&[foo.clone(), bar.clone()]), Some(&1));
+        assert_eq!(snapshot.get(&[Key::Exact("baz")]), Some(&2));
+        assert_eq!(snapshot.len(), 2);
+
+        assert_eq!(trie.get(&[foo.clone(), bar.clone()]), Some(&3));
+        assert_eq!(trie.get(&[Key::Exact("baz")]), None);
+        assert_eq!(trie.len(), 1);
+
+        // Untouched subtrees are shared between versions.
+        let mut other = snapshot.clone();
+        other.insert([baz.clone()], 4);
+        assert!(Arc::ptr_eq(
+            &snapshot.root.children[&foo],
+            &other.root.children[&foo]
+        ));
+    }
+
+    #[test]
+    fn test_compact() {
+        let mut trie = Trie::new();
+
+        let dev = Key::Exact("dev");
+        let local = Key::Exact("local");
+        let api = Key::Exact("api");
+        let foo = Key::Exact("foo");
+
+        trie.insert([dev.clone(), local.clone()], 1);
+        trie.insert([dev.clone(), local.clone(), Key::Wildcard], 2);
+        trie.insert([dev.clone(), local.clone(), api.clone(), foo.clone()], 3);
+
+        let mut compact = trie.clone();
+        compact.compact();
+
+        assert!(compact.is_compact());
+        assert_eq!(compact.len(), 3);
+        assert_eq!(compact.to_string(), trie.to_string());
+        assert!(compact.iter().eq(trie.iter()));
+        assert!(compact
+            .subtree(&[dev.clone(), local.clone(), api.clone()])
+            .eq(trie.subtree(&[dev.clone(), local.clone(), api.clone()])));
+
+        let keys = [
+            vec![dev.clone(), local.clone()],
+            vec![dev.clone(), local.clone(), api.clone()],
+            vec![dev.clone(), local.clone(), foo.clone(), foo.clone()],
+            vec![dev.clone(), local.clone(), api.clone(), api.clone()],
+            vec![dev.clone(), local.clone(), Key::Wildcard],
+            vec![foo.clone()],
+        ];
+
+        for key in &keys {
+            assert_eq!(compact.lookup(key), trie.lookup(key), "{key:?}");
+            assert_eq!(compact.find(key), trie.find(key), "{key:?}");
+            assert_eq!(compact.get(key), trie.get(key), "{key:?}");
+        }
+
+        // Updates go back to nodes.
+        compact.insert([foo.clone()], 4);
+        assert!(!compact.is_compact());
+        assert_eq!(compact.len(), 4);
+        assert_eq!(compact.get(&[dev.clone(), local.clone()]), Some(&1));
+        assert_eq!(compact.get(&[foo.clone()]), Some(&4));
+    }
 }
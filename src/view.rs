@@ -0,0 +1,562 @@
+use core::fmt;
+use std::ops::Range;
+
+use color_eyre::{eyre::eyre, Report};
+
+use crate::data::{Edns, Label, Name, QClass, QType, Question};
+
+pub const HEADER_SIZE: usize = 12;
+const MAX_NAME_LENGTH: usize = 255;
+
+/// A borrowed, zero-copy view over a DNS message.
+///
+/// Parsing validates the whole message up front and only records where each
+/// section starts, so that iterating over questions and records afterwards
+/// never fails nor allocates.
+#[derive(Copy, Clone)]
+pub struct MessageView<'a> {
+    data: &'a [u8],
+    header: HeaderView,
+    sections: [usize; 5],
+}
+
+impl<'a> MessageView<'a> {
+    pub fn parse(data: &'a [u8]) -> Result<Self, Report> {
+        let header = HeaderView::parse(data)?;
+
+        let mut sections = [HEADER_SIZE; 5];
+        let mut pos = HEADER_SIZE;
+
+        for _ in 0..header.qdcount {
+            pos = skip_name(data, pos)?;
+            pos = checked_advance(data, pos, 4)?;
+        }
+
+        sections[1] = pos;
+
+        let counts = [header.ancount, header.nscount, header.arcount];
+        for (i, count) in counts.into_iter().enumerate() {
+            for _ in 0..count {
+                pos = skip_name(data, pos)?;
+                pos = checked_advance(data, pos, 10)?;
+                let rdlength = read_u16(data, pos - 2) as usize;
+                pos = checked_advance(data, pos, rdlength)?;
+            }
+
+            sections[i + 2] = pos;
+        }
+
+        Ok(Self {
+            data,
+            header,
+            sections,
+        })
+    }
+
+    pub fn header(&self) -> &HeaderView {
+        &self.header
+    }
+
+    pub fn as_bytes(&self) -> &'a [u8] {
+        self.data
+    }
+
+    /// The raw bytes of the question section.
+    pub fn question_bytes(&self) -> &'a [u8] {
+        &self.data[self.sections[0]..self.sections[1]]
+    }
+
+    pub fn questions(&self) -> Questions<'a> {
+        Questions {
+            data: self.data,
+            pos: self.sections[0],
+            remaining: self.header.qdcount,
+        }
+    }
+
+    pub fn answers(&self) -> Records<'a> {
+        self.section(1, self.header.ancount)
+    }
+
+    pub fn authorities(&self) -> Records<'a> {
+        self.section(2, self.header.nscount)
+    }
+
+    pub fn additionals(&self) -> Records<'a> {
+        self.section(3, self.header.arcount)
+    }
+
+    /// Records of all three sections, in order.
+    pub fn records(&self) -> impl Iterator<Item = RecordView<'a>> {
+        self.answers()
+            .chain(self.authorities())
+            .chain(self.additionals())
+    }
+
+    pub fn edns(&self) -> Option<Edns> {
+        self.additionals()
+            .find(|record| record.rtype == QType::OPT as u16)
+            .map(|record| Edns::from_parts(record.rclass, record.ttl))
+    }
+
+    fn section(&self, section: usize, count: u16) -> Records<'a> {
+        Records {
+            data: self.data,
+            pos: self.sections[section],
+            remaining: count,
+        }
+    }
+}
+
+impl fmt::Debug for MessageView<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MessageView")
+            .field("header", &self.header)
+            .field("questions", &self.questions().collect::<Vec<_>>())
+            .field("answers", &self.answers().collect::<Vec<_>>())
+            .field("authorities", &self.authorities().collect::<Vec<_>>())
+            .field("additionals", &self.additionals().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct HeaderView {
+    pub id: u16,
+    pub flags: u16,
+    pub qdcount: u16,
+    pub ancount: u16,
+    pub nscount: u16,
+    pub arcount: u16,
+}
+
+impl HeaderView {
+    pub fn parse(data: &[u8]) -> Result<Self, Report> {
+        if data.len() < HEADER_SIZE {
+            return Err(eyre!("Message too short: {} bytes", data.len()));
+        }
+
+        Ok(Self {
+            id: read_u16(data, 0),
+            flags: read_u16(data, 2),
+            qdcount: read_u16(data, 4),
+            ancount: read_u16(data, 6),
+            nscount: read_u16(data, 8),
+            arcount: read_u16(data, 10),
+        })
+    }
+
+    pub fn qr(&self) -> bool {
+        self.flags & 0x8000 != 0
+    }
+
+    pub fn opcode(&self) -> u8 {
+        ((self.flags >> 11) & 0x0F) as u8
+    }
+
+    pub fn aa(&self) -> bool {
+        self.flags & 0x0400 != 0
+    }
+
+    pub fn tc(&self) -> bool {
+        self.flags & 0x0200 != 0
+    }
+
+    pub fn rd(&self) -> bool {
+        self.flags & 0x0100 != 0
+    }
+
+    pub fn ra(&self) -> bool {
+        self.flags & 0x0080 != 0
+    }
+
+    pub fn rcode(&self) -> u8 {
+        (self.flags & 0x000F) as u8
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct QuestionView<'a> {
+    pub qname: NameView<'a>,
+    pub qtype: u16,
+    pub qclass: u16,
+}
+
+impl QuestionView<'_> {
+    pub fn to_question(&self) -> Result<Question, Report> {
+        Ok(Question {
+            qname: self.qname.to_name(),
+            qtype: QType::try_from(self.qtype)?,
+            qclass: QClass::try_from(self.qclass)?,
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Questions<'a> {
+    data: &'a [u8],
+    pos: usize,
+    remaining: u16,
+}
+
+impl<'a> Iterator for Questions<'a> {
+    type Item = QuestionView<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+
+        let qname = NameView {
+            data: self.data,
+            offset: self.pos,
+        };
+
+        let pos = skip_name(self.data, self.pos).ok()?;
+        let question = QuestionView {
+            qname,
+            qtype: read_u16(self.data, pos),
+            qclass: read_u16(self.data, pos + 2),
+        };
+
+        self.pos = pos + 4;
+        self.remaining -= 1;
+
+        Some(question)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining as usize, Some(self.remaining as usize))
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct RecordView<'a> {
+    pub name: NameView<'a>,
+    pub rtype: u16,
+    pub rclass: u16,
+    pub ttl: u32,
+    /// Offset of the TTL field within the message.
+    pub ttl_offset: usize,
+    /// Range of the record data within the message.
+    pub rdata: Range<usize>,
+}
+
+impl<'a> RecordView<'a> {
+    pub fn rdata(&self) -> &'a [u8] {
+        &self.name.data[self.rdata.clone()]
+    }
+}
+
+/// Rewrite the TTL of every record in `message`, except for the OPT pseudo-record.
+pub fn rewrite_ttls(message: &mut [u8], ttl: impl Fn(u32) -> u32) -> Result<(), Report> {
+    let ttls = MessageView::parse(message)?
+        .records()
+        .filter(|record| record.rtype != QType::OPT as u16)
+        .map(|record| (record.ttl_offset, ttl(record.ttl)))
+        .collect::<Vec<_>>();
+
+    for (offset, ttl) in ttls {
+        message[offset..offset + 4].copy_from_slice(&ttl.to_be_bytes());
+    }
+
+    Ok(())
+}
+
+#[derive(Clone, Debug)]
+pub struct Records<'a> {
+    data: &'a [u8],
+    pos: usize,
+    remaining: u16,
+}
+
+impl<'a> Iterator for Records<'a> {
+    type Item = RecordView<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+
+        let name = NameView {
+            data: self.data,
+            offset: self.pos,
+        };
+
+        let pos = skip_name(self.data, self.pos).ok()?;
+        let rdlength = read_u16(self.data, pos + 8) as usize;
+        let rdata = pos + 10..pos + 10 + rdlength;
+
+        let record = RecordView {
+            name,
+            rtype: read_u16(self.data, pos),
+            rclass: read_u16(self.data, pos + 2),
+            ttl: read_u32(self.data, pos + 4),
+            ttl_offset: pos + 4,
+            rdata: rdata.clone(),
+        };
+
+        self.pos = rdata.end;
+        self.remaining -= 1;
+
+        Some(record)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining as usize, Some(self.remaining as usize))
+    }
+}
+
+/// A possibly compressed domain name, borrowed from the message it appears in.
+#[derive(Copy, Clone)]
+pub struct NameView<'a> {
+    data: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> NameView<'a> {
+    /// View the name starting at `offset` in `data`, validating it first.
+    pub fn parse(data: &'a [u8], offset: usize) -> Result<(Self, usize), Report> {
+        let end = skip_name(data, offset)?;
+        Ok((Self { data, offset }, end))
+    }
+
+    pub fn labels(&self) -> Labels<'a> {
+        Labels {
+            data: self.data,
+            pos: self.offset,
+        }
+    }
+
+    pub fn is_root(&self) -> bool {
+        self.labels().next().is_none()
+    }
+
+    pub fn to_name(&self) -> Name {
+        self.labels()
+            .map(|label| Label::new(label.to_vec()))
+            .collect()
+    }
+}
+
+impl fmt::Display for NameView<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_root() {
+            return write!(f, ".");
+        }
+
+        for (i, label) in self.labels().enumerate() {
+            if i > 0 {
+                write!(f, ".")?;
+            }
+
+            write!(f, "{}", String::from_utf8_lossy(label))?;
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Debug for NameView<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Labels<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Iterator for Labels<'a> {
+    type Item = &'a [u8];
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let len = *self.data.get(self.pos)? as usize;
+
+            if len & 0xC0 == 0xC0 {
+                let low = *self.data.get(self.pos + 1)? as usize;
+                self.pos = ((len & 0x3F) << 8) | low;
+                continue;
+            }
+
+            if len == 0 {
+                return None;
+            }
+
+            let label = self.data.get(self.pos + 1..self.pos + 1 + len)?;
+            self.pos += 1 + len;
+
+            return Some(label);
+        }
+    }
+}
+
+/// Validate the name starting at `pos` and return the position right after it.
+///
+/// Compression pointers must point strictly backwards, which rules out loops.
+fn skip_name(data: &[u8], pos: usize) -> Result<usize, Report> {
+    let mut pos = pos;
+    let mut end = None;
+    let mut length = 0;
+
+    loop {
+        let Some(&len) = data.get(pos) else {
+            return Err(eyre!("Name runs past the end of the message"));
+        };
+
+        match len & 0xC0 {
+            0x00 if len == 0 => {
+                return Ok(end.unwrap_or(pos + 1));
+            }
+            0x00 => {
+                length += len as usize + 1;
+
+                if length > MAX_NAME_LENGTH {
+                    return Err(eyre!("Name is longer than {MAX_NAME_LENGTH} bytes"));
+                }
+
+                pos = checked_advance(data, pos, len as usize + 1)?;
+            }
+            0xC0 => {
+                let Some(&low) = data.get(pos + 1) else {
+                    return Err(eyre!("Truncated compression pointer"));
+                };
+
+                let target = (((len & 0x3F) as usize) << 8) | low as usize;
+
+                if target >= pos {
+                    return Err(eyre!("Compression pointer does not point backwards"));
+                }
+
+                end.get_or_insert(pos + 2);
+                pos = target;
+            }
+            _ => return Err(eyre!("Unsupported label type: {len:#04x}")),
+        }
+    }
+}
+
+fn checked_advance(data: &[u8], pos: usize, count: usize) -> Result<usize, Report> {
+    let next = pos + count;
+
+    if next > data.len() {
+        return Err(eyre!("Message truncated at offset {pos}"));
+    }
+
+    Ok(next)
+}
+
+fn read_u16(data: &[u8], pos: usize) -> u16 {
+    u16::from_be_bytes([data[pos], data[pos + 1]])
+}
+
+fn read_u32(data: &[u8], pos: usize) -> u32 {
+    u32::from_be_bytes([data[pos], data[pos + 1], data[pos + 2], data[pos + 3]])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn view_query() {
+        let data: &[u8] = &[
+            100, 68, 1, 0, 0, 1, 0, 0, 0, 0, 0, 1, 3, 102, 111, 111, 5, 108, 111, 99, 97, 108, 3,
+            100, 101, 118, 0, 0, 255, 0, 1, 0, 0, 41, 2, 0, 0, 0, 0, 0, 0, 0,
+        ];
+
+        let message = MessageView::parse(data).unwrap();
+        println!("Message: {message:#?}");
+
+        assert_eq!(message.header().id, 25668);
+        assert!(message.header().rd());
+        assert_eq!(message.header().opcode(), 0);
+
+        let questions = message.questions().collect::<Vec<_>>();
+        assert_eq!(questions.len(), 1);
+        assert_eq!(questions[0].qname.to_string(), "foo.local.dev");
+        assert_eq!(questions[0].qtype, 255);
+        assert_eq!(questions[0].qclass, 1);
+
+        let additionals = message.additionals().collect::<Vec<_>>();
+        assert_eq!(additionals.len(), 1);
+        assert!(additionals[0].name.is_root());
+        assert_eq!(additionals[0].rtype, 41);
+        assert_eq!(additionals[0].rclass, 512);
+
+        assert_eq!(
+            message.edns(),
+            Some(Edns {
+                udp_payload_size: 512,
+                ..Edns::default()
+            })
+        );
+    }
+
+    #[test]
+    fn view_compressed() {
+        let data: &[u8] = &[
+            13, 208, 129, 128, 0, 1, 0, 1, 0, 0, 0, 0, 4, 110, 101, 119, 115, 11, 121, 99, 111,
+            109, 98, 105, 110, 97, 116, 111, 114, 3, 99, 111, 109, 0, 0, 1, 0, 1, 192, 12, 0, 1, 0,
+            1, 0, 0, 0, 1, 0, 4, 209, 216, 230, 240,
+        ];
+
+        let message = MessageView::parse(data).unwrap();
+
+        let answer = message.answers().next().unwrap();
+        assert_eq!(
+            answer.name.to_name(),
+            Name::new("news.ycombinator.com".to_string())
+        );
+        assert_eq!(answer.ttl, 1);
+        assert_eq!(answer.rdata(), &[209, 216, 230, 240]);
+    }
+
+    #[test]
+    fn view_nested_pointers() {
+        let data: &[u8] = &[
+            58, 211, 129, 128, 0, 1, 0, 2, 0, 1, 0, 0, 13, 99, 111, 110, 102, 105, 103, 117, 114,
+            97, 116, 105, 111, 110, 2, 108, 115, 5, 97, 112, 112, 108, 101, 3, 99, 111, 109, 0, 0,
+            65, 0, 1, 192, 12, 0, 5, 0, 1, 0, 0, 13, 244, 0, 37, 10, 103, 115, 112, 101, 49, 49,
+            45, 115, 115, 108, 2, 108, 115, 5, 97, 112, 112, 108, 101, 3, 99, 111, 109, 7, 101,
+            100, 103, 101, 107, 101, 121, 3, 110, 101, 116, 0, 192, 56, 0, 5, 0, 1, 0, 0, 84, 68,
+            0, 26, 6, 101, 49, 48, 52, 57, 57, 5, 100, 115, 99, 101, 57, 10, 97, 107, 97, 109, 97,
+            105, 101, 100, 103, 101, 192, 88, 192, 112, 0, 6, 0, 1, 0, 0, 3, 204, 0, 50, 7, 110,
+            48, 100, 115, 99, 101, 57, 192, 118, 10, 104, 111, 115, 116, 109, 97, 115, 116, 101,
+            114, 6, 97, 107, 97, 109, 97, 105, 192, 35, 100, 43, 245, 193, 0, 0, 3, 232, 0, 0, 3,
+            232, 0, 0, 3, 232, 0, 0, 7, 8,
+        ];
+
+        let message = MessageView::parse(data).unwrap();
+        println!("Message: {message:#?}");
+
+        assert_eq!(message.answers().count(), 2);
+        assert_eq!(message.authorities().count(), 1);
+
+        let authority = message.authorities().next().unwrap();
+        assert_eq!(authority.name.to_string(), "dsce9.akamaiedge.net");
+
+        let (mname, _) = NameView::parse(data, authority.rdata.start).unwrap();
+        assert_eq!(mname.to_string(), "n0dsce9.akamaiedge.net");
+    }
+
+    #[test]
+    fn view_truncated() {
+        let data: &[u8] = &[
+            100, 68, 1, 0, 0, 1, 0, 0, 0, 0, 0, 0, 3, 102, 111, 111, 5, 108, 111,
+        ];
+
+        assert!(MessageView::parse(data).is_err());
+        assert!(MessageView::parse(&data[..8]).is_err());
+    }
+
+    #[test]
+    fn view_pointer_loop() {
+        let data: &[u8] = &[100, 68, 1, 0, 0, 1, 0, 0, 0, 0, 0, 0, 192, 12, 0, 1, 0, 1];
+
+        assert!(MessageView::parse(data).is_err());
+    }
+}